@@ -0,0 +1,421 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::history::History;
+use crate::http::{bearer, json, same, text};
+use crate::mesh::Flight;
+use crate::scheduler::{Board, Health};
+use crate::server::Server;
+use crate::timetable::Direction;
+
+const SCHEDULE: &str = "/api/v1/schedule";
+const PAUSE: &str = "/api/v1/schedule/pause";
+const RESUME: &str = "/api/v1/schedule/resume";
+const RUNS: &str = "/api/v1/runs";
+const RESULTS: &str = "/api/v1/results";
+/// Results returned when the request does not ask for a number.
+const DEFAULT_LIMIT: usize = 10;
+const MAX_BODY: usize = 64 * 1024;
+
+/// Remote control of `speedy serve`, every request needs `token` as bearer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Api {
+    pub listen: SocketAddr,
+    pub token: String,
+}
+
+/// Run requested through the API, unset fields use the defaults of a rule.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Trigger {
+    /// Tested instead of a server group.
+    pub servers: Option<Vec<Server>>,
+    /// Server group, the `default` one unless given.
+    pub group: Option<String>,
+    #[serde(deserialize_with = "direction")]
+    pub direction: Option<Direction>,
+}
+
+fn direction<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Direction>, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "down" => Ok(Some(Direction::Down)),
+        "up" => Ok(Some(Direction::Up)),
+        "both" => Ok(Some(Direction::Both)),
+        other => Err(serde::de::Error::unknown_variant(
+            other,
+            &["down", "up", "both"],
+        )),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Schedule {
+    paused: bool,
+    /// Whether a test, scheduled or triggered, runs right now.
+    running: bool,
+    rules: Vec<Health>,
+}
+
+struct Control<F> {
+    token: String,
+    board: Board,
+    history: History,
+    flight: Flight,
+    trigger: F,
+}
+
+impl<F, Fut> Control<F>
+where
+    F: Fn(Trigger) -> Result<(String, Fut), String>,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    fn schedule(&self) -> Response<Body> {
+        json(
+            StatusCode::OK,
+            &Schedule {
+                paused: self.board.paused(),
+                running: self.flight.busy(),
+                rules: self.board.snapshot(),
+            },
+        )
+    }
+
+    async fn run(&self, req: Request<Body>) -> Response<Body> {
+        let mut body = req.into_body();
+        let mut content = Vec::new();
+
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) if content.len() + chunk.len() <= MAX_BODY => {
+                    content.extend_from_slice(&chunk)
+                }
+                Ok(_) => return text(StatusCode::PAYLOAD_TOO_LARGE, "request too large"),
+                Err(err) => return text(StatusCode::BAD_REQUEST, err.to_string()),
+            }
+        }
+
+        let trigger = match content.is_empty() {
+            true => Trigger::default(),
+            false => match serde_json::from_slice::<Trigger>(&content) {
+                Ok(trigger) => trigger,
+                Err(err) => return text(StatusCode::BAD_REQUEST, err.to_string()),
+            },
+        };
+
+        // Taken before the run starts, so a second trigger is refused right away.
+        let Some(guard) = self.flight.try_acquire() else {
+            return text(StatusCode::CONFLICT, "a test is running");
+        };
+
+        let (run, test) = match (self.trigger)(trigger) {
+            Ok(started) => started,
+            Err(err) => return text(StatusCode::BAD_REQUEST, err),
+        };
+
+        println!("Starting run {run} requested through the API");
+
+        let id = run.clone();
+        tokio::spawn(async move {
+            if let Err(err) = test.await {
+                eprintln!("Run {id} requested through the API failed: {err}");
+            }
+
+            drop(guard);
+        });
+
+        json(StatusCode::ACCEPTED, &serde_json::json!({ "run": run }))
+    }
+
+    async fn results(&self, query: Option<&str>) -> Response<Body> {
+        let limit = query
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("limit="));
+
+        match limit.map(str::parse::<usize>) {
+            None => json(StatusCode::OK, &self.history.last(DEFAULT_LIMIT).await),
+            Some(Ok(limit)) => json(StatusCode::OK, &self.history.last(limit).await),
+            Some(Err(err)) => text(StatusCode::BAD_REQUEST, format!("limit: {err}")),
+        }
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if !bearer(req.headers()).is_some_and(|token| same(&self.token, token)) {
+            return text(StatusCode::UNAUTHORIZED, "unknown token");
+        }
+
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, SCHEDULE) => self.schedule(),
+            (&Method::POST, PAUSE) => {
+                println!("Schedule paused through the API");
+                self.board.set_paused(true);
+                self.schedule()
+            }
+            (&Method::POST, RESUME) => {
+                println!("Schedule resumed through the API");
+                self.board.set_paused(false);
+                self.schedule()
+            }
+            (&Method::POST, RUNS) => self.run(req).await,
+            (&Method::GET, RESULTS) => self.results(req.uri().query()).await,
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// Binds the API on `api.listen`, the returned future serves it until
+/// `shutdown` is cancelled.
+///
+/// `trigger` starts a run on request and returns its ID, it is only called
+/// while holding `flight`, which is released once the run completes.
+pub fn bind<F, Fut>(
+    api: &Api,
+    board: Board,
+    history: History,
+    flight: Flight,
+    trigger: F,
+    shutdown: CancellationToken,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), hyper::Error>>), hyper::Error>
+where
+    F: Fn(Trigger) -> Result<(String, Fut), String> + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let control = Arc::new(Control {
+        token: api.token.clone(),
+        board,
+        history,
+        flight,
+        trigger,
+    });
+
+    let make = make_service_fn(move |_| {
+        let control = Arc::clone(&control);
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let control = Arc::clone(&control);
+
+                async move { Ok::<_, Infallible>(control.handle(req).await) }
+            }))
+        }
+    });
+
+    let server = hyper::Server::try_bind(&api.listen)?.serve(make);
+    let addr = server.local_addr();
+
+    Ok((
+        addr,
+        server.with_graceful_shutdown(async move { shutdown.cancelled().await }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use time::OffsetDateTime;
+    use tokio::sync::oneshot;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{bind, Api, Trigger};
+    use crate::history::History;
+    use crate::influxdb::Direction;
+    use crate::mesh::Flight;
+    use crate::report::Report;
+    use crate::scheduler::Board;
+    use crate::timetable;
+
+    struct Agent {
+        addr: SocketAddr,
+        board: Board,
+        flight: Flight,
+        triggered: Arc<Mutex<Vec<Trigger>>>,
+        /// Completes the running triggered test.
+        done: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    }
+
+    fn agent(history: History) -> Agent {
+        let board = Board::default();
+        let flight = Flight::default();
+        let triggered = Arc::new(Mutex::new(Vec::new()));
+        let done = Arc::new(Mutex::new(None));
+
+        let (seen, running) = (Arc::clone(&triggered), Arc::clone(&done));
+        let trigger = move |trigger: Trigger| {
+            if trigger.group.as_deref() == Some("missing") {
+                return Err("Server group (missing) does not exist".to_string());
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            seen.lock().unwrap().push(trigger);
+            *running.lock().unwrap() = Some(sender);
+
+            Ok(("20240604T161248-00ff".to_string(), async move {
+                _ = receiver.await;
+                Ok(())
+            }))
+        };
+
+        let api = Api {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            token: "secret".to_string(),
+        };
+        let (addr, server) = bind(
+            &api,
+            board.clone(),
+            history,
+            flight.clone(),
+            trigger,
+            CancellationToken::new(),
+        )
+        .unwrap();
+        tokio::spawn(server);
+
+        Agent {
+            addr,
+            board,
+            flight,
+            triggered,
+            done,
+        }
+    }
+
+    async fn request(
+        agent: &Agent,
+        method: reqwest::Method,
+        path: &str,
+        body: &str,
+    ) -> (u16, serde_json::Value) {
+        let response = reqwest::Client::new()
+            .request(method, format!("http://{}{path}", agent.addr))
+            .bearer_auth("secret")
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        let body = response.text().await.unwrap();
+
+        (status, serde_json::from_str(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_triggered_runs_hold_the_flight() {
+        let agent = agent(History::default());
+
+        let (status, body) = request(
+            &agent,
+            reqwest::Method::POST,
+            "/api/v1/runs",
+            r#"{"servers": ["localhost:5201"], "direction": "down"}"#,
+        )
+        .await;
+        assert_eq!(202, status);
+        assert_eq!("20240604T161248-00ff", body["run"]);
+        assert!(agent.flight.busy());
+
+        let (status, _) = request(&agent, reqwest::Method::POST, "/api/v1/runs", "").await;
+        assert_eq!(409, status);
+
+        let (_, schedule) = request(&agent, reqwest::Method::GET, "/api/v1/schedule", "").await;
+        assert_eq!(true, schedule["running"]);
+
+        let sender = agent.done.lock().unwrap().take().unwrap();
+        sender.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!agent.flight.busy());
+
+        let (status, _) = request(&agent, reqwest::Method::POST, "/api/v1/runs", "").await;
+        assert_eq!(202, status);
+
+        let triggered = agent.triggered.lock().unwrap();
+        assert_eq!(2, triggered.len());
+        assert_eq!(
+            "localhost:5201:1",
+            triggered[0].servers.as_ref().unwrap()[0].to_string()
+        );
+        assert_eq!(Some(timetable::Direction::Down), triggered[0].direction);
+        assert_eq!(Trigger::default(), triggered[1]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_triggers_are_refused() {
+        let agent = agent(History::default());
+
+        for body in [
+            r#"{"direction": "sideways"}"#,
+            r#"{"server": "localhost"}"#,
+            r#"{"group": "missing"}"#,
+        ] {
+            let (status, _) = request(&agent, reqwest::Method::POST, "/api/v1/runs", body).await;
+            assert_eq!(400, status, "{body}");
+        }
+
+        assert!(!agent.flight.busy());
+
+        let status = reqwest::Client::new()
+            .post(format!("http://{}/api/v1/runs", agent.addr))
+            .bearer_auth("guess")
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(401, status);
+        assert!(agent.triggered.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume_and_results() {
+        let history = History::default();
+        let agent = agent(history.clone());
+
+        let (_, schedule) =
+            request(&agent, reqwest::Method::POST, "/api/v1/schedule/pause", "").await;
+        assert_eq!(true, schedule["paused"]);
+        assert!(agent.board.paused());
+
+        let (_, schedule) =
+            request(&agent, reqwest::Method::POST, "/api/v1/schedule/resume", "").await;
+        assert_eq!(false, schedule["paused"]);
+        assert!(!agent.board.paused());
+
+        let start = OffsetDateTime::now_utc() - time::Duration::hours(1);
+        history
+            .push((0..15).map(|i| Report {
+                time: start + time::Duration::minutes(i),
+                server: "localhost:5201".to_string(),
+                direction: Direction::Download,
+                bits_per_second: Some(i as f64),
+                rtt_ms: None,
+                retransmits: None,
+                intervals: Vec::new(),
+                error: None,
+                tags: Vec::new(),
+            }))
+            .await
+            .unwrap();
+
+        let (_, results) = request(&agent, reqwest::Method::GET, "/api/v1/results", "").await;
+        assert_eq!(10, results.as_array().unwrap().len());
+        assert_eq!(Some(14.0), results[0]["bits_per_second"].as_f64());
+
+        let (_, results) =
+            request(&agent, reqwest::Method::GET, "/api/v1/results?limit=2", "").await;
+        assert_eq!(2, results.as_array().unwrap().len());
+
+        let (status, _) =
+            request(&agent, reqwest::Method::GET, "/api/v1/results?limit=x", "").await;
+        assert_eq!(400, status);
+    }
+}
@@ -0,0 +1,381 @@
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+use crate::influxdb::Direction;
+use crate::models::IPerf3;
+use crate::report::{self, Builder};
+
+/// Start of a run ID, the rest is random so runs of the same second differ.
+const RUN_TIME: &[FormatItem<'static>] =
+    format_description!("[year][month][day]T[hour][minute][second]");
+const EXTENSION: &str = ".json.gz";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to access archive ({0}): {1}")]
+    IO(String, std::io::Error),
+
+    #[error("Invalid archived result ({0}): {1}")]
+    Json(String, serde_json::Error),
+
+    #[error("No archive configured, set [sinks.archive] path")]
+    NotConfigured,
+
+    #[error("No archived run matches ({0})")]
+    NotFound(String),
+
+    #[error("More than one archived run matches ({0}): {1}")]
+    Ambiguous(String, String),
+}
+
+/// Raw iperf3 results, one gzipped JSON file per direction of a run named
+/// `<run-id>_<direction>_<server>.json.gz`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Archive {
+    pub path: PathBuf,
+    /// Days a result is kept, 0 keeps every result.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+}
+
+fn default_retention_days() -> u32 {
+    30
+}
+
+/// New run ID, e.g. `20240604T161248-3f9a`.
+pub fn run_id(now: OffsetDateTime) -> String {
+    format!(
+        "{}-{:04x}",
+        now.format(RUN_TIME).unwrap_or_default(),
+        rand::thread_rng().gen::<u16>()
+    )
+}
+
+fn started(name: &str) -> Option<OffsetDateTime> {
+    let time = name.get(..15)?;
+
+    PrimitiveDateTime::parse(time, RUN_TIME)
+        .ok()
+        .map(PrimitiveDateTime::assume_utc)
+}
+
+/// Characters of a host that are safe in a file name.
+fn sanitize(server: &str) -> String {
+    server
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '-',
+            },
+        )
+        .collect()
+}
+
+impl Archive {
+    fn io(&self, err: std::io::Error) -> Error {
+        Error::IO(self.path.display().to_string(), err)
+    }
+
+    /// Stores `result`, then removes results older than the retention.
+    pub async fn write(
+        &self,
+        run: &str,
+        direction: &Direction,
+        result: &IPerf3,
+    ) -> Result<PathBuf, Error> {
+        let connecting_to = &result.start.connecting_to;
+        let name = format!(
+            "{run}_{}_{}-{}{EXTENSION}",
+            direction.to_string(),
+            sanitize(&connecting_to.host),
+            connecting_to.port
+        );
+        let path = self.path.join(name);
+
+        let json = serde_json::to_vec(result)
+            .map_err(|err| Error::Json(path.display().to_string(), err))?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&json).map_err(|err| self.io(err))?;
+        let compressed = encoder.finish().map_err(|err| self.io(err))?;
+
+        tokio::fs::create_dir_all(&self.path)
+            .await
+            .map_err(|err| self.io(err))?;
+
+        let temporary = path.with_extension("tmp");
+        tokio::fs::write(&temporary, compressed)
+            .await
+            .map_err(|err| self.io(err))?;
+        tokio::fs::rename(&temporary, &path)
+            .await
+            .map_err(|err| self.io(err))?;
+
+        self.prune(OffsetDateTime::now_utc()).await?;
+
+        Ok(path)
+    }
+
+    /// Names of the archived results, oldest first.
+    async fn names(&self) -> Result<Vec<String>, Error> {
+        let mut entries = match tokio::fs::read_dir(&self.path).await {
+            Ok(entries) => entries,
+            // Nothing was archived yet.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(self.io(err)),
+        };
+        let mut names = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await.map_err(|err| self.io(err))? {
+            if let Some(name) = entry.file_name().to_str() {
+                if name.ends_with(EXTENSION) && started(name).is_some() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    async fn prune(&self, now: OffsetDateTime) -> Result<(), Error> {
+        if self.retention_days == 0 {
+            return Ok(());
+        }
+
+        let oldest = now - time::Duration::days(i64::from(self.retention_days));
+
+        for name in self.names().await? {
+            if started(&name).is_some_and(|started| started < oldest) {
+                tokio::fs::remove_file(self.path.join(name))
+                    .await
+                    .map_err(|err| self.io(err))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Results of the run whose ID is or starts with `run`.
+    pub async fn find(&self, run: &str) -> Result<Vec<(String, IPerf3)>, Error> {
+        let names = self
+            .names()
+            .await?
+            .into_iter()
+            .filter(|name| name.starts_with(run))
+            .collect::<Vec<_>>();
+
+        let mut runs = names
+            .iter()
+            .filter_map(|name| name.split('_').next())
+            .collect::<Vec<_>>();
+        runs.dedup();
+
+        match runs.len() {
+            0 => return Err(Error::NotFound(run.to_string())),
+            1 => {}
+            _ => return Err(Error::Ambiguous(run.to_string(), runs.join(", "))),
+        }
+
+        let mut results = Vec::new();
+
+        for name in names {
+            let path = self.path.join(&name);
+            let compressed = tokio::fs::read(&path).await.map_err(|err| self.io(err))?;
+            let mut json = String::new();
+            GzDecoder::new(compressed.as_slice())
+                .read_to_string(&mut json)
+                .map_err(|err| self.io(err))?;
+
+            let result = serde_json::from_str(&json)
+                .map_err(|err| Error::Json(path.display().to_string(), err))?;
+
+            results.push((name, result));
+        }
+
+        Ok(results)
+    }
+}
+
+fn details(rtt: Option<f64>, retransmits: Option<i64>) -> String {
+    let mut details = String::new();
+
+    if let Some(rtt) = rtt {
+        details += &format!("  rtt {rtt:.1}ms");
+    }
+
+    if let Some(retransmits) = retransmits {
+        details += &format!("  retr {retransmits}");
+    }
+
+    details + "\n"
+}
+
+/// Human readable form of an archived result named `name`.
+pub fn show(name: &str, result: &IPerf3) -> String {
+    let start = &result.start;
+    let test = &start.test_start;
+    let direction = match name.split('_').nth(1) {
+        Some("down") => Direction::Download,
+        _ => Direction::Upload,
+    };
+
+    let mut output = format!(
+        "{name}\n{} {}:{}, {}, {} streams, {}s, {}\n",
+        direction.to_string(),
+        start.connecting_to.host,
+        start.connecting_to.port,
+        test.protocol,
+        test.num_streams,
+        test.duration,
+        start.version
+    );
+
+    let report = Builder::result(direction, OffsetDateTime::UNIX_EPOCH, &[], result);
+
+    if !report.intervals.is_empty() {
+        for interval in report.intervals.iter() {
+            output += &format!(
+                "  {:>5.1}-{:<5.1}s {:>13}",
+                interval.start,
+                interval.end,
+                report::bitrate(interval.bits_per_second)
+            );
+            output += &details(interval.rtt_ms, interval.retransmits);
+        }
+
+        if let Some(bits_per_second) = report.bits_per_second {
+            output += &format!("  total {}", report::bitrate(bits_per_second));
+            output += &details(report.rtt_ms, report.retransmits);
+        }
+    }
+
+    if let Some(ref error) = result.error {
+        output += &format!("  error: {error}\n");
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use time::macros::datetime;
+
+    use super::{run_id, show, started, Archive, Error};
+    use crate::influxdb::Direction;
+    use crate::models::IPerf3;
+
+    fn fixture() -> IPerf3 {
+        serde_json::from_str(include_str!(
+            "../fixtures/iperf3/3.17-linux-tcp-multistream.json"
+        ))
+        .unwrap()
+    }
+
+    fn archive(dir: &TempDir, retention_days: u32) -> Archive {
+        Archive {
+            path: dir.path().join("archive"),
+            retention_days,
+        }
+    }
+
+    #[test]
+    fn test_run_id_starts_with_the_time() {
+        let id = run_id(datetime!(2024-06-04 16:12:48 UTC));
+
+        assert!(id.starts_with("20240604T161248-"), "{id}");
+        assert_eq!(20, id.len());
+        assert_eq!(Some(datetime!(2024-06-04 16:12:48 UTC)), started(&id));
+    }
+
+    #[tokio::test]
+    async fn test_written_runs_are_found_by_prefix() {
+        let dir = TempDir::new().unwrap();
+        let archive = archive(&dir, 0);
+        let result = fixture();
+
+        let path = archive
+            .write("20240604T161248-00aa", &Direction::Upload, &result)
+            .await
+            .unwrap();
+        archive
+            .write("20240604T161248-00aa", &Direction::Download, &result)
+            .await
+            .unwrap();
+        archive
+            .write("20240605T090000-00bb", &Direction::Upload, &result)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            "20240604T161248-00aa_up_speedtest.init7.net-5201.json.gz",
+            path.file_name().unwrap()
+        );
+
+        let found = archive.find("20240604").await.unwrap();
+        assert_eq!(2, found.len());
+        assert_eq!(result.intervals.len(), found[0].1.intervals.len());
+        assert_eq!(result.start.cookie, found[0].1.start.cookie);
+
+        assert!(matches!(
+            archive.find("2024").await,
+            Err(Error::Ambiguous(..))
+        ));
+        assert!(matches!(
+            archive.find("2023").await,
+            Err(Error::NotFound(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_old_runs_are_pruned() {
+        let dir = TempDir::new().unwrap();
+        let archive = archive(&dir, 30);
+        let result = fixture();
+
+        archive
+            .write("20000101T000000-0001", &Direction::Upload, &result)
+            .await
+            .unwrap();
+        archive
+            .write(
+                &run_id(time::OffsetDateTime::now_utc()),
+                &Direction::Upload,
+                &result,
+            )
+            .await
+            .unwrap();
+
+        let names = archive.names().await.unwrap();
+        assert_eq!(1, names.len());
+        assert!(!names[0].starts_with("2000"));
+    }
+
+    #[test]
+    fn test_show() {
+        let output = show(
+            "20240604T161248-00aa_up_speedtest.init7.net-5201.json.gz",
+            &fixture(),
+        );
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(
+            "up speedtest.init7.net:5201, TCP, 2 streams, 5s, iperf 3.17.1",
+            lines[1]
+        );
+        assert!(lines[2].contains("935.2 Mbit/s"), "{output}");
+        assert!(lines[4].starts_with("  total"), "{output}");
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::task::JoinSet;
+
+use crate::selection;
+use crate::server::{self, Groups, IpVersion, Server};
+
+pub const DEFAULT_REGION: &str = "europe";
+
+lazy_static! {
+    /// Curated public servers per region, embedded from `catalog.list`.
+    pub static ref CATALOG: Groups = server::parse_server_list(include_str!("../catalog.list"))
+        .expect("embedded catalog is valid")
+        .into_iter()
+        .map(|(region, mut servers)| {
+            for server in servers.iter_mut() {
+                server.region.get_or_insert_with(|| region.clone());
+            }
+
+            (region, servers)
+        })
+        .collect();
+}
+
+/// Servers of the catalog `region`.
+pub fn region(name: &str) -> Result<&'static [Server], server::Error> {
+    CATALOG.get(name).map(Vec::as_slice).ok_or_else(|| {
+        server::Error::Region(
+            name.to_string(),
+            CATALOG.keys().cloned().collect::<Vec<_>>().join(", "),
+        )
+    })
+}
+
+/// Connect latency of every target, in the order given.
+async fn probe_all(targets: Vec<(String, u16, IpVersion)>) -> Vec<Option<Duration>> {
+    let mut probes = JoinSet::new();
+    let mut results = vec![None; targets.len()];
+
+    for (index, (host, port, ip)) in targets.into_iter().enumerate() {
+        probes.spawn(async move { (index, selection::probe(&host, port, ip).await) });
+    }
+
+    while let Some(result) = probes.join_next().await {
+        if let Ok((index, latency)) = result {
+            results[index] = latency;
+        }
+    }
+
+    results
+}
+
+fn latency(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("up {}ms", latency.as_millis()),
+        None => "down".to_string(),
+    }
+}
+
+/// Prints the catalog of `regions` with each host's share of the weight and
+/// whether its first port accepts connections.
+pub async fn list(regions: &[&str]) -> Result<(), server::Error> {
+    let mut rows = Vec::new();
+
+    for name in regions {
+        let servers = region(name)?;
+        let total = servers
+            .iter()
+            .map(|server| u64::from(server.weight))
+            .sum::<u64>();
+
+        for server in servers {
+            rows.push((name, server, u64::from(server.weight) * 100 / total.max(1)));
+        }
+    }
+
+    let health = probe_all(
+        rows.iter()
+            .map(|(_, server, _)| (server.host.clone(), server.ports.start, server.ip))
+            .collect(),
+    )
+    .await;
+
+    println!(
+        "{:<14} {:<36} {:<10} {:>6} {:>6}  HEALTH",
+        "REGION", "HOST", "PORTS", "WEIGHT", "SHARE"
+    );
+
+    for ((region, server, share), health) in rows.iter().zip(health) {
+        println!(
+            "{:<14} {:<36} {:<10} {:>6} {:>5}%  {}",
+            region,
+            server.host,
+            server.ports.to_string(),
+            server.weight,
+            share,
+            latency(health)
+        );
+    }
+
+    Ok(())
+}
+
+/// Connects to every port of `servers`, fastest first, and returns how many
+/// of them answered.
+pub async fn probe(servers: &[Server]) -> usize {
+    let targets = servers
+        .iter()
+        .flat_map(|server| {
+            server
+                .ports
+                .iter()
+                .map(|port| (server.host.clone(), port, server.ip))
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = targets
+        .iter()
+        .map(|(host, port, _)| format!("{host}:{port}"))
+        .zip(probe_all(targets.clone()).await)
+        .collect::<Vec<_>>();
+
+    results.sort_by_key(|(_, latency)| latency.unwrap_or(Duration::MAX));
+
+    for (target, health) in results.iter() {
+        println!("{target:<44} {}", latency(*health));
+    }
+
+    let reachable = results
+        .iter()
+        .filter(|(_, health)| health.is_some())
+        .count();
+    println!("{reachable} of {} ports reachable", results.len());
+
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{region, CATALOG, DEFAULT_REGION};
+
+    #[test]
+    fn test_catalog_regions() {
+        for name in ["europe", "north-america", "asia", "oceania"] {
+            let servers = region(name).unwrap();
+
+            assert!(!servers.is_empty(), "{name}");
+            assert!(servers
+                .iter()
+                .all(|server| server.region.as_deref() == Some(name) && server.weight > 0));
+        }
+
+        assert!(CATALOG.contains_key(DEFAULT_REGION));
+    }
+
+    #[test]
+    fn test_unknown_region_lists_available_ones() {
+        let err = region("mars").unwrap_err().to_string();
+
+        assert!(err.contains("mars"));
+        assert!(err.contains("north-america"));
+    }
+}
@@ -1,33 +1,34 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::sync::Arc;
 
 use clap::{Parser, Subcommand};
-use tokio::task::JoinSet;
-
-use crate::influxdb::{Client, Direction, Speed};
-use crate::models::IPerf3;
-use crate::{influxdb, iperf3, timetable};
-use lazy_static::lazy_static;
-
-lazy_static! {
-    static ref EUROPE_SERVERS: Vec<String> = vec![
-        "speedtest.init7.net:10".to_string(),
-        "iperf.online.net:5209:7".to_string(),
-        "speedtest.serverius.net:5002:1".to_string(),
-        "ams.speedtest.clouvider.net:5201:3".to_string(),
-        "ams.speedtest.clouvider.net:5202:7".to_string(),
-        "ams.speedtest.clouvider.net:5203:7".to_string(),
-        "ams.speedtest.clouvider.net:5204:10".to_string(),
-        "ams.speedtest.clouvider.net:5205:10".to_string(),
-        "ams.speedtest.clouvider.net:5206:10".to_string(),
-        "ams.speedtest.clouvider.net:5207:10".to_string(),
-        "ams.speedtest.clouvider.net:5208:4".to_string(),
-        "ams.speedtest.clouvider.net:5209:4".to_string(),
-        "speedtest.ams1.novogara.net:5209:4".to_string(),
-        "speedtest.ams1.novogara.net:5201:3".to_string(),
-        "speedtest.ams1.novogara.net:5202:4".to_string(),
-        "speedtest.ams1.novogara.net:5204:5".to_string(),
-    ];
-}
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::archive::{self, Archive};
+use crate::collector::{self, Sink};
+use crate::history::History;
+use crate::influxdb::{Direction, Outcome, Speed};
+use crate::mesh::{self, Flight, Mesh};
+use crate::models::{IPerf3, Interval};
+use crate::progress::{Live, Progress};
+use crate::report::{self, Format};
+use crate::selection::{self, Selector};
+use crate::server::{Server, DEFAULT_GROUP};
+use crate::timetable::{Mode, Params};
+use crate::webtest::{self, Endpoints, Engine};
+use crate::{
+    catalog, config, grafana, influxdb, iperf3, iperf3d, replay, serve, server, serverlist,
+    timetable,
+};
+
+/// Intervals written to InfluxDB at once while a test runs.
+const BATCH: usize = 10;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -37,177 +38,1187 @@ lazy_static! {
     long_about = "Monitor your network speed with iperf3 and store your data in InfluxDB for later processing."
 )]
 struct Cli {
+    /// Configuration file (TOML), overridden by environment variables and flags
+    #[arg(short, long, env = "SPEEDY_CONFIG")]
+    config: Option<PathBuf>,
+    /// Servers of the default group as `<host>[:<port>[-<port>][:<weight>]]`, the port
+    /// defaults to 5201
     #[arg(short, long, default_value = None)]
-    servers: Option<Vec<String>>,
-    #[arg(short, long, required = false, default_value_t = 7)]
-    timeout: i32,
-    #[arg(short, long, required = false, default_value_t = 3)]
-    retries: i32,
+    servers: Option<Vec<Server>>,
+    /// File with one server per line, reloaded by `serve` when it changes
+    #[arg(long, conflicts_with = "servers")]
+    servers_file: Option<PathBuf>,
+    /// Use the built-in server catalog of a region as the default group, see `servers list`
+    #[arg(long, env = "SPEEDY_REGION")]
+    region: Option<String>,
+    #[arg(short, long, env = "SPEEDY_TIMEOUT")]
+    timeout: Option<i32>,
+    #[arg(short, long, env = "SPEEDY_RETRIES")]
+    retries: Option<i32>,
+    #[arg(long, env = "SPEEDY_INFLUX_HOST")]
+    influx_host: Option<String>,
+    #[arg(long, env = "SPEEDY_INFLUX_BUCKET")]
+    influx_bucket: Option<String>,
+    #[arg(long, env = "SPEEDY_INFLUX_TOKEN", hide_env_values = true)]
+    influx_token: Option<String>,
 
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    /// Print the effective configuration with secrets redacted
+    Show {},
+}
+
+#[derive(Debug, Subcommand)]
+enum ServersCommands {
+    /// Print the built-in catalog (every region unless --region is given) and whether each host is up
+    List {},
+    /// Connect to every port of a server group and report reachability and latency
+    Probe {
+        /// Server group to probe
+        #[arg(short, long, default_value = DEFAULT_GROUP)]
+        group: String,
+    },
+    /// Convert a published iperf3 server list (JSON or CSV) into a file for --servers-file
+    Import {
+        file: PathBuf,
+        /// Format of the list, detected from the file extension by default
+        #[arg(long, value_enum)]
+        format: Option<serverlist::Format>,
+        /// Put every server into this group instead of one group per continent
+        #[arg(short, long)]
+        group: Option<String>,
+        /// File to write, replaced on every import
+        #[arg(short, long, default_value = "servers.toml")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum GrafanaCommands {
+    /// Write a dashboard of the points speedy stores and the files provisioning it with an
+    /// InfluxDB data source, the data source reads its token from SPEEDY_INFLUX_TOKEN
+    Export {
+        /// Directory to write to, mounted by docker-compose.yml
+        #[arg(short, long, default_value = "grafana-dashboards")]
+        output: PathBuf,
+        /// InfluxDB URL as Grafana reaches it, the influx service of docker-compose.yml by
+        /// default
+        #[arg(long, default_value = grafana::COMPOSE_URL)]
+        url: String,
+        /// Measurement queried, [sinks.influxdb] measurement by default
+        #[arg(short, long)]
+        measurement: Option<String>,
+    },
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
-    Run {},
+    Run {
+        /// Print one line per interval even on a terminal
+        #[arg(long)]
+        plain: bool,
+        /// Print the results in this format, only `table` shows live progress
+        #[arg(short, long, value_enum, default_value_t)]
+        output: Format,
+        /// Do not write the results to InfluxDB or a collector, no sink has to be configured
+        #[arg(long)]
+        no_sink: bool,
+        /// Measure with iperf3 or the HTTP endpoints of [engines.http]
+        #[arg(short, long, value_enum, default_value_t)]
+        engine: Engine,
+    },
     Serve {
-        #[arg(short, long, required = false, default_value = "config.timetable")]
-        timetable: String,
+        #[arg(short, long, required = false)]
+        timetable: Option<PathBuf>,
+        /// Seconds to wait for running tests on SIGTERM/SIGINT before cancelling them
+        #[arg(long, required = false, default_value_t = 30)]
+        shutdown_timeout: u64,
+        /// Address of the HTTP endpoint reporting the health of every rule and serving the
+        /// dashboard, e.g. 127.0.0.1:9090. With [api] both need its token
+        #[arg(long)]
+        status_addr: Option<SocketAddr>,
+    },
+    /// Accept results from agents with a [sinks.collector] and write them to InfluxDB
+    Collector {
+        /// Address to listen on, overrides [collector] listen
+        #[arg(short, long)]
+        listen: Option<SocketAddr>,
+    },
+    /// Answer iperf3 clients like `iperf3 -s`, one test at a time, so no iperf3 has to be
+    /// installed on test endpoints
+    Server {
+        /// Address to listen on, `::` accepts IPv6 as well
+        #[arg(short = 'B', long, default_value = "0.0.0.0")]
+        bind: IpAddr,
+        /// TCP and UDP port
+        #[arg(short, long, default_value_t = 5201)]
+        port: u16,
+    },
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    Servers {
+        #[command(subcommand)]
+        command: ServersCommands,
+    },
+    Grafana {
+        #[command(subcommand)]
+        command: GrafanaCommands,
+    },
+    /// Print the raw results a run stored in the archive
+    Show {
+        /// Run ID, or its start such as `20240604T16`, printed when the run is archived
+        run: String,
+        /// Print the stored iperf3 JSON instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write archived iperf3 JSON results (`iperf3 -J`) to InfluxDB, the collector and the
+    /// history, importing a file again overwrites its InfluxDB points instead of duplicating them
+    Import {
+        /// Result files, directories are searched for `.json` files
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
     },
 }
 
+/// How a command ended, turned into the process exit code by `main`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Finished,
+    /// Stopped by a signal after every running test completed.
+    Stopped,
+    /// Stopped by a signal, running tests had to be cancelled.
+    Cancelled,
+}
+
+impl Status {
+    pub fn exit_code(self) -> ExitCode {
+        match self {
+            Status::Finished | Status::Stopped => ExitCode::SUCCESS,
+            Status::Cancelled => ExitCode::from(3),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
-enum Error {
+pub enum Error {
     #[error("IPerf3 Error {0}")]
     IPerf3(#[from] iperf3::Error),
 
+    #[error("HTTP Error {0}")]
+    Http(#[from] webtest::Error),
+
     #[error("InfluxDB Error {0}")]
     InfluxDB(#[from] crate::influxdb::Error),
 }
 
+impl Error {
+    /// What an interrupted test measured before it stopped.
+    fn partial(&self) -> Option<&IPerf3> {
+        match self {
+            Error::IPerf3(iperf3::Error::Partial(result)) => Some(result),
+            Error::Http(webtest::Error::Partial(result)) => Some(result),
+            _ => None,
+        }
+    }
+
+    /// Host and port a failed test was against, if it got that far. iperf3
+    /// errors name one of `servers`, HTTP ones the URL of the endpoint.
+    fn server(&self, servers: &[Server]) -> Option<(String, u16)> {
+        match self {
+            Error::IPerf3(err) => {
+                let name = err.server()?;
+
+                servers.iter().find_map(|server| {
+                    match server
+                        .ports
+                        .iter()
+                        .find(|port| name == format!("{}:{port}", server.host))
+                    {
+                        Some(port) => Some((server.host.clone(), port)),
+                        // Busy mesh peers are named without their port.
+                        None if name == server.host => {
+                            Some((server.host.clone(), server.ports.start))
+                        }
+                        None => None,
+                    }
+                })
+            }
+            Error::Http(webtest::Error::Request(url, _) | webtest::Error::Status(url, _)) => {
+                let url = reqwest::Url::parse(url).ok()?;
+                Some((url.host_str()?.to_string(), url.port_or_known_default()?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether every server tried was running another test.
+    pub fn busy(&self) -> bool {
+        matches!(
+            self,
+            Error::IPerf3(iperf3::Error::Busy(_) | iperf3::Error::AllBusy(..))
+        )
+    }
+}
+
 async fn insert(
     client: &influxdb::Client,
-    result: IPerf3,
+    intervals: &[Interval],
     direction: influxdb::Direction,
     now: time::OffsetDateTime,
+    tags: &[(&str, &str)],
 ) -> Result<(), Error> {
-    let speeds = result.intervals.iter().map(|interval| {
-        let stream = &interval.streams[0];
+    let speeds = intervals.iter().map(|interval| {
+        let sum = &interval.sum;
 
         Speed::new(
-            now + time::Duration::seconds_f64(stream.seconds),
+            now + time::Duration::seconds_f64(sum.end),
             direction.clone(),
-            stream.bits_per_second as u64,
+            sum.bits_per_second as u64,
         )
     });
 
-    client.insert_multiple(speeds).await?;
+    client.insert_multiple(speeds, tags).await?;
 
     Ok(())
 }
 
-async fn run(
-    servers: &[String],
-    client: &crate::influxdb::Client,
-    timeout: i32,
-    retries: i32,
+/// One attempt at testing a direction against `servers` with the engine of
+/// `test`, `true` downloads.
+async fn attempt(
+    test: &Test,
+    servers: Vec<Server>,
+    options: iperf3::Options,
+    download: bool,
+) -> Result<IPerf3, Error> {
+    let (cancel, endpoints) = (&test.cancel, &test.endpoints);
+
+    match (test.engine, download) {
+        (Engine::Iperf3, true) => iperf3::download_speed(&servers, &options, cancel)
+            .await
+            .map_err(Error::from),
+        (Engine::Iperf3, false) => iperf3::upload_speed(&servers, &options, cancel)
+            .await
+            .map_err(Error::from),
+        (Engine::Http, true) => webtest::download_speed(endpoints, &options, cancel)
+            .await
+            .map_err(Error::from),
+        (Engine::Http, false) => webtest::upload_speed(endpoints, &options, cancel)
+            .await
+            .map_err(Error::from),
+    }
+}
+
+/// `tags` with `server` set to the host that ran the test, once one did.
+fn tagged<'a>(tags: &[(&'a str, &'a str)], host: Option<&'a str>) -> Vec<(&'a str, &'a str)> {
+    tags.iter()
+        .copied()
+        .filter(|(key, _)| host.is_none() || *key != "server")
+        .chain(host.map(|host| ("server", host)))
+        .collect()
+}
+
+/// Settings of a single run, the global flags merged with per-rule params.
+#[derive(Debug, Clone)]
+pub struct Test {
+    pub options: iperf3::Options,
+    pub retries: i32,
+    pub direction: timetable::Direction,
+    pub mode: Mode,
+    pub engine: Engine,
+    /// Endpoints of the HTTP engine.
+    pub endpoints: Endpoints,
+    /// Cancels the running iperf3 process, e.g. on shutdown.
+    pub cancel: CancellationToken,
+    /// Shows the intervals as they arrive, only set by `speedy run`.
+    pub progress: Option<Arc<Progress>>,
+    /// Names the raw results of this run in the archive.
+    pub run: String,
+    pub archive: Option<Archive>,
+    /// Peers of `mode=mesh` runs.
+    pub mesh: Mesh,
+    /// Held while the run tests, set when this agent also serves peers.
+    pub flight: Option<Flight>,
+    /// Receives the reports of `progress` once the run is done.
+    pub collector: Option<Sink>,
+    /// Configured tags, posted to the collector with the reports.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Test {
+    pub fn new(
+        timeout: i32,
+        retries: i32,
+        params: &Params,
+        selector: Arc<Selector>,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            options: iperf3::Options {
+                timeout,
+                duration: params
+                    .duration
+                    .map(|duration| duration.whole_seconds() as i32),
+                streams: params.streams.unwrap_or(1),
+                protocol: params.protocol.unwrap_or_default(),
+                bitrate: params
+                    .bitrate
+                    .as_deref()
+                    .and_then(|bitrate| server::parse_bitrate(bitrate).ok()),
+                strategy: params.strategy.unwrap_or_default(),
+                selector,
+                events: None,
+            },
+            retries: params.retries.unwrap_or(retries),
+            direction: params.direction.unwrap_or(timetable::Direction::Both),
+            mode: params.mode.unwrap_or_default(),
+            engine: params.engine.unwrap_or_default(),
+            endpoints: Endpoints::default(),
+            cancel,
+            progress: None,
+            run: archive::run_id(time::OffsetDateTime::now_utc()),
+            archive: None,
+            mesh: Mesh::default(),
+            flight: None,
+            collector: None,
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+pub async fn run(
+    servers: &[Server],
+    client: Option<&crate::influxdb::Client>,
+    test: &Test,
 ) -> Result<(), Error> {
+    let result = match test.mode {
+        // Peers are tested one direction at a time, the flight is taken for each.
+        Mode::Mesh => mesh::run(client, test).await,
+        _ => {
+            let attempt = |servers, options, download| attempt(test, servers, options, download);
+            run_group(servers, client, test, &attempt).await
+        }
+    };
+
+    if let (Some(sink), Some(progress)) = (&test.collector, &test.progress) {
+        match sink.submit(&progress.reports(), &test.tags).await {
+            Ok(()) => eprintln!("Results posted to {}", sink.url),
+            Err(err) => eprintln!("Failed to submit results: {err}"),
+        }
+    }
+
+    result
+}
+
+/// Tests the servers of a group, one after the other with `mode=compare`.
+async fn run_group<A, Fut>(
+    servers: &[Server],
+    client: Option<&crate::influxdb::Client>,
+    test: &Test,
+    attempt: &A,
+) -> Result<(), Error>
+where
+    A: Fn(Vec<Server>, iperf3::Options, bool) -> Fut,
+    Fut: Future<Output = Result<IPerf3, Error>>,
+{
+    let _flight = match test.flight {
+        Some(ref flight) => match flight.acquire(&test.cancel).await {
+            Some(guard) => Some(guard),
+            None => return Err(iperf3::Error::Canceled.into()),
+        },
+        None => None,
+    };
+
+    // The HTTP engine has its own endpoints, there are no servers to compare.
+    if test.mode == Mode::Single || test.engine == Engine::Http {
+        return run_servers(servers, client, test, &[], attempt).await;
+    }
+
+    let mut failed = None;
+
+    // Listed order, so every run of a rule visits the servers at the same offsets.
+    for host in selection::hosts(servers, test.options.protocol) {
+        if test.cancel.is_cancelled() {
+            break;
+        }
+
+        if host.weight == 0 {
+            continue;
+        }
+
+        let group = servers
+            .iter()
+            .filter(|server| server.host == host.name)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        eprintln!("Testing server {}", host.name);
+
+        if let Err(err) = run_servers(&group, client, test, &[("server", host.name)], attempt).await
+        {
+            failed.get_or_insert(err);
+        }
+    }
+
+    match failed {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Tests both directions against `servers`, points are written with `tags`.
+async fn run_servers<A, Fut>(
+    servers: &[Server],
+    client: Option<&crate::influxdb::Client>,
+    test: &Test,
+    tags: &[(&str, &str)],
+    attempt: &A,
+) -> Result<(), Error>
+where
+    A: Fn(Vec<Server>, iperf3::Options, bool) -> Fut,
+    Fut: Future<Output = Result<IPerf3, Error>>,
+{
     let now = time::OffsetDateTime::now_utc();
+    let mut failed = None;
+
+    if test.direction.download() {
+        if let Err(err) = direction(servers, client, test, true, now, tags, attempt).await {
+            eprintln!("Failed to execute download: {}", err);
+            failed.get_or_insert(err);
+        }
+    }
+
+    if test.direction.upload() {
+        if let Err(err) = direction(servers, client, test, false, now, tags, attempt).await {
+            eprintln!("Failed to execute upload: {}", err);
+            failed.get_or_insert(err);
+        }
+    }
+
+    match failed {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Runs one direction with retries.
+pub async fn run_direction(
+    servers: &[Server],
+    client: Option<&crate::influxdb::Client>,
+    test: &Test,
+    download: bool,
+    now: time::OffsetDateTime,
+    tags: &[(&str, &str)],
+) -> Result<(), Error> {
+    let attempt = |servers, options, download| attempt(test, servers, options, download);
+    direction(servers, client, test, download, now, tags, &attempt).await
+}
+
+/// Runs one direction with `attempt` until it passes. Intervals are written in
+/// batches while the test runs so an interrupted test still keeps what it
+/// measured, the ones an attempt that is retried did not write yet are dropped.
+async fn direction<A, Fut>(
+    servers: &[Server],
+    client: Option<&crate::influxdb::Client>,
+    test: &Test,
+    download: bool,
+    now: time::OffsetDateTime,
+    tags: &[(&str, &str)],
+    attempt: &A,
+) -> Result<(), Error>
+where
+    A: Fn(Vec<Server>, iperf3::Options, bool) -> Fut,
+    Fut: Future<Output = Result<IPerf3, Error>>,
+{
+    let direction = match download {
+        true => Direction::Download,
+        false => Direction::Upload,
+    };
 
-    let download = || async {
+    let (sender, mut streams) = mpsc::unbounded_channel();
+
+    // Every attempt sends its events on a channel of its own, closed once it returns.
+    let attempts = async move {
         let mut result = None;
 
-        for _ in 0..retries {
-            match iperf3::download_speed(servers, timeout).await {
+        for _ in 0..test.retries {
+            if test.cancel.is_cancelled() {
+                break;
+            }
+
+            let (events, stream) = mpsc::unbounded_channel();
+            _ = sender.send(stream);
+
+            let mut options = test.options.clone();
+            options.events = Some(events);
+
+            match attempt(servers.to_vec(), options, download).await {
                 Ok(val) => return Ok(val),
+                Err(err) if err.partial().is_some() => return Err(err),
                 Err(err) => result = Some(Err(err)),
             }
         }
 
-        result.unwrap_or(Err(iperf3::Error::Canceled))
+        result.unwrap_or(Err(iperf3::Error::Canceled.into()))
     };
 
-    let upload = || async {
-        let mut result = None;
+    let mut meter = test
+        .progress
+        .as_deref()
+        .map(|progress| progress.meter(direction.clone(), now, tags));
 
-        for _ in 0..retries {
-            match iperf3::upload_speed(servers, timeout).await {
-                Ok(val) => return Ok(val),
-                Err(err) => result = Some(Err(err)),
+    let written = &direction;
+    let write = move |intervals: Vec<Interval>, host: Option<String>| async move {
+        match client {
+            Some(client) if !intervals.is_empty() => {
+                let tags = tagged(tags, host.as_deref());
+                insert(client, &intervals, written.clone(), now, &tags).await
             }
+            _ => Ok(()),
         }
+    };
+
+    let sink = async {
+        let mut inserted = Ok(());
+        let mut host = None;
+        let mut pending = Vec::new();
 
-        result.unwrap_or(Err(iperf3::Error::Canceled))
+        while let Some(mut events) = streams.recv().await {
+            // The attempt before was retried.
+            pending.clear();
+
+            while let Some(event) = events.recv().await {
+                if let Some(ref mut meter) = meter {
+                    meter.event(&event);
+                }
+
+                match event {
+                    iperf3::Event::Start(start) if !start.connecting_to.host.is_empty() => {
+                        host = Some(start.connecting_to.host)
+                    }
+                    iperf3::Event::Interval(interval) => pending.push(interval),
+                    _ => {}
+                }
+
+                if pending.len() >= BATCH {
+                    let batch = std::mem::take(&mut pending);
+                    inserted = inserted.and(write(batch, host.clone()).await);
+                }
+            }
+        }
+
+        inserted = inserted.and(write(pending, host.clone()).await);
+
+        (inserted, host)
     };
 
-    match download().await {
-        Ok(result) => {
-            insert(client, result, Direction::Download, now).await?;
-            println!("Values insert into InfluxDB");
+    let (result, (inserted, host)) = tokio::join!(attempts, sink);
+
+    // Servers turning a test away never report the host themselves.
+    let attempted = result.as_ref().err().and_then(|err| err.server(servers));
+    let host = host.or_else(|| attempted.as_ref().map(|(host, _)| host.clone()));
+
+    if let Some(mut meter) = meter {
+        if let Some((ref host, port)) = attempted {
+            meter.attempted(host, port);
         }
-        Err(err) => eprintln!("Failed to execute download: {}", err),
+
+        meter.done(result.as_ref().err().map(ToString::to_string));
     }
 
-    match upload().await {
-        Ok(result) => {
-            insert(client, result, Direction::Upload, now).await?;
+    let archived = match result {
+        Ok(ref result) => Some(result),
+        Err(ref err) => err.partial(),
+    };
+
+    if let (Some(archive), Some(archived)) = (&test.archive, archived) {
+        match archive.write(&test.run, &direction, archived).await {
+            Ok(path) => eprintln!("Archived run {} in {}", test.run, path.display()),
+            Err(err) => eprintln!("Failed to archive run {}: {}", test.run, err),
         }
-        Err(err) => eprintln!("Failed to execute upload: {}", err),
+    }
+
+    // Busy servers and cancelled tests say nothing about the network.
+    let outcome = match result {
+        Ok(ref result) => Some(Outcome::new(
+            now,
+            direction.clone(),
+            report::mean_rtt_ms(&result.end),
+            false,
+        )),
+        Err(ref err) if err.busy() || test.cancel.is_cancelled() => None,
+        Err(_) => Some(Outcome::new(now, direction.clone(), None, true)),
+    };
+
+    let inserted = match (client, outcome) {
+        (Some(client), Some(outcome)) => match client
+            .insert_outcome(outcome, &tagged(tags, host.as_deref()))
+            .await
+        {
+            Ok(()) => inserted,
+            Err(err) => inserted.and(Err(err.into())),
+        },
+        _ => inserted,
+    };
+
+    result?;
+    inserted?;
+
+    if download && client.is_some() {
+        eprintln!("Values insert into InfluxDB");
     }
 
     Ok(())
 }
 
-pub async fn execute() -> Result<(), Box<dyn std::error::Error>> {
+pub async fn execute() -> Result<Status, Box<dyn std::error::Error>> {
     let cli = Cli::parse();
-    let servers = Arc::new(cli.servers.unwrap_or(EUROPE_SERVERS.clone()));
-    let influx_db_host =
-        std::env::var("SPEEDY_INFLUX_HOST").unwrap_or("http://localhost:8086".to_string());
-    let influx_db_bucket =
-        std::env::var("SPEEDY_INFLUX_BUCKET").unwrap_or("network_speeds".to_string());
-    let influx_db_token = std::env::var("SPEEDY_INFLUX_TOKEN")
-        .expect("Provide an API Token for InfluxDB in SPEEDY_INFLUX_TOKEN environment variable");
-
-    let client = Client::new(&influx_db_host, &influx_db_bucket, &influx_db_token);
+    let mut overrides = config::Overrides {
+        servers: cli.servers,
+        servers_file: cli.servers_file,
+        region: cli.region,
+        timeout: cli.timeout,
+        retries: cli.retries,
+        timetable: None,
+        influx_host: cli.influx_host,
+        influx_bucket: cli.influx_bucket,
+        influx_token: cli.influx_token,
+    };
 
     match cli.command {
-        Commands::Run {} => {
-            run(&servers, &client, cli.timeout, cli.retries).await?;
-            Ok(())
-        }
-        Commands::Serve { timetable } => {
-            let client = Arc::new(client);
-
-            let path = tokio::fs::canonicalize(timetable.as_str()).await?;
-            let file_content = tokio::fs::read_to_string(path).await?;
-            let mut table = match timetable::parse(&file_content) {
-                Ok((_, table)) => table,
-                Err(_) => {
-                    return Err(format!("Failed to parse timetable file ({}).", &timetable).into())
+        Commands::Run {
+            plain,
+            output,
+            no_sink,
+            engine,
+        } => {
+            let config = config::load(cli.config.as_deref(), &overrides).await?;
+            let client = match (no_sink, &config.sinks.collector) {
+                (true, _) => None,
+                // Agents posting to a collector need no InfluxDB credentials.
+                (false, Some(_)) => config.influx_client().ok(),
+                (false, None) => Some(config.influx_client()?),
+            };
+            let cancel = CancellationToken::new();
+            let interrupt = cancel.clone();
+
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    interrupt.cancel();
                 }
+            });
+
+            let progress = Arc::new(Progress::new(match output {
+                Format::Table if plain => Live::Lines,
+                Format::Table => Live::Interactive,
+                _ => Live::Quiet,
+            }));
+            let params = Params {
+                engine: Some(engine),
+                ..Default::default()
             };
+            let mut test = Test::new(
+                config.defaults.timeout,
+                config.defaults.retries,
+                &params,
+                Arc::default(),
+                cancel.clone(),
+            );
+            test.endpoints = config.engines.http.clone();
+            test.progress = Some(Arc::clone(&progress));
+            test.archive = config.sinks.archive.clone();
+            test.tags = config.tags.clone();
+            if !no_sink {
+                test.collector = config.sinks.collector.clone();
+            }
 
-            let invalid_count = table
+            let result = run(config.default_servers()?, client.as_ref(), &test).await;
+            let tags = config
+                .tags
                 .iter()
-                .filter(|item| {
-                    let res = item.end_hour <= 24
-                        && ((item.end_hour - item.start_hour) as i64) < item.duration.whole_hours();
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
 
-                    if res {
-                        eprintln!("Invalid timerange: {item}");
-                    }
+            print!(
+                "{}",
+                report::render(
+                    &progress.reports(),
+                    output,
+                    &config.sinks.influxdb.measurement,
+                    &tags
+                )?
+            );
 
-                    res
-                })
-                .count();
+            match result {
+                _ if cancel.is_cancelled() => Ok(Status::Cancelled),
+                Ok(()) => {
+                    eprintln!("Job finished");
+                    Ok(Status::Finished)
+                }
+                Err(err) => Err(err.into()),
+            }
+        }
+        Commands::Serve {
+            timetable,
+            shutdown_timeout,
+            status_addr,
+        } => {
+            overrides.timetable = timetable;
+
+            let options = serve::Options {
+                config: cli.config,
+                overrides,
+                shutdown_timeout: tokio::time::Duration::from_secs(shutdown_timeout),
+                status_addr,
+            };
 
-            if invalid_count > 0 {
-                return Err("File contains invalid TimeRange".into());
+            serve::serve(options).await
+        }
+        Commands::Collector { listen } => {
+            let config = config::load(cli.config.as_deref(), &overrides).await?;
+
+            if config.collector.agents.is_empty() {
+                return Err("Configure the agents allowed to post in [collector.agents]".into());
             }
 
-            let mut set = JoinSet::new();
-            table.drain(..).for_each(|item| {
-                let c = Arc::clone(&client);
-                let servers = Arc::clone(&servers);
-                let duration =
-                    tokio::time::Duration::from_nanos(item.duration.whole_nanoseconds() as u64);
-                set.spawn(async move {
-                    loop {
-                        let hour = time::OffsetDateTime::now_utc().hour();
+            let client = match config.collector.sqlite {
+                Some(_) => config.influx_client().ok(),
+                None => Some(config.influx_client()?),
+            };
+            let shutdown = CancellationToken::new();
+            let (addr, server) = collector::bind(
+                listen.unwrap_or(config.collector.listen),
+                config.collector.clone(),
+                client.map(|client| client.with_tags(BTreeMap::new())),
+                config.tags.clone(),
+                shutdown.clone(),
+            )?;
+            println!(
+                "Accepting results of {} agents on http://{addr}",
+                config.collector.agents.len()
+            );
 
-                        if hour >= item.start_hour && hour < item.end_hour {
-                            run(&servers, &c, cli.timeout, cli.retries).await.unwrap();
-                        }
+            let mut terminate = signal(SignalKind::terminate())?;
+            let mut interrupt = signal(SignalKind::interrupt())?;
 
-                        tokio::time::sleep(duration).await;
-                    }
-                });
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = interrupt.recv() => {}
+                }
+
+                shutdown.cancel();
+            });
+
+            server.await?;
+
+            Ok(Status::Stopped)
+        }
+        Commands::Server { bind, port } => {
+            let server =
+                iperf3d::Server::bind(SocketAddr::new(bind, port), Flight::default()).await?;
+            println!("Listening for iperf3 clients on {}", server.local_addr()?);
+
+            let shutdown = CancellationToken::new();
+            let stop = shutdown.clone();
+            let mut terminate = signal(SignalKind::terminate())?;
+            let mut interrupt = signal(SignalKind::interrupt())?;
+
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = interrupt.recv() => {}
+                }
+
+                stop.cancel();
             });
 
-            while let Some(_res) = set.join_next().await {}
+            server.run(shutdown).await;
+
+            Ok(Status::Stopped)
+        }
+        Commands::Grafana {
+            command:
+                GrafanaCommands::Export {
+                    output,
+                    url,
+                    measurement,
+                },
+        } => {
+            let config = config::load(cli.config.as_deref(), &overrides).await?;
+            let influxdb = &config.sinks.influxdb;
+            let export = grafana::Export {
+                url,
+                bucket: influxdb.bucket.clone(),
+                measurement: measurement.unwrap_or_else(|| influxdb.measurement.clone()),
+            };
+
+            for path in export.write(&output).await? {
+                println!("Wrote {}", path.display());
+            }
+
+            Ok(Status::Finished)
+        }
+        Commands::Config {
+            command: ConfigCommands::Show {},
+        } => {
+            let config = config::load(cli.config.as_deref(), &overrides).await?;
+            print!("{}", toml::to_string_pretty(&config.redacted())?);
+
+            Ok(Status::Finished)
+        }
+        Commands::Servers {
+            command: ServersCommands::List {},
+        } => {
+            let regions = match overrides.region {
+                Some(ref region) => vec![region.as_str()],
+                None => catalog::CATALOG.keys().map(String::as_str).collect(),
+            };
+
+            catalog::list(&regions).await?;
+
+            Ok(Status::Finished)
+        }
+        Commands::Servers {
+            command: ServersCommands::Probe { group },
+        } => {
+            let config = config::load(cli.config.as_deref(), &overrides).await?;
+            let servers = config
+                .servers
+                .get(&group)
+                .ok_or_else(|| format!("Server group ({group}) does not exist"))?;
+
+            match catalog::probe(servers).await {
+                0 => Err(format!("No server of group ({group}) is reachable").into()),
+                _ => Ok(Status::Finished),
+            }
+        }
+        Commands::Servers {
+            command:
+                ServersCommands::Import {
+                    file,
+                    format,
+                    group,
+                    output,
+                },
+        } => {
+            let summary = serverlist::import(&file, format, group.as_deref(), &output).await?;
+
+            for reason in summary.skipped.iter() {
+                eprintln!("Skipped entry: {reason}");
+            }
+
+            println!(
+                "Imported {} servers in {} groups into {} ({} duplicates merged, {} skipped)",
+                summary.groups.values().map(Vec::len).sum::<usize>(),
+                summary.groups.len(),
+                output.display(),
+                summary.duplicates,
+                summary.skipped.len()
+            );
+
+            Ok(Status::Finished)
+        }
+        Commands::Show { run, json } => {
+            let config = config::load(cli.config.as_deref(), &overrides).await?;
+            let archive = config
+                .sinks
+                .archive
+                .as_ref()
+                .ok_or(archive::Error::NotConfigured)?;
+
+            for (name, result) in archive.find(&run).await? {
+                match json {
+                    true => println!("{}", serde_json::to_string_pretty(&result)?),
+                    false => println!("{}", archive::show(&name, &result)),
+                }
+            }
 
-            Ok(())
+            Ok(Status::Finished)
         }
+        Commands::Import { paths } => {
+            let config = config::load(cli.config.as_deref(), &overrides).await?;
+            let client = match config.sinks.collector {
+                Some(_) => config.influx_client().ok(),
+                None => Some(config.influx_client()?),
+            };
+            let history = match config.sinks.history {
+                Some(ref store) => Some(History::open(store).await?),
+                None => None,
+            };
+            let summary = replay::import(
+                &paths,
+                client.as_ref(),
+                config.sinks.collector.as_ref(),
+                history.as_ref(),
+                &config.tags,
+            )
+            .await?;
+
+            for reason in summary.skipped.iter() {
+                eprintln!("Skipped file: {reason}");
+            }
+
+            println!(
+                "Imported {} points from {} files ({} skipped)",
+                summary.points,
+                summary.files,
+                summary.skipped.len()
+            );
+
+            Ok(Status::Finished)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::future::{self, Ready};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use tokio_util::sync::CancellationToken;
+
+    use super::{run_group, Error, Test};
+    use crate::dashboard;
+    use crate::influxdb::{self, Client};
+    use crate::iperf3::{self, Event, Options};
+    use crate::models::IPerf3;
+    use crate::progress::{Live, Progress};
+    use crate::server::Server;
+    use crate::timetable::{self, Mode, Params};
+
+    /// Attempts answered by `answer` with the number of the attempt, the
+    /// servers and whether it downloads. The events of the result are sent like
+    /// iperf3 sends them, without the end when the attempt fails.
+    fn attempts(
+        answer: impl Fn(usize, &[Server], bool) -> (IPerf3, Result<(), Error>),
+    ) -> impl Fn(Vec<Server>, Options, bool) -> Ready<Result<IPerf3, Error>> {
+        let count = AtomicUsize::new(0);
+
+        move |servers, options, download| {
+            let (result, outcome) =
+                answer(count.fetch_add(1, Ordering::SeqCst), &servers, download);
+
+            if let Some(ref events) = options.events {
+                iperf3::events(&result)
+                    .filter(|event| outcome.is_ok() || !matches!(event, Event::End(_)))
+                    .for_each(|event| _ = events.send(event));
+            }
+
+            future::ready(outcome.map(|()| result))
+        }
+    }
+
+    /// A test against `host` measuring `intervals` one second intervals.
+    fn measured(host: &str, intervals: usize, download: bool) -> IPerf3 {
+        let mut result: IPerf3 = serde_json::from_str(include_str!(
+            "../fixtures/iperf3/3.17-linux-tcp-multistream.json"
+        ))
+        .unwrap();
+
+        result.start.connecting_to.host = host.to_string();
+        result.start.test_start.reverse = download;
+        result.intervals = (0..intervals)
+            .map(|second| {
+                let mut interval = result.intervals[0].clone();
+                interval.sum.start = second as f64;
+                interval.sum.end = second as f64 + 1.0;
+                interval
+            })
+            .collect();
+
+        result
+    }
+
+    /// Tests the first server with a weight.
+    fn passing(_: usize, servers: &[Server], download: bool) -> (IPerf3, Result<(), Error>) {
+        let server = servers.iter().find(|server| server.weight > 0).unwrap();
+
+        (measured(&server.host, 2, download), Ok(()))
+    }
+
+    fn test(mode: Mode) -> Test {
+        let params = Params {
+            mode: Some(mode),
+            ..Default::default()
+        };
+
+        Test::new(7, 1, &params, Arc::default(), CancellationToken::new())
+    }
+
+    /// Field, direction and server of every written point.
+    fn points(writes: &Mutex<Vec<String>>) -> BTreeSet<(String, String, String)> {
+        let writes = writes.lock().unwrap().join("\n");
+
+        writes
+            .lines()
+            .map(|line| {
+                let mut parts = line.split(' ');
+                let tags = parts.next().unwrap().split(',').collect::<Vec<_>>();
+                let tag = |name: &str| {
+                    tags.iter()
+                        .find_map(|tag| tag.strip_prefix(&format!("{name}=")))
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                let field = parts.next().unwrap().split('=').next().unwrap();
+
+                (field.to_string(), tag("direction"), tag("server"))
+            })
+            .collect()
+    }
+
+    fn servers(list: &[&str]) -> Vec<Server> {
+        list.iter().map(|server| server.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_compare_tests_both_directions_against_each_host() {
+        let (addr, writes) = influxdb::fake();
+        let client = Client::new(format!("http://{addr}"), "speeds", "token");
+        let servers = servers(&[
+            "a.example:5201:1",
+            "b.example:5201:0",
+            "c.example:5201-5202:1",
+        ]);
+
+        run_group(
+            &servers,
+            Some(&client),
+            &test(Mode::Compare),
+            &attempts(passing),
+        )
+        .await
+        .unwrap();
+
+        let mut expected = BTreeSet::new();
+        for field in ["failed", "speed"] {
+            for direction in ["down", "up"] {
+                for server in ["a.example", "c.example"] {
+                    expected.insert((field.into(), direction.into(), server.into()));
+                }
+            }
+        }
+
+        assert_eq!(expected, points(&writes));
+    }
+
+    #[tokio::test]
+    async fn test_single_tags_points_with_the_host_tested() {
+        let (addr, writes) = influxdb::fake();
+        let client = Client::new(format!("http://{addr}"), "speeds", "token");
+        let servers = servers(&["b.example:5201:1", "c.example:5201:1"]);
+
+        run_group(
+            &servers,
+            Some(&client),
+            &test(Mode::Single),
+            &attempts(passing),
+        )
+        .await
+        .unwrap();
+
+        let points = points(&writes);
+        assert_eq!(4, points.len(), "{points:?}");
+        assert!(points.iter().all(|(_, _, server)| server == "b.example"));
+    }
+
+    #[tokio::test]
+    async fn test_intervals_are_written_in_batches_without_retried_attempts() {
+        let (addr, writes) = influxdb::fake();
+        let client = Client::new(format!("http://{addr}"), "speeds", "token");
+        let servers = servers(&["a.example:5201:1"]);
+        let mut test = test(Mode::Single);
+        test.retries = 2;
+        test.direction = timetable::Direction::Down;
+        // The first attempt fails after a few intervals, the retry passes.
+        let retried = attempts(|count, _, download| match count {
+            0 => (
+                measured("a.example", 5, download),
+                Err(
+                    iperf3::Error::Command("connection reset".into(), "a.example:5201".into())
+                        .into(),
+                ),
+            ),
+            _ => (measured("b.example", 25, download), Ok(())),
+        });
+
+        run_group(&servers, Some(&client), &test, &retried)
+            .await
+            .unwrap();
+
+        let speeds = writes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|write| write.contains(" speed="))
+            .map(|write| write.lines().count())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![10, 10, 5], speeds);
+        assert!(points(&writes)
+            .iter()
+            .all(|(_, _, server)| server == "b.example"));
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_reported_against_the_server_tried() {
+        let (addr, writes) = influxdb::fake();
+        let client = Client::new(format!("http://{addr}"), "speeds", "token");
+        let progress = Arc::new(Progress::new(Live::Quiet));
+        let mut test = test(Mode::Single);
+        test.direction = timetable::Direction::Down;
+        test.progress = Some(Arc::clone(&progress));
+        // Refused like iperf3 refuses a test, naming no host.
+        let refused = attempts(|_, servers, _| {
+            let result: IPerf3 =
+                serde_json::from_str(include_str!("../fixtures/iperf3/3.17-server-busy.json"))
+                    .unwrap();
+            let name = format!("{}:{}", servers[0].host, servers[0].ports.start);
+            let error = iperf3::Error::Command(result.error.clone().unwrap(), name);
+
+            (result, Err(error.into()))
+        });
+
+        assert!(run_group(
+            &servers(&["a.example:5201:1"]),
+            Some(&client),
+            &test,
+            &refused
+        )
+        .await
+        .is_err());
+
+        let health = dashboard::servers(&progress.reports());
+        assert_eq!(1, health.len());
+        assert_eq!("a.example", health[0].server);
+        assert_eq!(1, health[0].failures);
+        assert_eq!(
+            BTreeSet::from([("failed".into(), "down".into(), "a.example".into())]),
+            points(&writes)
+        );
+    }
+
+    #[test]
+    fn test_errors_name_the_server_tried() {
+        let servers = servers(&["[::1]:5201-5202:1", "mesh.example:5201:1"]);
+        let server = |err: iperf3::Error| Error::from(err).server(&servers);
+
+        assert_eq!(
+            Some(("::1".to_string(), 5202)),
+            server(iperf3::Error::Busy("::1:5202".into()))
+        );
+        assert_eq!(
+            Some(("mesh.example".to_string(), 5201)),
+            server(iperf3::Error::Busy("mesh.example".into()))
+        );
+        assert_eq!(None, server(iperf3::Error::Canceled));
     }
 }
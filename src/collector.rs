@@ -0,0 +1,779 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::http::{bearer, json, same, text};
+use crate::influxdb::Client;
+use crate::report::Report;
+
+const RESULTS: &str = "/api/v1/results";
+const AGENTS: &str = "/api/v1/agents";
+/// Largest batch the collector accepts.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+/// Batches kept while the collector is unreachable, older ones are dropped.
+const MAX_SPOOLED: usize = 10_000;
+const POST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to access spool ({0}): {1}")]
+    IO(String, std::io::Error),
+
+    #[error("Invalid spooled batch ({0}): {1}")]
+    Json(String, serde_json::Error),
+
+    #[error("Failed to post results to {0}: {1}")]
+    Request(String, reqwest::Error),
+
+    #[error("Collector {0} answered with {1}")]
+    Status(String, reqwest::StatusCode),
+
+    #[error("Failed to listen on {0}: {1}")]
+    Bind(SocketAddr, hyper::Error),
+
+    #[error("Failed to read collector state ({0}): {1}")]
+    State(String, std::io::Error),
+
+    #[error("Failed to open the SQLite store ({0}): {1}")]
+    Sqlite(String, rusqlite::Error),
+}
+
+/// One direction of a test as agents post it, with the tags of its points.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Submitted {
+    pub report: Report,
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Batch {
+    pub agent: String,
+    pub results: Vec<Submitted>,
+}
+
+/// `speedy collector`, writes the results agents post to InfluxDB and SQLite.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Collector {
+    pub listen: SocketAddr,
+    /// Token of every agent allowed to post, by agent name.
+    pub agents: BTreeMap<String, String>,
+    /// File keeping when agents were last seen across restarts, only kept in
+    /// memory otherwise.
+    pub state: Option<PathBuf>,
+    /// SQLite database every result is kept in as well, InfluxDB is optional
+    /// with it.
+    pub sqlite: Option<PathBuf>,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8087)),
+            agents: BTreeMap::new(),
+            state: None,
+            sqlite: None,
+        }
+    }
+}
+
+/// Collector an agent posts its results to, batches wait in `spool` while
+/// it can not be reached.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sink {
+    /// Base URL of the collector, e.g. `https://collector.example.net:8087`.
+    pub url: String,
+    /// Name of this agent in the collector's `[collector.agents]`.
+    pub agent: String,
+    pub token: String,
+    pub spool: PathBuf,
+}
+
+impl Sink {
+    fn io(&self, err: std::io::Error) -> Error {
+        Error::IO(self.spool.display().to_string(), err)
+    }
+
+    /// Posts the results of a run in one batch, then whatever is spooled. A
+    /// batch that can not be posted is spooled, oldest batches are posted first.
+    pub async fn submit(
+        &self,
+        reports: &[Report],
+        tags: &BTreeMap<String, String>,
+    ) -> Result<(), Error> {
+        if !reports.is_empty() {
+            let batch = Batch {
+                agent: self.agent.clone(),
+                results: reports
+                    .iter()
+                    .map(|report| {
+                        let mut tags = tags.clone();
+                        tags.extend(report.tags.iter().cloned());
+
+                        Submitted {
+                            report: report.clone(),
+                            tags,
+                        }
+                    })
+                    .collect(),
+            };
+
+            if self.spooled().await?.is_empty() {
+                match self.post(&batch).await {
+                    Ok(()) => return Ok(()),
+                    Err(err) => {
+                        self.spool(&batch).await?;
+                        return Err(err);
+                    }
+                }
+            }
+
+            self.spool(&batch).await?;
+        }
+
+        for path in self.spooled().await? {
+            let content = tokio::fs::read(&path).await.map_err(|err| self.io(err))?;
+
+            match serde_json::from_slice::<Batch>(&content) {
+                Ok(batch) => self.post(&batch).await?,
+                Err(err) => eprintln!("Dropping spooled batch ({}): {err}", path.display()),
+            }
+
+            tokio::fs::remove_file(&path)
+                .await
+                .map_err(|err| self.io(err))?;
+        }
+
+        Ok(())
+    }
+
+    async fn post(&self, batch: &Batch) -> Result<(), Error> {
+        let url = format!("{}{RESULTS}", self.url.trim_end_matches('/'));
+        let body = serde_json::to_vec(batch).map_err(|err| Error::Json(url.clone(), err))?;
+
+        let response = reqwest::Client::new()
+            .post(&url)
+            .bearer_auth(&self.token)
+            .header(CONTENT_TYPE, "application/json")
+            .timeout(POST_TIMEOUT)
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| Error::Request(url.clone(), err))?;
+
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            // Posting it again would not change the answer.
+            reqwest::StatusCode::BAD_REQUEST => {
+                eprintln!("Collector {url} rejected a batch of {}", batch.agent);
+                Ok(())
+            }
+            status => Err(Error::Status(url, status)),
+        }
+    }
+
+    /// Spooled batches, oldest first.
+    async fn spooled(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut entries = match tokio::fs::read_dir(&self.spool).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(self.io(err)),
+        };
+        let mut paths = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await.map_err(|err| self.io(err))? {
+            let path = entry.path();
+
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                paths.push(path);
+            }
+        }
+
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    async fn spool(&self, batch: &Batch) -> Result<(), Error> {
+        let name = format!(
+            "{:020}.json",
+            OffsetDateTime::now_utc().unix_timestamp_nanos()
+        );
+        let path = self.spool.join(name);
+        let content = serde_json::to_vec(batch)
+            .map_err(|err| Error::Json(path.display().to_string(), err))?;
+
+        tokio::fs::create_dir_all(&self.spool)
+            .await
+            .map_err(|err| self.io(err))?;
+        write(&path, &content).await.map_err(|err| self.io(err))?;
+
+        let spooled = self.spooled().await?;
+
+        for path in spooled
+            .iter()
+            .take(spooled.len().saturating_sub(MAX_SPOOLED))
+        {
+            eprintln!("Spool is full, dropping {}", path.display());
+            tokio::fs::remove_file(path)
+                .await
+                .map_err(|err| self.io(err))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes through a temporary file so a crash never leaves half a batch.
+async fn write(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let temporary = path.with_extension("tmp");
+
+    tokio::fs::write(&temporary, content).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Agent {
+    name: String,
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_seen: Option<OffsetDateTime>,
+    /// Results received, since the collector started unless it has a state file.
+    results: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Seen {
+    #[serde(with = "time::serde::rfc3339")]
+    last_seen: OffsetDateTime,
+    results: usize,
+}
+
+/// Results in SQLite, a row per report with the report as the line
+/// `[sinks.history]` writes for it.
+struct Store(Arc<std::sync::Mutex<rusqlite::Connection>>);
+
+impl Store {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let failed = |err| Error::Sqlite(path.display().to_string(), err);
+        let connection = rusqlite::Connection::open(path).map_err(failed)?;
+
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS results (
+                    agent TEXT NOT NULL,
+                    time TEXT NOT NULL,
+                    tags TEXT NOT NULL,
+                    report TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS results_time ON results (time);",
+            )
+            .map_err(failed)?;
+
+        Ok(Self(Arc::new(std::sync::Mutex::new(connection))))
+    }
+
+    /// Inserts the reports of `batch` in one transaction.
+    async fn insert(&self, batch: &Batch) -> Result<(), String> {
+        let rows = batch
+            .results
+            .iter()
+            .map(|submitted| {
+                (
+                    batch.agent.clone(),
+                    submitted.report.time.format(&Rfc3339).unwrap_or_default(),
+                    serde_json::to_string(&submitted.tags).unwrap_or_default(),
+                    serde_json::to_string(&submitted.report).unwrap_or_default(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let connection = Arc::clone(&self.0);
+
+        let inserted = tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap();
+            let transaction = connection.transaction()?;
+
+            for row in rows {
+                transaction.execute(
+                    "INSERT INTO results (agent, time, tags, report) VALUES (?1, ?2, ?3, ?4)",
+                    row,
+                )?;
+            }
+
+            transaction.commit()
+        });
+
+        match inserted.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+struct Ingest {
+    collector: Collector,
+    client: Option<Client>,
+    store: Option<Store>,
+    /// Tags of points agents did not set themselves.
+    tags: BTreeMap<String, String>,
+    seen: Mutex<BTreeMap<String, Seen>>,
+}
+
+/// Agents seen before, as the collector last wrote them to `path`.
+fn load(path: &Path) -> Result<BTreeMap<String, Seen>, Error> {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(Error::State(path.display().to_string(), err)),
+    };
+
+    match serde_json::from_slice(&content) {
+        Ok(seen) => Ok(seen),
+        Err(err) => {
+            eprintln!("Ignoring collector state ({}): {err}", path.display());
+            Ok(BTreeMap::new())
+        }
+    }
+}
+
+impl Ingest {
+    /// Whether `token` belongs to `agent`, or to any agent without one.
+    fn authorized(&self, token: Option<&str>, agent: Option<&str>) -> bool {
+        let Some(token) = token else {
+            return false;
+        };
+
+        self.collector
+            .agents
+            .iter()
+            .filter(|(name, _)| agent.map_or(true, |agent| agent == name.as_str()))
+            .any(|(_, expected)| same(expected, token))
+    }
+
+    async fn results(&self, req: Request<Body>) -> Response<Body> {
+        let token = bearer(req.headers()).map(str::to_string);
+
+        // Only agents get to make the collector buffer a batch.
+        if !self.authorized(token.as_deref(), None) {
+            return text(StatusCode::UNAUTHORIZED, "unknown token");
+        }
+
+        let mut body = req.into_body();
+        let mut content = Vec::new();
+
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) if content.len() + chunk.len() <= MAX_BODY => {
+                    content.extend_from_slice(&chunk)
+                }
+                Ok(_) => return text(StatusCode::PAYLOAD_TOO_LARGE, "batch too large"),
+                Err(err) => return text(StatusCode::BAD_REQUEST, err.to_string()),
+            }
+        }
+
+        let batch = match serde_json::from_slice::<Batch>(&content) {
+            Ok(batch) => batch,
+            Err(err) => return text(StatusCode::BAD_REQUEST, err.to_string()),
+        };
+
+        if !self.authorized(token.as_deref(), Some(&batch.agent)) {
+            eprintln!("Rejected results claiming to be from agent {}", batch.agent);
+            return text(StatusCode::UNAUTHORIZED, "unknown agent or token");
+        }
+
+        let mut points = 0;
+
+        for submitted in batch.results.iter() {
+            let report = &submitted.report;
+            let mut tags = self.tags.clone();
+            tags.extend(submitted.tags.clone());
+            tags.insert("agent".to_string(), batch.agent.clone());
+
+            let tags = tags
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
+
+            let Some(ref client) = self.client else {
+                continue;
+            };
+
+            let written = match report.intervals.is_empty() {
+                true => Ok(()),
+                false => client.insert_multiple(report.speeds(), &tags).await,
+            };
+            let written = match written {
+                Ok(()) => client.insert_outcome(report.outcome(), &tags).await,
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = written {
+                eprintln!("Failed to write results of agent {}: {err}", batch.agent);
+                return text(StatusCode::BAD_GATEWAY, err.to_string());
+            }
+
+            points += report.speeds().count();
+        }
+
+        // After InfluxDB, a batch posted again when a write failed rewrites the
+        // same points there but would add its rows twice.
+        if let Some(ref store) = self.store {
+            if let Err(err) = store.insert(&batch).await {
+                eprintln!("Failed to store results of agent {}: {err}", batch.agent);
+                return text(StatusCode::INTERNAL_SERVER_ERROR, err);
+            }
+        }
+
+        let mut seen = self.seen.lock().await;
+        let agent = seen.entry(batch.agent.clone()).or_insert(Seen {
+            last_seen: OffsetDateTime::UNIX_EPOCH,
+            results: 0,
+        });
+        agent.last_seen = OffsetDateTime::now_utc();
+        agent.results += batch.results.len();
+
+        if let Some(ref path) = self.collector.state {
+            let content = serde_json::to_vec(&*seen).unwrap_or_default();
+
+            // The results are written, only the agent list would be stale.
+            if let Err(err) = write(path, &content).await {
+                eprintln!(
+                    "Failed to write collector state ({}): {err}",
+                    path.display()
+                );
+            }
+        }
+
+        drop(seen);
+
+        println!(
+            "Received {} results ({points} points) from agent {}",
+            batch.results.len(),
+            batch.agent
+        );
+
+        json(
+            StatusCode::OK,
+            &serde_json::json!({ "results": batch.results.len(), "points": points }),
+        )
+    }
+
+    async fn agents(&self) -> Vec<Agent> {
+        let seen = self.seen.lock().await;
+
+        self.collector
+            .agents
+            .keys()
+            .map(|name| Agent {
+                name: name.clone(),
+                last_seen: seen.get(name).map(|seen| seen.last_seen),
+                results: seen.get(name).map_or(0, |seen| seen.results),
+            })
+            .collect()
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, RESULTS) => self.results(req).await,
+            (&Method::GET, AGENTS) => match self.authorized(bearer(req.headers()), None) {
+                true => json(StatusCode::OK, &self.agents().await),
+                false => text(StatusCode::UNAUTHORIZED, "unknown token"),
+            },
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// Binds the collector on `addr`, the returned future serves it until
+/// `shutdown` is cancelled. Points are written with `tags` unless the agent
+/// set them, `client` should not add any.
+pub fn bind(
+    addr: SocketAddr,
+    collector: Collector,
+    client: Option<Client>,
+    tags: BTreeMap<String, String>,
+    shutdown: CancellationToken,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), hyper::Error>>), Error> {
+    let seen = match collector.state {
+        Some(ref path) => load(path)?,
+        None => BTreeMap::new(),
+    };
+    let store = match collector.sqlite {
+        Some(ref path) => Some(Store::open(path)?),
+        None => None,
+    };
+    let ingest = Arc::new(Ingest {
+        collector,
+        client,
+        store,
+        tags,
+        seen: Mutex::new(seen),
+    });
+
+    let make = make_service_fn(move |_| {
+        let ingest = Arc::clone(&ingest);
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let ingest = Arc::clone(&ingest);
+
+                async move { Ok::<_, Infallible>(ingest.handle(req).await) }
+            }))
+        }
+    });
+
+    let server = hyper::Server::try_bind(&addr)
+        .map_err(|err| Error::Bind(addr, err))?
+        .serve(make);
+    let addr = server.local_addr();
+
+    Ok((
+        addr,
+        server.with_graceful_shutdown(async move { shutdown.cancelled().await }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use tempfile::TempDir;
+    use time::macros::datetime;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{bind, Collector, Error, Sink};
+    use crate::influxdb::{self, Client, Direction};
+    use crate::report::{Interval, Report};
+
+    fn serve(collector: Collector, client: Option<Client>) -> SocketAddr {
+        let tags = BTreeMap::from([("site".to_string(), "unknown".to_string())]);
+
+        let (addr, server) = bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Collector {
+                agents: BTreeMap::from([("zurich".to_string(), "secret".to_string())]),
+                ..collector
+            },
+            client,
+            tags,
+            CancellationToken::new(),
+        )
+        .unwrap();
+        tokio::spawn(server);
+
+        addr
+    }
+
+    fn collector(state: Option<PathBuf>) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let (influxdb, writes) = influxdb::fake();
+        let client = Client::new(format!("http://{influxdb}"), "speeds", "token");
+        let collector = Collector {
+            state,
+            ..Default::default()
+        };
+
+        (serve(collector, Some(client)), writes)
+    }
+
+    fn sink(url: String, token: &str, dir: &TempDir) -> Sink {
+        Sink {
+            url,
+            agent: "zurich".to_string(),
+            token: token.to_string(),
+            spool: dir.path().join("spool"),
+        }
+    }
+
+    fn report() -> Report {
+        Report {
+            time: datetime!(2024-06-04 16:12:48 UTC),
+            server: "speedtest.init7.net:5201".to_string(),
+            direction: Direction::Download,
+            bits_per_second: Some(9e8),
+            rtt_ms: None,
+            retransmits: None,
+            intervals: vec![Interval {
+                start: 0.0,
+                end: 1.0,
+                bits_per_second: 9e8,
+                rtt_ms: None,
+                retransmits: None,
+            }],
+            error: None,
+            tags: vec![("server".to_string(), "speedtest.init7.net".to_string())],
+        }
+    }
+
+    async fn list(addr: SocketAddr, token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("http://{addr}/api/v1/agents"))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_results_are_written_with_the_agent() {
+        let (addr, writes) = collector(None);
+        let dir = TempDir::new().unwrap();
+        let sink = sink(format!("http://{addr}"), "secret", &dir);
+        let tags = BTreeMap::from([("site".to_string(), "office".to_string())]);
+
+        sink.submit(&[report()], &tags).await.unwrap();
+
+        let writes = writes.lock().unwrap().join("\n");
+        assert!(writes.contains("agent=zurich"), "{writes}");
+        assert!(writes.contains("server=speedtest.init7.net"), "{writes}");
+        assert!(writes.contains("site=office"), "{writes}");
+        assert!(writes.contains("failed=0i"), "{writes}");
+
+        let agents = list(addr, "secret").await.text().await.unwrap();
+        let agents = serde_json::from_str::<serde_json::Value>(&agents).unwrap();
+        assert_eq!("zurich", agents[0]["name"]);
+        assert_eq!(1, agents[0]["results"].as_u64().unwrap());
+        assert!(agents[0]["last_seen"].is_string());
+
+        assert_eq!(401, list(addr, "guess").await.status());
+    }
+
+    #[tokio::test]
+    async fn test_agents_are_remembered_across_restarts() {
+        let dir = TempDir::new().unwrap();
+        let state = dir.path().join("agents.json");
+        let (addr, _) = collector(Some(state.clone()));
+
+        sink(format!("http://{addr}"), "secret", &dir)
+            .submit(&[report(), report()], &BTreeMap::new())
+            .await
+            .unwrap();
+
+        let (addr, _) = collector(Some(state));
+        let agents = list(addr, "secret").await.text().await.unwrap();
+        let agents = serde_json::from_str::<serde_json::Value>(&agents).unwrap();
+        assert_eq!(2, agents[0]["results"].as_u64().unwrap());
+        assert!(agents[0]["last_seen"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_results_are_kept_in_sqlite_without_influxdb() {
+        let dir = TempDir::new().unwrap();
+        let database = dir.path().join("results.db");
+        let collector = Collector {
+            sqlite: Some(database.clone()),
+            ..Default::default()
+        };
+        let addr = serve(collector, None);
+        let tags = BTreeMap::from([("site".to_string(), "office".to_string())]);
+
+        sink(format!("http://{addr}"), "secret", &dir)
+            .submit(&[report(), report()], &tags)
+            .await
+            .unwrap();
+
+        let connection = rusqlite::Connection::open(database).unwrap();
+        let rows = connection
+            .prepare("SELECT agent, time, tags, report FROM results")
+            .unwrap()
+            .query_map((), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(2, rows.len());
+        let (agent, time, tags, report) = &rows[0];
+        assert_eq!("zurich", agent);
+        assert_eq!("2024-06-04T16:12:48Z", time);
+        assert_eq!(r#"{"server":"speedtest.init7.net","site":"office"}"#, tags);
+        let report = serde_json::from_str::<Report>(report).unwrap();
+        assert_eq!("speedtest.init7.net:5201", report.server);
+        assert_eq!(1, report.intervals.len());
+    }
+
+    #[tokio::test]
+    async fn test_bodies_of_unknown_tokens_are_not_read() {
+        let (addr, _) = collector(None);
+
+        // Far beyond MAX_BODY, rejected before any of it is read.
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/api/v1/results"))
+            .bearer_auth("guess")
+            .body(vec![b' '; 2 * super::MAX_BODY])
+            .send()
+            .await;
+
+        assert!(response.map_or(true, |response| response.status() == 401));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_tokens_are_refused_and_spooled() {
+        let (addr, writes) = collector(None);
+        let dir = TempDir::new().unwrap();
+        let sink = sink(format!("http://{addr}"), "guess", &dir);
+
+        let err = sink
+            .submit(&[report()], &BTreeMap::new())
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(err, Error::Status(_, status) if status == 401),
+            "{err}"
+        );
+        assert_eq!(1, sink.spooled().await.unwrap().len());
+        assert!(writes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spooled_batches_are_posted_once_the_collector_is_back() {
+        let dir = TempDir::new().unwrap();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = sink(
+            format!("http://{}", closed.local_addr().unwrap()),
+            "secret",
+            &dir,
+        );
+        drop(closed);
+
+        assert!(sink.submit(&[report()], &BTreeMap::new()).await.is_err());
+        assert!(sink.submit(&[report()], &BTreeMap::new()).await.is_err());
+        assert_eq!(2, sink.spooled().await.unwrap().len());
+
+        let (addr, writes) = collector(None);
+        sink.url = format!("http://{addr}");
+
+        sink.submit(&[], &BTreeMap::new()).await.unwrap();
+
+        assert!(sink.spooled().await.unwrap().is_empty());
+        // Speeds and outcome of each batch.
+        assert_eq!(4, writes.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::api::Api;
+use crate::archive::Archive;
+use crate::catalog::{self, DEFAULT_REGION};
+use crate::collector::{self, Collector};
+use crate::history;
+use crate::influxdb::Client;
+use crate::mesh::Mesh;
+use crate::server::{self, Groups, Server, DEFAULT_GROUP};
+use crate::timetable::{self, Table};
+use crate::webtest::Endpoints;
+
+pub const DEFAULT_TIMETABLE: &str = "config.timetable";
+const REDACTED: &str = "********";
+/// Tags written by speedy itself.
+const RESERVED_TAGS: [&str; 5] = ["direction", "server", "src_site", "dst_site", "agent"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to read configuration file ({0}): {1}")]
+    IO(String, std::io::Error),
+
+    #[error("Invalid configuration file ({0}): {1}")]
+    Toml(String, toml::de::Error),
+
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Timetable(#[from] timetable::Error),
+
+    #[error(transparent)]
+    Server(#[from] server::Error),
+
+    #[error("Provide an API Token for InfluxDB in [sinks.influxdb] token, --influx-token or the SPEEDY_INFLUX_TOKEN environment variable")]
+    MissingToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxDb {
+    pub host: String,
+    pub bucket: String,
+    pub token: Option<String>,
+    pub measurement: String,
+}
+
+impl Default for InfluxDb {
+    fn default() -> Self {
+        Self {
+            host: "http://localhost:8086".to_string(),
+            bucket: "network_speeds".to_string(),
+            token: None,
+            measurement: "network_speeds".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sinks {
+    pub influxdb: InfluxDb,
+    /// Keeps the raw iperf3 result of every run, see `speedy show`.
+    pub archive: Option<Archive>,
+    /// Keeps recent results for the dashboard of `serve`, in memory otherwise.
+    pub history: Option<history::Store>,
+    /// Posts results to a `speedy collector` as well.
+    pub collector: Option<collector::Sink>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Engines {
+    /// Used by rules with `engine=http` and `run --engine http`.
+    pub http: Endpoints,
+}
+
+/// Either a timetable file or inline rules in the timetable syntax.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Schedule {
+    pub timetable: Option<PathBuf>,
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    pub timeout: i32,
+    pub retries: i32,
+    /// Catalog region used when no `default` server group is configured.
+    pub region: Option<String>,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Self {
+            timeout: 7,
+            retries: 3,
+            region: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub defaults: Defaults,
+    pub schedule: Schedule,
+    pub sinks: Sinks,
+    pub engines: Engines,
+    pub mesh: Mesh,
+    /// Used by `speedy collector` only.
+    pub collector: Collector,
+    /// Remote control of `speedy serve`, read once at start.
+    pub api: Option<Api>,
+    /// Extra tags attached to every stored point.
+    pub tags: BTreeMap<String, String>,
+    /// Server groups, rules without `servers=` use the `default` group.
+    pub servers: Groups,
+}
+
+/// Values given on the command line or through environment variables,
+/// they take precedence over the configuration file.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub servers: Option<Vec<Server>>,
+    pub servers_file: Option<PathBuf>,
+    /// Replaces the `default` group with a catalog region.
+    pub region: Option<String>,
+    pub timeout: Option<i32>,
+    pub retries: Option<i32>,
+    pub timetable: Option<PathBuf>,
+    pub influx_host: Option<String>,
+    pub influx_bucket: Option<String>,
+    pub influx_token: Option<String>,
+}
+
+impl Config {
+    /// Parses and validates a configuration file without applying overrides.
+    pub fn parse(content: &str, name: &str) -> Result<Self, Error> {
+        let config: Config =
+            toml::from_str(content).map_err(|err| Error::Toml(name.to_string(), err))?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.defaults.timeout < 3 {
+            return Err(Error::Invalid(
+                "defaults.timeout has to be at least 3 seconds".to_string(),
+            ));
+        }
+
+        if self.defaults.retries < 1 {
+            return Err(Error::Invalid(
+                "defaults.retries has to be at least 1".to_string(),
+            ));
+        }
+
+        if let Some(ref region) = self.defaults.region {
+            catalog::region(region)?;
+        }
+
+        if self.schedule.timetable.is_some() && !self.schedule.rules.is_empty() {
+            return Err(Error::Invalid(
+                "schedule.timetable and schedule.rules cannot be used together".to_string(),
+            ));
+        }
+
+        let host = &self.sinks.influxdb.host;
+        if !host.starts_with("http://") && !host.starts_with("https://") {
+            return Err(Error::Invalid(format!(
+                "sinks.influxdb.host ({host}) has to be an http:// or https:// URL"
+            )));
+        }
+
+        if let Some(key) = self
+            .tags
+            .keys()
+            .find(|key| key.is_empty() || RESERVED_TAGS.contains(&key.as_str()))
+        {
+            return Err(Error::Invalid(format!("tag ({key}) is reserved or empty")));
+        }
+
+        if self.mesh.site.is_empty() && !self.mesh.peers.is_empty() {
+            return Err(Error::Invalid(
+                "mesh.site has to be set to test mesh.peers".to_string(),
+            ));
+        }
+
+        if let Some(ref sink) = self.sinks.collector {
+            if !sink.url.starts_with("http://") && !sink.url.starts_with("https://") {
+                return Err(Error::Invalid(format!(
+                    "sinks.collector.url ({}) has to be an http:// or https:// URL",
+                    sink.url
+                )));
+            }
+
+            if sink.agent.is_empty() || sink.token.is_empty() {
+                return Err(Error::Invalid(
+                    "sinks.collector.agent and sinks.collector.token cannot be empty".to_string(),
+                ));
+            }
+        }
+
+        if let Some((name, _)) = self
+            .collector
+            .agents
+            .iter()
+            .find(|(_, token)| token.is_empty())
+        {
+            return Err(Error::Invalid(format!(
+                "collector.agents ({name}) needs a token"
+            )));
+        }
+
+        if self.api.as_ref().is_some_and(|api| api.token.is_empty()) {
+            return Err(Error::Invalid("api.token cannot be empty".to_string()));
+        }
+
+        if let Some((name, _)) = self.servers.iter().find(|(_, list)| list.is_empty()) {
+            return Err(Error::Invalid(format!(
+                "server group ({name}) does not contain any server"
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, overrides: &Overrides) {
+        if let Some(timeout) = overrides.timeout {
+            self.defaults.timeout = timeout;
+        }
+        if let Some(retries) = overrides.retries {
+            self.defaults.retries = retries;
+        }
+        if let Some(ref timetable) = overrides.timetable {
+            self.schedule = Schedule {
+                timetable: Some(timetable.clone()),
+                rules: Vec::new(),
+            };
+        }
+        if let Some(ref host) = overrides.influx_host {
+            self.sinks.influxdb.host = host.clone();
+        }
+        if let Some(ref bucket) = overrides.influx_bucket {
+            self.sinks.influxdb.bucket = bucket.clone();
+        }
+        if let Some(ref token) = overrides.influx_token {
+            self.sinks.influxdb.token = Some(token.clone());
+        }
+        if let Some(ref region) = overrides.region {
+            self.defaults.region = Some(region.clone());
+            self.servers.remove(DEFAULT_GROUP);
+        }
+        if let Some(ref servers) = overrides.servers {
+            self.servers
+                .insert(DEFAULT_GROUP.to_string(), servers.clone());
+        }
+    }
+
+    /// Servers of the `default` group.
+    pub fn default_servers(&self) -> Result<&[Server], Error> {
+        self.servers
+            .get(DEFAULT_GROUP)
+            .map(Vec::as_slice)
+            .ok_or_else(|| Error::Invalid(format!("server group ({DEFAULT_GROUP}) is not defined")))
+    }
+
+    pub fn influx_token(&self) -> Result<&str, Error> {
+        self.sinks
+            .influxdb
+            .token
+            .as_deref()
+            .ok_or(Error::MissingToken)
+    }
+
+    pub fn influx_client(&self) -> Result<Client, Error> {
+        let influxdb = &self.sinks.influxdb;
+
+        Ok(
+            Client::new(&influxdb.host, &influxdb.bucket, self.influx_token()?)
+                .with_measurement(influxdb.measurement.as_str())
+                .with_tags(self.tags.clone()),
+        )
+    }
+
+    /// Timetable file the schedule is read from, if it is not inline.
+    pub fn timetable_path(&self) -> Option<PathBuf> {
+        match self.schedule.timetable {
+            Some(ref path) => Some(path.clone()),
+            None if self.schedule.rules.is_empty() => Some(PathBuf::from(DEFAULT_TIMETABLE)),
+            None => None,
+        }
+    }
+
+    /// Reads the schedule from the timetable file or the inline rules.
+    pub async fn schedule(&self) -> Result<Vec<Table>, Error> {
+        match self.timetable_path() {
+            Some(path) => Ok(timetable::read(&path).await?),
+            None => Ok(timetable::load(
+                &self.schedule.rules.join("\n"),
+                "schedule.rules",
+            )?),
+        }
+    }
+
+    /// Copy of the configuration that is safe to print.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+
+        if config.sinks.influxdb.token.is_some() {
+            config.sinks.influxdb.token = Some(REDACTED.to_string());
+        }
+
+        if let Some(ref mut sink) = config.sinks.collector {
+            sink.token = REDACTED.to_string();
+        }
+
+        if let Some(ref mut api) = config.api {
+            api.token = REDACTED.to_string();
+        }
+
+        for token in config.collector.agents.values_mut() {
+            *token = REDACTED.to_string();
+        }
+
+        config
+    }
+}
+
+/// Builds the effective configuration: built-in defaults, then the file at
+/// `path`, then `overrides`.
+pub async fn load(path: Option<&Path>, overrides: &Overrides) -> Result<Config, Error> {
+    let mut config = match path {
+        Some(path) => {
+            let name = path.display().to_string();
+            let content = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|err| Error::IO(name.clone(), err))?;
+
+            Config::parse(&content, &name)?
+        }
+        None => Config::default(),
+    };
+
+    if let Some(ref path) = overrides.servers_file {
+        config.servers = server::read_server_list(path).await?;
+    }
+
+    config.apply(overrides);
+    config.validate()?;
+
+    if !config.servers.contains_key(DEFAULT_GROUP) {
+        let region = config.defaults.region.as_deref().unwrap_or(DEFAULT_REGION);
+        let servers = catalog::region(region)?.to_vec();
+
+        config.servers.insert(DEFAULT_GROUP.to_string(), servers);
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, Config, Overrides, DEFAULT_GROUP};
+
+    const EXAMPLE: &str = include_str!("../config.example.toml");
+
+    #[test]
+    fn test_parse_example() {
+        let config = Config::parse(EXAMPLE, "config.example.toml").unwrap();
+
+        assert_eq!(7, config.defaults.timeout);
+        assert_eq!("network_speeds", config.sinks.influxdb.measurement);
+        assert!(config.servers.contains_key("default"));
+        assert!(config.servers.contains_key("eu-core"));
+        assert_eq!(Some("office"), config.tags.get("site").map(String::as_str));
+        assert_eq!(None, config.sinks.archive);
+
+        let config = Config::parse("[sinks.archive]\npath = \"archive\"\n", "speedy.toml").unwrap();
+        assert_eq!(30, config.sinks.archive.unwrap().retention_days);
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        let err = Config::parse("[defaults]\ntimeout = 10\nretry = 3\n", "speedy.toml")
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("speedy.toml"));
+        assert!(err.contains("retry"));
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        assert!(Config::parse("[defaults]\nretries = 0\n", "speedy.toml").is_err());
+        assert!(Config::parse("[sinks.influxdb]\nhost = \"localhost\"\n", "speedy.toml").is_err());
+        assert!(Config::parse("[tags]\ndirection = \"x\"\n", "speedy.toml").is_err());
+        assert!(Config::parse("[tags]\nserver = \"x\"\n", "speedy.toml").is_err());
+        assert!(Config::parse("[servers]\neu = []\n", "speedy.toml").is_err());
+        assert!(Config::parse("[tags]\nsrc_site = \"x\"\n", "speedy.toml").is_err());
+        assert!(Config::parse("[mesh.peers]\nberlin = \"10.0.0.2\"\n", "speedy.toml").is_err());
+        assert!(Config::parse("[tags]\nagent = \"x\"\n", "speedy.toml").is_err());
+        assert!(Config::parse(
+            "[api]\nlisten = \"127.0.0.1:9091\"\ntoken = \"\"\n",
+            "speedy.toml"
+        )
+        .is_err());
+        assert!(Config::parse("[collector.agents]\nzurich = \"\"\n", "speedy.toml").is_err());
+        assert!(Config::parse(
+            "[sinks.collector]\nurl = \"collector\"\nagent = \"a\"\ntoken = \"t\"\nspool = \"s\"\n",
+            "speedy.toml"
+        )
+        .is_err());
+        assert!(Config::parse(
+            "[schedule]\ntimetable = \"a\"\nrules = [\"0-3 10m\"]\n",
+            "speedy.toml"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_overrides_take_precedence() {
+        let mut config = Config::parse(EXAMPLE, "config.example.toml").unwrap();
+
+        config.apply(&Overrides {
+            timeout: Some(20),
+            influx_token: Some("secret".to_string()),
+            servers: Some(vec!["localhost:5201:1".parse().unwrap()]),
+            ..Default::default()
+        });
+
+        assert_eq!(20, config.defaults.timeout);
+        assert_eq!(3, config.defaults.retries);
+        assert_eq!(
+            "localhost:5201:1",
+            config.default_servers().unwrap()[0].to_string()
+        );
+        assert_eq!("secret", config.influx_token().unwrap());
+    }
+
+    #[test]
+    fn test_redacted_hides_token() {
+        let mut config = Config::default();
+        config.sinks.influxdb.token = Some("secret".to_string());
+        config
+            .collector
+            .agents
+            .insert("zurich".to_string(), "secret".to_string());
+
+        let shown = toml::to_string(&config.redacted()).unwrap();
+
+        assert!(!shown.contains("secret"));
+        assert!(shown.contains("********"));
+    }
+
+    #[tokio::test]
+    async fn test_region_fills_the_default_group() {
+        let config = load(None, &Overrides::default()).await.unwrap();
+        assert_eq!(
+            Some("europe"),
+            config.default_servers().unwrap()[0].region.as_deref()
+        );
+
+        let config = load(
+            None,
+            &Overrides {
+                region: Some("asia".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            Some("asia"),
+            config.servers[DEFAULT_GROUP][0].region.as_deref()
+        );
+
+        let err = load(
+            None,
+            &Overrides {
+                region: Some("mars".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("mars"));
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::history::History;
+use crate::influxdb::Direction;
+use crate::report::Report;
+use crate::scheduler::{Board, Health};
+
+/// The dashboard, it fetches everything it shows from `/dashboard.json`.
+pub const PAGE: &str = include_str!("../assets/dashboard.html");
+
+/// One test direction as the dashboard charts it, without its intervals.
+#[derive(Debug, Clone, Serialize)]
+struct Point {
+    #[serde(with = "time::serde::rfc3339")]
+    time: OffsetDateTime,
+    server: String,
+    direction: String,
+    bits_per_second: Option<f64>,
+    rtt_ms: Option<f64>,
+    error: Option<String>,
+}
+
+/// How a server fared in the shown range.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ServerHealth {
+    pub server: String,
+    pub tests: usize,
+    pub failures: usize,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_success: Option<OffsetDateTime>,
+    pub last_error: Option<String>,
+    /// Of the latest successful test of each direction.
+    pub download: Option<f64>,
+    pub upload: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Overview<'a> {
+    host: &'a str,
+    paused: bool,
+    rules: Vec<Health>,
+    servers: Vec<ServerHealth>,
+    points: Vec<Point>,
+}
+
+/// Length of a chart range as the page asks for it, `24h` or `7d`.
+pub fn range(query: Option<&str>) -> Result<time::Duration, String> {
+    let range = query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("range="));
+
+    match range {
+        None | Some("24h") => Ok(time::Duration::hours(24)),
+        Some("7d") => Ok(time::Duration::days(7)),
+        Some(other) => Err(format!("range ({other}) has to be 24h or 7d")),
+    }
+}
+
+/// Health of every server `reports` reached, by host.
+pub fn servers(reports: &[Report]) -> Vec<ServerHealth> {
+    let mut servers = BTreeMap::<&str, ServerHealth>::new();
+
+    // Without a server the test failed before reaching any.
+    for report in reports.iter().filter(|report| !report.server.is_empty()) {
+        let host = report
+            .server
+            .rsplit_once(':')
+            .map_or(report.server.as_str(), |(host, _)| host);
+        let health = servers.entry(host).or_insert_with(|| ServerHealth {
+            server: host.to_string(),
+            ..Default::default()
+        });
+
+        health.tests += 1;
+
+        match report.error {
+            Some(ref error) => {
+                health.failures += 1;
+                health.last_error = Some(error.clone());
+            }
+            None => {
+                health.last_success = Some(report.time);
+
+                match report.direction {
+                    Direction::Download => health.download = report.bits_per_second,
+                    Direction::Upload => health.upload = report.bits_per_second,
+                }
+            }
+        }
+    }
+
+    servers.into_values().collect()
+}
+
+/// Everything the dashboard shows for the last `range`.
+pub async fn overview<'a>(
+    host: &'a str,
+    board: &Board,
+    history: &History,
+    range: time::Duration,
+) -> Overview<'a> {
+    let reports = history.since(OffsetDateTime::now_utc() - range).await;
+
+    Overview {
+        host,
+        paused: board.paused(),
+        rules: board.snapshot(),
+        servers: servers(&reports),
+        points: reports
+            .into_iter()
+            .map(|report| Point {
+                time: report.time,
+                server: report.server,
+                direction: report.direction.to_string(),
+                bits_per_second: report.bits_per_second,
+                rtt_ms: report.rtt_ms,
+                error: report.error,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::{range, servers};
+    use crate::influxdb::Direction;
+    use crate::iperf3::{self, Error};
+    use crate::models::IPerf3;
+    use crate::report::{Builder, Report};
+
+    fn report(server: &str, direction: Direction, error: Option<&str>) -> Report {
+        Report {
+            time: datetime!(2024-06-04 16:12:48 UTC),
+            server: server.to_string(),
+            direction,
+            bits_per_second: error.is_none().then_some(9e8),
+            rtt_ms: None,
+            retransmits: None,
+            intervals: Vec::new(),
+            error: error.map(str::to_string),
+            tags: Vec::new(),
+        }
+    }
+
+    /// Report of a test the server turned away, which names no host.
+    fn busy(host: &str, port: u16) -> Report {
+        let result: IPerf3 =
+            serde_json::from_str(include_str!("../fixtures/iperf3/3.17-server-busy.json")).unwrap();
+        let error = Error::Busy(format!("{host}:{port}"));
+        let mut builder =
+            Builder::new(Direction::Download, datetime!(2024-06-04 16:12:48 UTC), &[]);
+
+        iperf3::events(&result).for_each(|event| builder.event(&event));
+        builder.attempted(host, port);
+        builder.build(Some(error.to_string()))
+    }
+
+    #[test]
+    fn test_servers_are_grouped_by_host() {
+        let health = servers(&[
+            report("speedtest.init7.net:5201", Direction::Download, None),
+            report("speedtest.init7.net:5203", Direction::Upload, None),
+            busy("ping.online.net", 5209),
+            report("", Direction::Download, Some("no server reached")),
+        ]);
+
+        assert_eq!(2, health.len());
+        assert_eq!("ping.online.net", health[0].server);
+        assert_eq!((1, 1), (health[0].tests, health[0].failures));
+        assert_eq!(
+            Some("iperf3 server is busy (Server ping.online.net:5209)"),
+            health[0].last_error.as_deref()
+        );
+        assert_eq!(None, health[0].last_success);
+
+        assert_eq!("speedtest.init7.net", health[1].server);
+        assert_eq!((2, 0), (health[1].tests, health[1].failures));
+        assert_eq!(
+            (Some(9e8), Some(9e8)),
+            (health[1].download, health[1].upload)
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq!(Ok(time::Duration::hours(24)), range(None));
+        assert_eq!(Ok(time::Duration::days(7)), range(Some("range=7d")));
+        assert!(range(Some("range=1y")).is_err());
+    }
+}
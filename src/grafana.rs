@@ -0,0 +1,421 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+/// Data source the dashboard queries, created by the provisioning file.
+const DATASOURCE_UID: &str = "speedy-influxdb";
+/// Where docker-compose.yml mounts the exported dashboards.
+const DASHBOARDS_PATH: &str = "/etc/grafana/dashboards";
+/// InfluxDB as Grafana reaches it in docker-compose.yml.
+pub const COMPOSE_URL: &str = "http://influx:8086";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to write Grafana files ({0}): {1}")]
+    IO(String, std::io::Error),
+}
+
+/// What the exported files point Grafana at.
+#[derive(Debug, Clone)]
+pub struct Export {
+    /// InfluxDB as Grafana reaches it.
+    pub url: String,
+    pub bucket: String,
+    pub measurement: String,
+}
+
+/// Quotes an InfluxQL identifier.
+fn identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quotes a YAML scalar, JSON strings are valid YAML.
+fn scalar(value: &str) -> String {
+    Value::from(value).to_string()
+}
+
+impl Export {
+    fn query(&self, select: &str, filter: &str, group_by: &str) -> String {
+        let filter = match filter {
+            "" => String::new(),
+            filter => format!("{filter} AND "),
+        };
+        let group_by = match group_by {
+            "" => String::new(),
+            group_by => format!(" GROUP BY {group_by}"),
+        };
+
+        format!(
+            "SELECT {select} FROM {} WHERE {filter}$timeFilter{group_by}",
+            identifier(&self.measurement)
+        )
+    }
+
+    /// Dashboard of every field and tag speedy writes, see
+    /// [`crate::influxdb::Speed`] and [`crate::influxdb::Outcome`].
+    pub fn dashboard(&self) -> Value {
+        let speed = r#"mean("speed") / 1000000"#;
+        let per_interval = "time($__interval)";
+
+        let panels = vec![
+            stat(
+                "Mean download",
+                "Mbits",
+                self.query(speed, r#""direction" = 'down'"#, ""),
+                (0, 0),
+            ),
+            stat(
+                "Mean upload",
+                "Mbits",
+                self.query(speed, r#""direction" = 'up'"#, ""),
+                (6, 0),
+            ),
+            stat(
+                "Failed tests",
+                "percent",
+                self.query(r#"mean("failed") * 100"#, "", ""),
+                (12, 0),
+            ),
+            stat(
+                "Mean RTT",
+                "ms",
+                self.query(r#"mean("rtt_ms")"#, "", ""),
+                (18, 0),
+            ),
+            series(
+                "Throughput",
+                "Mbits",
+                self.query(
+                    speed,
+                    "",
+                    &format!(r#"{per_interval}, "direction" fill(null)"#),
+                ),
+                "$tag_direction",
+                (0, 4, 24),
+            ),
+            series(
+                "Download per server",
+                "Mbits",
+                self.query(
+                    speed,
+                    r#""direction" = 'down'"#,
+                    &format!(r#"{per_interval}, "server" fill(null)"#),
+                ),
+                "$tag_server",
+                (0, 12, 12),
+            ),
+            series(
+                "Upload per server",
+                "Mbits",
+                self.query(
+                    speed,
+                    r#""direction" = 'up'"#,
+                    &format!(r#"{per_interval}, "server" fill(null)"#),
+                ),
+                "$tag_server",
+                (12, 12, 12),
+            ),
+            series(
+                "Latency",
+                "ms",
+                self.query(
+                    r#"mean("rtt_ms")"#,
+                    "",
+                    &format!(r#"{per_interval}, "server" fill(null)"#),
+                ),
+                "$tag_server",
+                (0, 20, 12),
+            ),
+            bars(
+                "Failures",
+                self.query(r#"sum("failed")"#, "", r#"time(1h), "server" fill(0)"#),
+                "$tag_server",
+                (12, 20, 12),
+            ),
+            series(
+                "Site to site",
+                "Mbits",
+                self.query(
+                    speed,
+                    r#""src_site" <> ''"#,
+                    &format!(r#"{per_interval}, "src_site", "dst_site", "direction" fill(null)"#),
+                ),
+                "$tag_src_site → $tag_dst_site $tag_direction",
+                (0, 28, 24),
+            ),
+        ];
+
+        json!({
+            "uid": "speedy",
+            "title": "Speedy",
+            "description": format!(
+                "Speeds, latency and failures written by speedy to {}",
+                self.measurement
+            ),
+            "tags": ["speedy"],
+            "editable": true,
+            "schemaVersion": 38,
+            "time": { "from": "now-24h", "to": "now" },
+            "refresh": "1m",
+            "panels": panels
+                .into_iter()
+                .enumerate()
+                .map(|(id, mut panel)| {
+                    panel["id"] = json!(id + 1);
+                    panel
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// InfluxQL data source, the token is read from `SPEEDY_INFLUX_TOKEN` when
+    /// Grafana starts.
+    pub fn datasource(&self) -> String {
+        format!(
+            r#"apiVersion: 1
+datasources:
+  - name: speedy
+    uid: {DATASOURCE_UID}
+    type: influxdb
+    access: proxy
+    url: {}
+    jsonData:
+      dbName: {}
+      httpMode: GET
+      httpHeaderName1: Authorization
+    secureJsonData:
+      httpHeaderValue1: "Token ${{SPEEDY_INFLUX_TOKEN}}"
+"#,
+            scalar(&self.url),
+            scalar(&self.bucket)
+        )
+    }
+
+    /// Loads every dashboard in [`DASHBOARDS_PATH`].
+    pub fn provider(&self) -> String {
+        format!(
+            r#"apiVersion: 1
+providers:
+  - name: speedy
+    type: file
+    options:
+      path: {DASHBOARDS_PATH}
+"#
+        )
+    }
+
+    /// Writes the dashboard and provisioning files below `dir`, laid out to
+    /// be mounted by docker-compose.yml.
+    pub async fn write(&self, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        let dashboard = serde_json::to_string_pretty(&self.dashboard()).unwrap_or_default() + "\n";
+        let files = [
+            (dir.join("dashboards/speedy.json"), dashboard),
+            (
+                dir.join("provisioning/datasources/speedy.yaml"),
+                self.datasource(),
+            ),
+            (
+                dir.join("provisioning/dashboards/speedy.yaml"),
+                self.provider(),
+            ),
+        ];
+
+        for (path, content) in files.iter() {
+            let written = async {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                tokio::fs::write(path, content).await
+            };
+
+            written
+                .await
+                .map_err(|err| Error::IO(path.display().to_string(), err))?;
+        }
+
+        Ok(files.into_iter().map(|(path, _)| path).collect())
+    }
+}
+
+fn target(query: String, alias: &str) -> Value {
+    json!({
+        "refId": "A",
+        "datasource": { "type": "influxdb", "uid": DATASOURCE_UID },
+        "rawQuery": true,
+        "resultFormat": "time_series",
+        "query": query,
+        "alias": alias,
+    })
+}
+
+fn stat(title: &str, unit: &str, query: String, (x, y): (u32, u32)) -> Value {
+    json!({
+        "type": "stat",
+        "title": title,
+        "datasource": { "type": "influxdb", "uid": DATASOURCE_UID },
+        "gridPos": { "x": x, "y": y, "w": 6, "h": 4 },
+        "fieldConfig": { "defaults": { "unit": unit, "decimals": 1 }, "overrides": [] },
+        "options": { "reduceOptions": { "calcs": ["mean"], "fields": "", "values": false } },
+        "targets": [target(query, "")],
+    })
+}
+
+fn series(
+    title: &str,
+    unit: &str,
+    query: String,
+    alias: &str,
+    (x, y, w): (u32, u32, u32),
+) -> Value {
+    json!({
+        "type": "timeseries",
+        "title": title,
+        "datasource": { "type": "influxdb", "uid": DATASOURCE_UID },
+        "gridPos": { "x": x, "y": y, "w": w, "h": 8 },
+        "fieldConfig": {
+            "defaults": { "unit": unit, "custom": { "spanNulls": 3_600_000 } },
+            "overrides": [],
+        },
+        "targets": [target(query, alias)],
+    })
+}
+
+fn bars(title: &str, query: String, alias: &str, (x, y, w): (u32, u32, u32)) -> Value {
+    json!({
+        "type": "timeseries",
+        "title": title,
+        "datasource": { "type": "influxdb", "uid": DATASOURCE_UID },
+        "gridPos": { "x": x, "y": y, "w": w, "h": 8 },
+        "fieldConfig": {
+            "defaults": {
+                "unit": "short",
+                "decimals": 0,
+                "custom": { "drawStyle": "bars", "fillOpacity": 80, "stacking": { "mode": "normal" } },
+            },
+            "overrides": [],
+        },
+        "targets": [target(query, alias)],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use time::macros::datetime;
+
+    use super::{identifier, Export, COMPOSE_URL};
+    use crate::config::Config;
+    use crate::influxdb::{self, Direction};
+    use crate::report::{Interval, Report};
+
+    fn export(measurement: &str) -> Export {
+        Export {
+            url: "http://influx:8086".to_string(),
+            bucket: "network-speeds".to_string(),
+            measurement: measurement.to_string(),
+        }
+    }
+
+    fn queries(dashboard: &serde_json::Value) -> Vec<String> {
+        dashboard["panels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|panel| panel["targets"].as_array().unwrap())
+            .map(|target| target["query"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_queries_use_fields_and_tags_speedy_writes() {
+        let report = Report {
+            time: datetime!(2024-06-04 16:12:48 UTC),
+            server: "speedtest.init7.net:5201".to_string(),
+            direction: Direction::Download,
+            bits_per_second: Some(9e8),
+            rtt_ms: Some(11.2),
+            retransmits: None,
+            intervals: vec![Interval {
+                start: 0.0,
+                end: 1.0,
+                bits_per_second: 9e8,
+                rtt_ms: None,
+                retransmits: None,
+            }],
+            error: None,
+            tags: Vec::new(),
+        };
+        let tags = [
+            ("server", "speedtest.init7.net"),
+            ("src_site", "zurich"),
+            ("dst_site", "berlin"),
+        ];
+        let written = influxdb::lines("m", &tags, report.speeds(), report.outcome())
+            .unwrap()
+            .join("\n");
+
+        let queries = queries(&export("office").dashboard());
+        assert_eq!(10, queries.len());
+
+        for query in queries.iter() {
+            assert!(query.contains(r#" FROM "office" WHERE "#), "{query}");
+
+            // Every other quoted name is a field or tag of the written points.
+            for name in query.split('"').skip(1).step_by(2) {
+                assert!(
+                    name == "office" || written.contains(&format!("{name}=")),
+                    "{name} of ({query}) is not written:\n{written}"
+                );
+            }
+        }
+
+        assert_eq!(r#""office \"a\"""#, identifier(r#"office "a""#));
+    }
+
+    #[test]
+    fn test_provisioning_is_parameterized() {
+        let datasource = export("office").datasource();
+
+        assert!(datasource.contains("url: \"http://influx:8086\"\n"));
+        assert!(datasource.contains("dbName: \"network-speeds\"\n"));
+        assert!(datasource.contains("uid: speedy-influxdb\n"));
+        assert!(datasource.contains("${SPEEDY_INFLUX_TOKEN}"));
+        assert!(export("office")
+            .provider()
+            .contains("path: /etc/grafana/dashboards\n"));
+    }
+
+    #[tokio::test]
+    async fn test_default_export_matches_the_checked_in_files() {
+        let dir = TempDir::new().unwrap();
+        let influxdb = Config::default().sinks.influxdb;
+        let export = Export {
+            url: COMPOSE_URL.to_string(),
+            bucket: influxdb.bucket,
+            measurement: influxdb.measurement,
+        };
+
+        let written = export.write(dir.path()).await.unwrap();
+        assert_eq!(3, written.len());
+
+        let dashboard: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&written[0]).unwrap()).unwrap();
+        assert_eq!("speedy", dashboard["uid"]);
+        assert_eq!(1, dashboard["panels"][0]["id"]);
+
+        let checked_in =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("grafana-dashboards");
+
+        for path in written.iter() {
+            let relative = path.strip_prefix(dir.path()).unwrap();
+
+            assert_eq!(
+                std::fs::read_to_string(checked_in.join(relative)).unwrap(),
+                std::fs::read_to_string(path).unwrap(),
+                "{} is not what `speedy grafana export` writes",
+                relative.display()
+            );
+        }
+    }
+}
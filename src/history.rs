@@ -0,0 +1,281 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::report::Report;
+
+/// Reports kept in memory whatever the retention.
+const CAPACITY: usize = 50_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to access history ({0}): {1}")]
+    IO(String, std::io::Error),
+}
+
+/// Reports of the last days as JSON lines, read by the dashboard and the API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Store {
+    pub path: PathBuf,
+    /// Days a report is kept, 0 keeps every report.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+}
+
+fn default_retention_days() -> u32 {
+    7
+}
+
+#[derive(Debug)]
+struct Kept {
+    /// Oldest first.
+    reports: VecDeque<Report>,
+    retention_days: u32,
+    path: Option<PathBuf>,
+    /// Lines in the file, including reports dropped since it was written.
+    lines: usize,
+}
+
+/// Latest reports of an agent, shared by its runs and readers. Without a
+/// store they are only kept in memory.
+#[derive(Debug, Clone)]
+pub struct History(Arc<Mutex<Kept>>);
+
+impl Default for History {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Kept {
+            reports: VecDeque::new(),
+            retention_days: default_retention_days(),
+            path: None,
+            lines: 0,
+        })))
+    }
+}
+
+impl Kept {
+    fn io(&self, err: std::io::Error) -> Error {
+        let path = self.path.as_deref().unwrap_or_else(|| Path::new(""));
+        Error::IO(path.display().to_string(), err)
+    }
+
+    /// Drops reports beyond the retention, the file is left as it is.
+    fn prune(&mut self, now: OffsetDateTime) {
+        if self.retention_days > 0 {
+            let oldest = now - time::Duration::days(i64::from(self.retention_days));
+
+            while self
+                .reports
+                .front()
+                .is_some_and(|report| report.time < oldest)
+            {
+                self.reports.pop_front();
+            }
+        }
+
+        let excess = self.reports.len().saturating_sub(CAPACITY);
+        self.reports.drain(..excess);
+    }
+
+    /// Replaces the file with the reports still kept.
+    async fn rewrite(&mut self) -> Result<(), Error> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        let mut content = Vec::new();
+
+        for report in self.reports.iter() {
+            content.extend(serde_json::to_vec(report).unwrap_or_default());
+            content.push(b'\n');
+        }
+
+        let temporary = path.with_extension("tmp");
+        let written = async {
+            tokio::fs::write(&temporary, content).await?;
+            tokio::fs::rename(&temporary, path).await
+        };
+        written.await.map_err(|err| self.io(err))?;
+
+        self.lines = self.reports.len();
+
+        Ok(())
+    }
+}
+
+impl History {
+    /// Reads the reports still within the retention from `store`.
+    pub async fn open(store: &Store) -> Result<Self, Error> {
+        let mut kept = Kept {
+            reports: VecDeque::new(),
+            retention_days: store.retention_days,
+            path: Some(store.path.clone()),
+            lines: 0,
+        };
+
+        match tokio::fs::read_to_string(&store.path).await {
+            Ok(content) => {
+                for (number, line) in content.lines().enumerate() {
+                    match serde_json::from_str::<Report>(line) {
+                        Ok(report) => kept.reports.push_back(report),
+                        Err(err) => eprintln!(
+                            "Skipping line {} of history ({}): {err}",
+                            number + 1,
+                            store.path.display()
+                        ),
+                    }
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(kept.io(err)),
+        }
+
+        if let Some(parent) = store.path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|err| kept.io(err))?;
+        }
+
+        kept.reports
+            .make_contiguous()
+            .sort_by_key(|report| report.time);
+        kept.prune(OffsetDateTime::now_utc());
+        kept.rewrite().await?;
+
+        Ok(Self(Arc::new(Mutex::new(kept))))
+    }
+
+    /// Keeps `reports` and appends them to the store.
+    pub async fn push(&self, reports: impl IntoIterator<Item = Report>) -> Result<(), Error> {
+        let mut kept = self.0.lock().await;
+        let added = reports.into_iter().collect::<Vec<_>>();
+
+        kept.reports.extend(added.iter().cloned());
+        kept.prune(OffsetDateTime::now_utc());
+
+        let Some(path) = kept.path.clone() else {
+            return Ok(());
+        };
+
+        // Dropped reports stay in the file until it is twice as long as needed.
+        if kept.lines + added.len() > 2 * kept.reports.len().max(CAPACITY / 10) {
+            return kept.rewrite().await;
+        }
+
+        let mut content = Vec::new();
+
+        for report in added.iter() {
+            content.extend(serde_json::to_vec(report).unwrap_or_default());
+            content.push(b'\n');
+        }
+
+        let appended = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            file.write_all(&content).await?;
+            file.flush().await
+        };
+        appended.await.map_err(|err| kept.io(err))?;
+
+        kept.lines += added.len();
+
+        Ok(())
+    }
+
+    /// The `count` latest reports, newest first.
+    pub async fn last(&self, count: usize) -> Vec<Report> {
+        self.0
+            .lock()
+            .await
+            .reports
+            .iter()
+            .rev()
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    /// Reports of tests started at `time` or later, oldest first.
+    pub async fn since(&self, time: OffsetDateTime) -> Vec<Report> {
+        self.0
+            .lock()
+            .await
+            .reports
+            .iter()
+            .filter(|report| report.time >= time)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use time::OffsetDateTime;
+
+    use super::{History, Store};
+    use crate::influxdb::Direction;
+    use crate::report::Report;
+
+    fn report(days_ago: i64, bits_per_second: f64) -> Report {
+        Report {
+            time: OffsetDateTime::now_utc() - time::Duration::days(days_ago),
+            server: "speedtest.init7.net:5201".to_string(),
+            direction: Direction::Download,
+            bits_per_second: Some(bits_per_second),
+            rtt_ms: Some(12.5),
+            retransmits: None,
+            intervals: Vec::new(),
+            error: None,
+            tags: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reports_survive_a_restart_within_the_retention() {
+        let dir = TempDir::new().unwrap();
+        let store = Store {
+            path: dir.path().join("history.jsonl"),
+            retention_days: 7,
+        };
+
+        let history = History::open(&store).await.unwrap();
+        history
+            .push([report(10, 1e8), report(2, 2e8), report(0, 3e8)])
+            .await
+            .unwrap();
+        assert_eq!(2, history.last(10).await.len());
+
+        std::fs::write(
+            &store.path,
+            std::fs::read_to_string(&store.path).unwrap() + "not json\n",
+        )
+        .unwrap();
+
+        let history = History::open(&store).await.unwrap();
+        let reports = history.last(10).await;
+        assert_eq!(2, reports.len());
+        assert_eq!(Some(3e8), reports[0].bits_per_second);
+        assert_eq!(
+            1,
+            history
+                .since(OffsetDateTime::now_utc() - time::Duration::days(1))
+                .await
+                .len()
+        );
+        assert_eq!(
+            2,
+            std::fs::read_to_string(&store.path)
+                .unwrap()
+                .lines()
+                .count()
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_derive::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::dashboard;
+use crate::history::History;
+use crate::scheduler::{Board, Health};
+
+#[derive(Debug, Serialize)]
+struct Status<'a> {
+    host: &'a str,
+    paused: bool,
+    rules: Vec<Health>,
+}
+
+pub fn json(status: StatusCode, body: &impl serde::Serialize) -> Response<Body> {
+    match serde_json::to_vec(body) {
+        Ok(body) => Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(err) => text(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+pub fn text(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
+
+/// Token of an `Authorization: Bearer` header.
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Compares in constant time, so a token can not be guessed byte by byte.
+pub fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The dashboard data and the status hold the results and errors of the rules,
+/// with an API token they are only given to requests bearing it. The page
+/// itself stays public, it fetches the data with the token.
+async fn handle(
+    req: Request<Body>,
+    host: &str,
+    board: &Board,
+    history: &History,
+    token: Option<&str>,
+) -> Response<Body> {
+    let allowed = token.map_or(true, |token| {
+        bearer(req.headers()).is_some_and(|bearer| same(token, bearer))
+    });
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/") => Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(dashboard::PAGE))
+            .unwrap(),
+        (&Method::GET, "/dashboard.json" | "/status") if !allowed => {
+            text(StatusCode::UNAUTHORIZED, "unknown token")
+        }
+        (&Method::GET, "/dashboard.json") => match dashboard::range(req.uri().query()) {
+            Ok(range) => json(
+                StatusCode::OK,
+                &dashboard::overview(host, board, history, range).await,
+            ),
+            Err(err) => text(StatusCode::BAD_REQUEST, err),
+        },
+        (&Method::GET, "/status") => json(
+            StatusCode::OK,
+            &Status {
+                host,
+                paused: board.paused(),
+                rules: board.snapshot(),
+            },
+        ),
+        _ => text(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Binds the status endpoint and the dashboard on `addr`, the returned future
+/// serves them until `shutdown` is cancelled. `token` is the API token, if any.
+pub fn status(
+    addr: SocketAddr,
+    host: String,
+    board: Board,
+    history: History,
+    token: Option<String>,
+    shutdown: CancellationToken,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), hyper::Error>>), hyper::Error> {
+    let make = make_service_fn(move |_| {
+        let host = host.clone();
+        let board = board.clone();
+        let history = history.clone();
+        let token = token.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let host = host.clone();
+                let board = board.clone();
+                let history = history.clone();
+                let token = token.clone();
+
+                async move {
+                    Ok::<_, Infallible>(
+                        handle(req, &host, &board, &history, token.as_deref()).await,
+                    )
+                }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make);
+    let addr = server.local_addr();
+
+    Ok((
+        addr,
+        server.with_graceful_shutdown(async move { shutdown.cancelled().await }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::sync::CancellationToken;
+
+    use super::status;
+    use crate::history::History;
+    use crate::scheduler::Board;
+
+    #[tokio::test]
+    async fn test_dashboard_and_status_need_the_api_token() {
+        let (addr, server) = status(
+            "127.0.0.1:0".parse().unwrap(),
+            "agent".to_string(),
+            Board::default(),
+            History::default(),
+            Some("secret".to_string()),
+            CancellationToken::new(),
+        )
+        .unwrap();
+        tokio::spawn(server);
+
+        let get = |path: &str, token: &str| {
+            reqwest::Client::new()
+                .get(format!("http://{addr}{path}"))
+                .bearer_auth(token)
+                .send()
+        };
+
+        assert_eq!(401, get("/dashboard.json", "guess").await.unwrap().status());
+        assert_eq!(
+            200,
+            get("/dashboard.json", "secret").await.unwrap().status()
+        );
+        assert_eq!(401, get("/status", "guess").await.unwrap().status());
+        assert_eq!(200, get("/status", "secret").await.unwrap().status());
+        assert_eq!(200, get("/", "guess").await.unwrap().status());
+    }
+}
@@ -1,4 +1,6 @@
-use influxdb::InfluxDbWriteable;
+use std::collections::BTreeMap;
+
+use influxdb::{InfluxDbWriteable, Query, WriteQuery};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -14,9 +16,22 @@ pub struct Speed {
     speed: u64, // bits per second
 }
 
+/// How one direction of a test went, written at its start next to its
+/// speeds. `failed` is 0 or 1 so failures can be summed, `rtt_ms` is only
+/// written when the sender measured it.
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    time: influxdb::Timestamp,
+    direction: String,
+    rtt_ms: Option<f64>,
+    failed: bool,
+}
+
 #[derive(Debug)]
 pub struct Client {
     inner: influxdb::Client,
+    measurement: String,
+    tags: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone)]
@@ -44,23 +59,161 @@ impl Speed {
     }
 }
 
+impl Outcome {
+    pub fn new(
+        time: time::OffsetDateTime,
+        direction: Direction,
+        rtt_ms: Option<f64>,
+        failed: bool,
+    ) -> Self {
+        Self {
+            time: influxdb::Timestamp::Seconds(time.unix_timestamp() as u128),
+            direction: direction.to_string(),
+            rtt_ms,
+            failed,
+        }
+    }
+
+    fn into_query(self, measurement: &str) -> WriteQuery {
+        let query = WriteQuery::new(self.time, measurement)
+            .add_tag("direction", self.direction)
+            .add_field("failed", i64::from(self.failed));
+
+        match self.rtt_ms {
+            Some(rtt_ms) => query.add_field("rtt_ms", rtt_ms),
+            None => query,
+        }
+    }
+}
+
 impl Client {
     #[inline]
     pub fn new(addr: impl AsRef<str>, bucket: impl AsRef<str>, token: impl AsRef<str>) -> Self {
         Self {
             inner: influxdb::Client::new(addr.as_ref(), bucket.as_ref()).with_token(token.as_ref()),
+            measurement: "network_speeds".to_string(),
+            tags: BTreeMap::new(),
         }
     }
 
     #[inline]
-    pub async fn insert_multiple(&self, speeds: impl Iterator<Item = Speed>) -> Result<(), Error> {
+    pub fn with_measurement(mut self, measurement: impl Into<String>) -> Self {
+        self.measurement = measurement.into();
+        self
+    }
+
+    /// Tags added to every written point.
+    #[inline]
+    pub fn with_tags(mut self, tags: BTreeMap<String, String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Writes `speeds` with the client tags and the `extra` ones of this run.
+    #[inline]
+    pub async fn insert_multiple(
+        &self,
+        speeds: impl Iterator<Item = Speed>,
+        extra: &[(&str, &str)],
+    ) -> Result<(), Error> {
         self.inner
-            .query(
-                speeds
-                    .map(|item| item.into_query("network_speeds"))
-                    .collect::<Vec<_>>(),
-            )
+            .query(queries(&self.measurement, &self.tags(extra), speeds))
             .await?;
         Ok(())
     }
+
+    /// Writes `outcome` with the client tags and the `extra` ones of this run.
+    #[inline]
+    pub async fn insert_outcome(
+        &self,
+        outcome: Outcome,
+        extra: &[(&str, &str)],
+    ) -> Result<(), Error> {
+        let query = tagged(outcome.into_query(&self.measurement), &self.tags(extra));
+
+        self.inner.query(query).await?;
+        Ok(())
+    }
+
+    fn tags<'a>(&'a self, extra: &[(&'a str, &'a str)]) -> Vec<(&'a str, &'a str)> {
+        self.tags
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(extra.iter().copied())
+            .collect()
+    }
+}
+
+fn tagged(query: WriteQuery, tags: &[(&str, &str)]) -> WriteQuery {
+    tags.iter()
+        .fold(query, |query, (key, value)| query.add_tag(*key, *value))
+}
+
+fn queries(
+    measurement: &str,
+    tags: &[(&str, &str)],
+    speeds: impl Iterator<Item = Speed>,
+) -> Vec<WriteQuery> {
+    speeds
+        .map(|item| tagged(item.into_query(measurement), tags))
+        .collect()
+}
+
+/// The points [`Client::insert_multiple`] and [`Client::insert_outcome`]
+/// would write, in line protocol with second precision.
+pub fn lines(
+    measurement: &str,
+    tags: &[(&str, &str)],
+    speeds: impl Iterator<Item = Speed>,
+    outcome: Outcome,
+) -> Result<Vec<String>, Error> {
+    queries(measurement, tags, speeds)
+        .into_iter()
+        .chain([tagged(outcome.into_query(measurement), tags)])
+        .map(|query| Ok(query.build()?.get()))
+        .collect()
+}
+
+/// Stand-in for InfluxDB keeping the bodies of the writes.
+#[cfg(test)]
+pub fn fake() -> (
+    std::net::SocketAddr,
+    std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+) {
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let kept = Arc::clone(&writes);
+
+    let server =
+        hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let writes = Arc::clone(&writes);
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let writes = Arc::clone(&writes);
+
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        writes
+                            .lock()
+                            .unwrap()
+                            .push(String::from_utf8_lossy(&body).to_string());
+
+                        Ok::<_, Infallible>(
+                            Response::builder().status(204).body(Body::empty()).unwrap(),
+                        )
+                    }
+                }))
+            }
+        }));
+    let addr = server.local_addr();
+
+    tokio::spawn(server);
+
+    (addr, kept)
 }
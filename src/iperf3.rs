@@ -1,22 +1,24 @@
 use rand::distributions::WeightedError;
 use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Display;
 use std::io;
 use std::process::Stdio;
-use tokio::io::AsyncReadExt;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc::UnboundedSender;
 use tokio_util::sync::CancellationToken;
 
 use crate::models;
+use crate::selection::{self, Selector, Strategy};
+use crate::server::{IpVersion, Server};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Failed to execute iperf3 command (Server {1}): {0}")]
     Command(String, String),
 
-    #[error(
-        "Server format is invalid, expected following format \"<server>:<port:OPTIONAL>:<weight>\""
-    )]
-    InvalidServerFormat,
-
     #[error("install iperf3 command")]
     IperfCommandDoesNotExist,
 
@@ -31,154 +33,599 @@ pub enum Error {
 
     #[error("request sending canceled")]
     Canceled,
+
+    #[error("no server in the group supports {0}")]
+    NoServer(Protocol),
+
+    #[error("iperf3 server is busy (Server {0})")]
+    Busy(String),
+
+    /// Hosts tried, then the `host:port` tried last.
+    #[error("every port of every server is busy, tried {0}")]
+    AllBusy(String, String),
+
+    #[error("test was interrupted after {} intervals", .0.intervals.len())]
+    Partial(Box<models::IPerf3>),
+}
+
+impl Error {
+    /// `host:port` of the server the failed run was against.
+    pub fn server(&self) -> Option<&str> {
+        match self {
+            Error::Command(_, name) | Error::Busy(name) | Error::AllBusy(_, name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Part of a running test, sent as soon as iperf3 reports it.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Start(Box<models::Start>),
+    Interval(models::Interval),
+    End(Box<models::End>),
+}
+
+/// The events a streaming run of `result` would have sent.
+pub fn events(result: &models::IPerf3) -> impl Iterator<Item = Event> + '_ {
+    std::iter::once(Event::Start(Box::new(result.start.clone())))
+        .chain(result.intervals.iter().cloned().map(Event::Interval))
+        .chain(std::iter::once(Event::End(Box::new(result.end.clone()))))
 }
 
 pub const IPERF3_BINARY: &str = "iperf3";
-pub const IPERF3_DEFAULT_PORT: &str = "5001";
 
-pub async fn download_speed<T: AsRef<str>>(
-    servs: &[T],
-    duration: i32,
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Protocol::Tcp => f.write_str("tcp"),
+            Protocol::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Upper bound for a whole test in seconds, used when `duration` is not set.
+    pub timeout: i32,
+    /// Length of the transfer in seconds.
+    pub duration: Option<i32>,
+    pub streams: u16,
+    pub protocol: Protocol,
+    /// Target bitrate in bits per second.
+    pub bitrate: Option<u64>,
+    pub strategy: Strategy,
+    pub selector: Arc<Selector>,
+    /// Receives the events of the running test, all at once at the end when
+    /// iperf3 is older than 3.17 and cannot stream them.
+    pub events: Option<UnboundedSender<Event>>,
+}
+
+impl Options {
+    /// Seconds a test transfers data.
+    pub fn length(&self) -> i32 {
+        match self.duration {
+            Some(duration) => duration,
+            None if self.timeout > 10 => self.timeout - 5,
+            None => self.timeout - 2,
+        }
+    }
+
+    fn deadline(&self) -> u64 {
+        match self.duration {
+            Some(duration) => (duration + 5) as u64,
+            None => (self.timeout + 3) as u64,
+        }
+    }
+}
+
+pub async fn download_speed(
+    servs: &[Server],
+    options: &Options,
+    cancel: &CancellationToken,
 ) -> Result<models::IPerf3, Error> {
-    check_iperf3_command().await?;
-    execute_speed_test(servs, duration, true).await
+    let streaming = check_iperf3_command().await?;
+    execute_speed_test(servs, options, true, streaming, cancel).await
 }
 
-pub async fn upload_speed<T: AsRef<str>>(
-    servs: &[T],
-    duration: i32,
+pub async fn upload_speed(
+    servs: &[Server],
+    options: &Options,
+    cancel: &CancellationToken,
 ) -> Result<models::IPerf3, Error> {
-    check_iperf3_command().await?;
-    execute_speed_test(servs, duration, false).await
+    let streaming = check_iperf3_command().await?;
+    execute_speed_test(servs, options, false, streaming, cancel).await
 }
 
-async fn check_iperf3_command() -> Result<(), Error> {
+/// Checks that iperf3 is installed and whether it supports `--json-stream`.
+async fn check_iperf3_command() -> Result<bool, Error> {
     let mut command = tokio::process::Command::new(IPERF3_BINARY);
     command.kill_on_drop(true);
     command.stdout(Stdio::piped());
     command.stderr(Stdio::piped());
     command.arg("--version");
 
-    match command.status().await {
-        Ok(_) => Ok(()),
+    match command.output().await {
+        Ok(output) => Ok(supports_json_stream(&String::from_utf8_lossy(
+            &output.stdout,
+        ))),
         Err(_) => Err(Error::IperfCommandDoesNotExist),
     }
 }
 
+/// `--json-stream` was added in iperf 3.17.
+fn supports_json_stream(version: &str) -> bool {
+    let numbers = version
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    match numbers[..] {
+        [major, minor, ..] => (major, minor) >= (3, 17),
+        _ => false,
+    }
+}
+
 fn build_iperf3_command(
-    addr: &str,
-    port: &str,
-    duration: i32,
+    server: &Server,
+    port: u16,
+    options: &Options,
     download: bool,
+    streaming: bool,
 ) -> tokio::process::Command {
     let mut command = tokio::process::Command::new(IPERF3_BINARY);
     command.kill_on_drop(true);
 
     command.stdout(Stdio::piped());
     command.stderr(Stdio::piped());
-    let mut dur = if duration > 10 {
-        duration - 5
-    } else {
-        duration - 2
-    }
-    .to_string();
 
-    dur.extend(&['s']);
+    command.arg(match streaming {
+        true => "--json-stream",
+        false => "-J",
+    });
 
     command.args([
-        "-J",
         "-Z",
         "--connect-timeout",
         "500", // 0.5s
         "-i",
         "1",
         "-t",
-        &dur,
-        if download { "-R" } else { "" },
-        "-c",
-        addr,
-        "-p",
-        port,
+        &format!("{}s", options.length()),
+        "-P",
+        &options.streams.max(1).to_string(),
     ]);
 
+    if options.protocol == Protocol::Udp {
+        command.arg("-u");
+    }
+
+    let bitrate = match (options.bitrate, server.max_bitrate) {
+        (Some(bitrate), Some(max)) => Some(bitrate.min(max)),
+        (bitrate, max) => bitrate.or(max),
+    };
+
+    if let Some(bitrate) = bitrate {
+        command.args(["-b", &bitrate.to_string()]);
+    }
+
+    match server.ip {
+        IpVersion::V4 => _ = command.arg("-4"),
+        IpVersion::V6 => _ = command.arg("-6"),
+        IpVersion::Any => {}
+    }
+
+    if download {
+        command.arg("-R");
+    }
+
+    command.args(["-c", &server.host, "-p", &port.to_string()]);
+
     command
 }
 
-fn pick_server(servers: &[impl AsRef<str>]) -> Result<(String, String), Error> {
-    let mut rng = rand::thread_rng();
+/// Every port to try in order: hosts in the order of the strategy, the ports
+/// of one host shuffled and next to each other.
+fn plan<'a>(
+    servers: &'a [Server],
+    options: &Options,
+    rng: &mut impl Rng,
+) -> Result<Vec<(&'a Server, u16)>, Error> {
+    let hosts = selection::hosts(servers, options.protocol);
 
-    let server = servers.choose_weighted(&mut rng, |item| match item.as_ref().rfind(':') {
-        Some(idx) if idx + 2 >= item.as_ref().len() => item.as_ref()[idx + 1..]
-            .parse::<i32>()
-            .expect("Weight needs to be a number"),
-        Some(_) | None => 0,
-    })?;
+    if hosts.is_empty() {
+        return Err(Error::NoServer(options.protocol));
+    }
 
-    parse_server(server)
+    Ok(options
+        .selector
+        .order(options.strategy, hosts, rng)?
+        .into_iter()
+        .flat_map(|mut host| {
+            host.ports.shuffle(rng);
+            host.ports
+        })
+        .collect())
 }
 
-async fn execute_speed_test<T: AsRef<str>>(
-    servers: &[T],
-    duration: i32,
+/// iperf3 refuses a second client while a test is running on that port.
+fn is_busy(output: &str) -> bool {
+    output.contains("server is busy")
+}
+
+async fn execute_speed_test(
+    servers: &[Server],
+    options: &Options,
     download: bool,
+    streaming: bool,
+    cancel: &CancellationToken,
 ) -> Result<models::IPerf3, Error> {
-    let (addr, port) = pick_server(servers)?;
+    if options.strategy == Strategy::Nearest {
+        options.selector.measure(servers, options.protocol).await;
+    }
+
+    let plan = plan(servers, options, &mut rand::thread_rng())?;
+    let mut tried: Vec<&str> = Vec::new();
+    let mut last = String::new();
+
+    for (server, port) in plan {
+        if cancel.is_cancelled() {
+            return Err(Error::Canceled);
+        }
+
+        match execute_iperf3(server, port, options, download, streaming, cancel).await {
+            Err(Error::Busy(name)) => {
+                eprintln!("Server {name} is busy, trying another port");
+
+                if !tried.contains(&server.host.as_str()) {
+                    tried.push(&server.host);
+                }
+
+                last = name;
+            }
+            result => return result,
+        }
+    }
+
+    Err(Error::AllBusy(tried.join(", "), last))
+}
+
+/// Collects the output of one iperf3 process and forwards its events.
+#[derive(Debug, Default)]
+struct Output {
+    streaming: bool,
+    events: Option<UnboundedSender<Event>>,
+    result: models::IPerf3,
+    /// Whole output of a non-streaming run, parsed once iperf3 exits.
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Line {
+    event: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+impl Output {
+    fn emit(&self, event: Event) {
+        if let Some(ref events) = self.events {
+            _ = events.send(event);
+        }
+    }
+
+    fn push(&mut self, line: &str) -> Result<(), Error> {
+        if !self.streaming {
+            self.text.push_str(line);
+            self.text.push('\n');
+            return Ok(());
+        }
+
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+
+        let Line { event, data } = serde_json::from_str(line)?;
+
+        match event.as_str() {
+            "start" => {
+                self.result.start = serde_json::from_value(data)?;
+                self.emit(Event::Start(Box::new(self.result.start.clone())));
+            }
+            "interval" => {
+                let interval: models::Interval = serde_json::from_value(data)?;
+                self.emit(Event::Interval(interval.clone()));
+                self.result.intervals.push(interval);
+            }
+            "end" => {
+                self.result.end = serde_json::from_value(data)?;
+                self.emit(Event::End(Box::new(self.result.end.clone())));
+            }
+            "error" => {
+                self.result.error = Some(match data {
+                    serde_json::Value::String(error) => error,
+                    data => data.to_string(),
+                });
+            }
+            _ => {
+                self.result.extra.insert(event, data);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Result of a process that exited, `name` identifies the server.
+    fn finish(mut self, success: bool, name: String) -> Result<models::IPerf3, Error> {
+        if !self.streaming && !self.text.trim().is_empty() {
+            match serde_json::from_str::<models::IPerf3>(&self.text) {
+                Ok(result) => {
+                    events(&result).for_each(|event| self.emit(event));
+                    self.result = result;
+                }
+                Err(err) if success => return Err(err.into()),
+                Err(_) => {}
+            }
+        }
 
-    let mut iperf3 = build_iperf3_command(&addr, &port, duration, download);
+        let error = self
+            .result
+            .error
+            .clone()
+            .unwrap_or_else(|| self.text.clone());
+
+        match success {
+            true => Ok(self.result),
+            false if is_busy(&error) => Err(Error::Busy(name)),
+            false => Err(Error::Command(error, name)),
+        }
+    }
+
+    /// Result of a process that was killed, intervals received so far are kept.
+    fn interrupted(self) -> Error {
+        match self.result.intervals.is_empty() {
+            true => Error::Canceled,
+            false => Error::Partial(Box::new(self.result)),
+        }
+    }
+}
+
+async fn execute_iperf3(
+    server: &Server,
+    port: u16,
+    options: &Options,
+    download: bool,
+    streaming: bool,
+    cancel: &CancellationToken,
+) -> Result<models::IPerf3, Error> {
+    let name = format!("{}:{port}", server.host);
+    let mut iperf3 = build_iperf3_command(server, port, options, download, streaming);
     let mut child = iperf3.spawn()?;
-    let token = CancellationToken::new();
+    let token = cancel.child_token();
 
     let sub_token = token.clone();
+    let mut output = Output {
+        streaming,
+        events: options.events.clone(),
+        ..Default::default()
+    };
+
+    // Read while iperf3 runs, waiting first could fill the pipe and stall it.
+    let mut lines = child.stdout.take().map(|out| BufReader::new(out).lines());
 
     let worker_handle = tokio::spawn(async move {
+        while let Some(ref mut reader) = lines {
+            tokio::select! {
+                _ = sub_token.cancelled() => {
+                    _ = child.kill().await;
+                    return Err(output.interrupted());
+                }
+                line = reader.next_line() => match line? {
+                    Some(line) => output.push(&line)?,
+                    None => lines = None,
+                }
+            }
+        }
+
         tokio::select! {
             _ = sub_token.cancelled() => {
                 _ = child.kill().await;
-                drop(child);
-                Err(Error::Canceled)
-            }
-            result = child.wait() => {
-                let data = match child.stdout {
-                    Some(ref mut out) => {
-                        let mut data = String::new();
-                        out.read_to_string(&mut data).await?;
-                        data
-                    }
-                    None => String::new(),
-                };
-
-                if result?.success() {
-                    let deserialized: models::IPerf3 = serde_json::from_str(&data)?;
-                    Ok(deserialized)
-                } else {
-                    Err(Error::Command(data, format!("{addr}:{port}")))
-                }
+                Err(output.interrupted())
             }
+            status = child.wait() => output.finish(status?.success(), name),
         }
     });
 
+    let deadline = options.deadline();
+
     tokio::spawn(async move {
-        tokio::time::sleep(tokio::time::Duration::from_secs((duration + 3) as u64)).await;
+        tokio::time::sleep(tokio::time::Duration::from_secs(deadline)).await;
         token.cancel();
     });
 
     worker_handle.await.unwrap()
 }
 
-fn parse_server<'str, T>(server: T) -> Result<(String, String), Error>
-where
-    T: AsRef<str> + 'str,
-{
-    let mut items = server.as_ref().splitn(3, ':');
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use tokio::sync::mpsc;
+
+    use super::{is_busy, plan, supports_json_stream, Error, Event, Options, Output, Protocol};
+    use crate::server::Server;
+
+    const STREAM: &str = include_str!("../fixtures/iperf3/3.17-linux-tcp-json-stream.jsonl");
+    const DOCUMENT: &str = include_str!("../fixtures/iperf3/3.17-linux-tcp-multistream.json");
+
+    fn servers(list: &[&str]) -> Vec<Server> {
+        list.iter().map(|server| server.parse().unwrap()).collect()
+    }
+
+    fn tcp() -> Options {
+        Options::default()
+    }
+
+    #[test]
+    fn test_plan_tries_every_port_of_a_host_together() {
+        let servers = servers(&[
+            "a.example:5201-5204:5",
+            "b.example:5201:5",
+            "a.example:5209:1",
+        ]);
+
+        for seed in 0..50 {
+            let plan = plan(&servers, &tcp(), &mut StdRng::seed_from_u64(seed)).unwrap();
+            let mut hosts = plan
+                .iter()
+                .map(|(server, _)| server.host.as_str())
+                .collect::<Vec<_>>();
+            hosts.dedup();
+
+            assert_eq!(6, plan.len());
+            assert_eq!(2, hosts.len(), "{hosts:?}");
+
+            let mut ports = plan.iter().map(|(_, port)| *port).collect::<Vec<_>>();
+            ports.sort();
+            assert_eq!(vec![5201, 5201, 5202, 5203, 5204, 5209], ports);
+        }
+    }
+
+    #[test]
+    fn test_plan_shuffles_ports() {
+        let servers = servers(&["a.example:5201-5209:1"]);
+
+        let firsts = (0..50)
+            .map(|seed| plan(&servers, &tcp(), &mut StdRng::seed_from_u64(seed)).unwrap()[0].1)
+            .collect::<std::collections::BTreeSet<_>>();
+
+        assert!(firsts.len() > 3, "{firsts:?}");
+    }
+
+    #[test]
+    fn test_plan_skips_zero_weight_and_unsupported() {
+        let mut servers = servers(&["a.example:5201:0", "b.example:5201:1", "c.example:5201:1"]);
+        servers[2].protocols = vec![Protocol::Udp];
+
+        let plan = plan(&servers, &tcp(), &mut StdRng::seed_from_u64(1)).unwrap();
+        assert_eq!(1, plan.len());
+        assert_eq!("b.example", plan[0].0.host);
+
+        assert!(super::plan(&servers[..1], &tcp(), &mut StdRng::seed_from_u64(1)).is_err());
+        assert!(super::plan(&servers[2..], &tcp(), &mut StdRng::seed_from_u64(1)).is_err());
+    }
+
+    #[test]
+    fn test_is_busy() {
+        assert!(is_busy(
+            r#"{"start": {}, "error": "error - the server is busy running a test. try again later"}"#
+        ));
+        assert!(!is_busy(
+            r#"{"error": "error - unable to connect to server"}"#
+        ));
+    }
+
+    #[test]
+    fn test_supports_json_stream() {
+        assert!(supports_json_stream(
+            "iperf 3.17.1 (cJSON 1.7.15)\nLinux speedy"
+        ));
+        assert!(supports_json_stream("iperf 4.0"));
+        assert!(!supports_json_stream("iperf 3.16 (cJSON 1.7.15)"));
+        assert!(!supports_json_stream("iperf 3.9"));
+        assert!(!supports_json_stream(""));
+    }
+
+    fn output(streaming: bool) -> (Output, mpsc::UnboundedReceiver<Event>) {
+        let (sender, events) = mpsc::unbounded_channel();
+        let output = Output {
+            streaming,
+            events: Some(sender),
+            ..Default::default()
+        };
+
+        (output, events)
+    }
+
+    fn intervals(events: &mut mpsc::UnboundedReceiver<Event>) -> usize {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| matches!(event, Event::Interval(_)))
+            .count()
+    }
+
+    #[test]
+    fn test_stream_emits_intervals_as_they_arrive() {
+        let (mut output, mut events) = output(true);
+        let mut lines = STREAM.lines();
+
+        output.push(lines.next().unwrap()).unwrap();
+        assert!(matches!(events.try_recv(), Ok(Event::Start(_))));
+
+        output.push(lines.next().unwrap()).unwrap();
+        assert_eq!(1, intervals(&mut events));
+
+        lines.for_each(|line| output.push(line).unwrap());
+        assert_eq!(1, intervals(&mut events));
+
+        let result = output.finish(true, "a.example:5201".into()).unwrap();
+        let expected = serde_json::from_str::<crate::models::IPerf3>(DOCUMENT).unwrap();
+
+        assert_eq!(2, result.intervals.len());
+        assert_eq!(expected.end.sum_sent, result.end.sum_sent);
+        assert_eq!(expected.start.version, result.start.version);
+    }
+
+    #[test]
+    fn test_document_emits_intervals_on_exit() {
+        let (mut output, mut events) = output(false);
+
+        DOCUMENT.lines().for_each(|line| output.push(line).unwrap());
+        assert_eq!(0, intervals(&mut events));
+
+        let result = output.finish(true, "a.example:5201".into()).unwrap();
+        assert_eq!(2, result.intervals.len());
+        assert_eq!(2, intervals(&mut events));
+    }
+
+    #[test]
+    fn test_interrupted_stream_keeps_partial_intervals() {
+        let (mut partial, _events) = output(true);
+        STREAM
+            .lines()
+            .take(2)
+            .for_each(|line| partial.push(line).unwrap());
+
+        match partial.interrupted() {
+            Error::Partial(result) => assert_eq!(1, result.intervals.len()),
+            err => panic!("{err}"),
+        }
+
+        let (empty, _events) = output(true);
+        assert!(matches!(empty.interrupted(), Error::Canceled));
+    }
 
-    let addr = items
-        .next()
-        .ok_or_else(|| Error::InvalidServerFormat)?
-        .to_string();
-    let port = items.next().unwrap_or(IPERF3_DEFAULT_PORT).to_string();
+    #[test]
+    fn test_stream_error_event_is_busy() {
+        let (mut output, _events) = output(true);
+        output
+            .push(r#"{"event":"error","data":"error - the server is busy running a test. try again later"}"#)
+            .unwrap();
 
-    match items.next() {
-        Some(_) => Ok((addr, port)),
-        None => Ok((addr, IPERF3_DEFAULT_PORT.to_string())),
+        assert!(matches!(
+            output.finish(false, "a.example:5201".into()),
+            Err(Error::Busy(name)) if name == "a.example:5201"
+        ));
     }
 }
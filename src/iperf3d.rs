@@ -0,0 +1,1198 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::mesh::Flight;
+use crate::report;
+
+/// Sent first on every connection, identifies the test a data stream belongs to.
+const COOKIE_SIZE: usize = 37;
+/// Time the client gets for every step outside the test itself.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a test may run past its duration before it is dropped.
+const GRACE: Duration = Duration::from_secs(30);
+const MAX_JSON: u32 = 1024 * 1024;
+const MAX_STREAMS: u32 = 128;
+const MAX_BLOCK: u32 = 1024 * 1024;
+const MAX_UDP_BLOCK: u32 = 65507;
+const MAX_TIME: u64 = 86400;
+/// Answer to the datagram opening a UDP stream, in host byte order like iperf3.
+const UDP_CONNECT_REPLY: u32 = 0x3938_3736;
+
+// States of the control connection.
+const TEST_START: i8 = 1;
+const TEST_RUNNING: i8 = 2;
+const TEST_END: i8 = 4;
+const PARAM_EXCHANGE: i8 = 9;
+const CREATE_STREAMS: i8 = 10;
+const SERVER_TERMINATE: i8 = 11;
+const CLIENT_TERMINATE: i8 = 12;
+const EXCHANGE_RESULTS: i8 = 13;
+const DISPLAY_RESULTS: i8 = 14;
+const IPERF_DONE: i8 = 16;
+const ACCESS_DENIED: i8 = -1;
+const SERVER_ERROR: i8 = -2;
+
+// iperf3 error numbers the client turns into a message.
+const IEDURATION: i32 = 5;
+const IENUMSTREAMS: i32 = 6;
+const IEBLOCKSIZE: i32 = 7;
+const IEUNIMP: i32 = 13;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to listen on {0}: {1}")]
+    Bind(SocketAddr, std::io::Error),
+
+    #[error("connection failed: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("invalid message from the client: {0}")]
+    Json(String),
+
+    #[error("unsupported test: {0}")]
+    Rejected(&'static str),
+
+    #[error("client did not {0} in time")]
+    Timeout(&'static str),
+
+    #[error("client terminated the test")]
+    Terminated,
+
+    #[error("unexpected state ({0}) from the client")]
+    State(i8),
+
+    #[error("server is shutting down")]
+    Shutdown,
+}
+
+/// Test parameters the client sends, unknown ones are ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Params {
+    udp: bool,
+    sctp: bool,
+    bidirectional: bool,
+    reverse: bool,
+    /// Seconds, 0 when the test ends after `num` bytes or `blockcount` blocks.
+    time: u64,
+    /// Seconds at the start left out of the results.
+    omit: u64,
+    num: u64,
+    blockcount: u64,
+    parallel: u32,
+    len: u32,
+    /// Bits per second of every stream, 0 is unlimited.
+    bandwidth: u64,
+    /// Microseconds between checks of the bandwidth.
+    pacing_timer: u64,
+    udp_counters_64bit: u8,
+}
+
+impl Params {
+    /// The iperf3 error number and reason of parameters this server can not run.
+    fn check(&self) -> Result<(), (i32, &'static str)> {
+        if self.sctp {
+            return Err((IEUNIMP, "SCTP"));
+        }
+
+        if self.bidirectional {
+            return Err((IEUNIMP, "bidirectional tests"));
+        }
+
+        if self.parallel > MAX_STREAMS {
+            return Err((IENUMSTREAMS, "too many streams"));
+        }
+
+        if self.time > MAX_TIME {
+            return Err((IEDURATION, "test too long"));
+        }
+
+        match self.udp {
+            true if (self.len as usize) < self.header() || self.len > MAX_UDP_BLOCK => {
+                Err((IEBLOCKSIZE, "invalid datagram size"))
+            }
+            false if self.len == 0 || self.len > MAX_BLOCK => {
+                Err((IEBLOCKSIZE, "invalid block size"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn streams(&self) -> usize {
+        self.parallel.max(1) as usize
+    }
+
+    /// Size of the sequence header of a datagram.
+    fn header(&self) -> usize {
+        match self.udp_counters_64bit {
+            0 => 12,
+            _ => 16,
+        }
+    }
+
+    fn budget(&self) -> Budget {
+        let bytes = match self.num {
+            0 => self.blockcount * u64::from(self.len),
+            num => num,
+        };
+
+        Budget((bytes > 0).then(|| Arc::new(AtomicU64::new(bytes))))
+    }
+
+    fn protocol(&self) -> &'static str {
+        match self.udp {
+            true => "UDP",
+            false => "TCP",
+        }
+    }
+}
+
+/// What one stream transferred.
+#[derive(Debug, Clone, Copy, Default)]
+struct Stats {
+    bytes: u64,
+    /// Highest sequence number seen, or datagrams sent.
+    packets: u64,
+    /// Datagrams lost.
+    errors: u64,
+    /// Seconds, RFC 1889 style.
+    jitter: f64,
+    transit: Option<f64>,
+}
+
+impl Stats {
+    fn received(&mut self, count: u64, transit: f64) {
+        match count > self.packets {
+            true => {
+                self.errors += count - 1 - self.packets;
+                self.packets = count;
+            }
+            // Late, it was counted as lost.
+            false => self.errors = self.errors.saturating_sub(1),
+        }
+
+        if let Some(previous) = self.transit {
+            self.jitter += ((transit - previous).abs() - self.jitter) / 16.0;
+        }
+
+        self.transit = Some(transit);
+    }
+}
+
+type Shared = Arc<Mutex<Vec<Stats>>>;
+
+/// Bytes left to send when the test ends after an amount of data.
+#[derive(Debug, Clone)]
+struct Budget(Option<Arc<AtomicU64>>);
+
+impl Budget {
+    fn take(&self, len: u64) -> bool {
+        match self.0 {
+            None => true,
+            Some(ref remaining) => remaining
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                    (remaining > 0).then(|| remaining.saturating_sub(len))
+                })
+                .is_ok(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pacer {
+    rate: u64,
+    started: Instant,
+    interval: Duration,
+}
+
+impl Pacer {
+    /// Waits until `sent` bytes are within the rate.
+    async fn wait(&self, sent: u64) {
+        if self.rate == 0 {
+            return;
+        }
+
+        while sent as f64 * 8.0 > self.rate as f64 * self.started.elapsed().as_secs_f64() {
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+/// How the server sends in reverse tests.
+#[derive(Debug, Clone)]
+struct Sending {
+    len: usize,
+    pacer: Pacer,
+    budget: Budget,
+    header: usize,
+}
+
+enum Streams {
+    Tcp(Vec<TcpStream>),
+    Udp(Vec<SocketAddr>),
+}
+
+/// The running test, data streams presenting its cookie are handed to it
+/// while it creates its streams.
+struct Active {
+    cookie: [u8; COOKIE_SIZE],
+    streams: Option<mpsc::UnboundedSender<(u64, TcpStream)>>,
+}
+
+struct State {
+    active: Mutex<Option<Active>>,
+    udp: Arc<UdpSocket>,
+    flight: Flight,
+}
+
+struct Summary {
+    params: Params,
+    bytes: u64,
+    seconds: f64,
+}
+
+/// iperf3 compatible server running one test at a time, like `iperf3 -s`.
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<State>,
+}
+
+impl Server {
+    /// Listens on TCP and UDP `addr`, clients are refused as busy while
+    /// `flight` is held by a test of this agent.
+    pub async fn bind(addr: SocketAddr, flight: Flight) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|err| Error::Bind(addr, err))?;
+        let addr = listener.local_addr()?;
+        let udp = UdpSocket::bind(addr)
+            .await
+            .map_err(|err| Error::Bind(addr, err))?;
+
+        Ok(Self {
+            listener,
+            state: Arc::new(State {
+                active: Mutex::new(None),
+                udp: Arc::new(udp),
+                flight,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves clients until `shutdown` is cancelled, a running test is then
+    /// terminated.
+    pub async fn run(self, shutdown: CancellationToken) {
+        let mut connections = JoinSet::new();
+        let mut sequence = 0;
+
+        loop {
+            let (socket, peer) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                Some(_) = connections.join_next() => continue,
+                accepted = self.listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        eprintln!("Failed to accept a connection: {err}");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+            };
+
+            sequence += 1;
+            connections.spawn(connection(
+                socket,
+                peer,
+                sequence,
+                Arc::clone(&self.state),
+                shutdown.clone(),
+            ));
+        }
+
+        while connections.join_next().await.is_some() {}
+    }
+}
+
+async fn connection(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    sequence: u64,
+    state: Arc<State>,
+    shutdown: CancellationToken,
+) {
+    let mut cookie = [0; COOKIE_SIZE];
+
+    if !matches!(
+        tokio::time::timeout(SETUP_TIMEOUT, socket.read_exact(&mut cookie)).await,
+        Ok(Ok(_))
+    ) {
+        return;
+    }
+
+    let flight = {
+        let mut active = state.active.lock().unwrap();
+
+        match *active {
+            Some(ref test) if test.cookie == cookie => {
+                if let Some(ref streams) = test.streams {
+                    _ = streams.send((sequence, socket));
+                }
+
+                return;
+            }
+            Some(_) => None,
+            None => {
+                let flight = state.flight.try_acquire();
+
+                if flight.is_some() {
+                    *active = Some(Active {
+                        cookie,
+                        streams: None,
+                    });
+                }
+
+                flight
+            }
+        }
+    };
+
+    let Some(_flight) = flight else {
+        eprintln!("Rejected {peer}, a test is already running");
+        _ = socket.write_i8(ACCESS_DENIED).await;
+        return;
+    };
+
+    println!("Accepted test from {peer}");
+
+    match test(socket, peer, &state, &shutdown).await {
+        Ok(Summary {
+            params,
+            bytes,
+            seconds,
+        }) => println!(
+            "Test from {peer} finished, {} {} streams {} {} over {seconds:.1}s",
+            params.protocol(),
+            params.streams(),
+            match params.reverse {
+                true => "sent",
+                false => "received",
+            },
+            report::bitrate(bytes as f64 * 8.0 / seconds.max(f64::EPSILON))
+        ),
+        Err(err) => eprintln!("Test from {peer} failed: {err}"),
+    }
+
+    state.active.lock().unwrap().take();
+}
+
+async fn within<T>(
+    step: &'static str,
+    future: impl std::future::Future<Output = std::io::Result<T>>,
+) -> Result<T, Error> {
+    match tokio::time::timeout(SETUP_TIMEOUT, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Error::Timeout(step)),
+    }
+}
+
+/// Reads a JSON message, prefixed by its length.
+async fn read_json<T: DeserializeOwned>(
+    control: &mut TcpStream,
+    step: &'static str,
+) -> Result<T, Error> {
+    let len = within(step, control.read_u32()).await?;
+
+    if len > MAX_JSON {
+        return Err(Error::Json(format!("{len} bytes is too long")));
+    }
+
+    let mut json = vec![0; len as usize];
+    within(step, control.read_exact(&mut json)).await?;
+
+    serde_json::from_slice(&json).map_err(|err| Error::Json(err.to_string()))
+}
+
+async fn write_json(control: &mut TcpStream, value: &serde_json::Value) -> Result<(), Error> {
+    let json = serde_json::to_vec(value).map_err(|err| Error::Json(err.to_string()))?;
+
+    control.write_u32(json.len() as u32).await?;
+    control.write_all(&json).await?;
+
+    Ok(())
+}
+
+/// Stream IDs as iperf3 numbers them, 1, 3, 4, 5...
+fn stream_id(index: usize) -> usize {
+    match index {
+        0 => 1,
+        index => index + 2,
+    }
+}
+
+fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+async fn tcp_streams(
+    receiver: &mut mpsc::UnboundedReceiver<(u64, TcpStream)>,
+    count: usize,
+) -> Result<Vec<TcpStream>, Error> {
+    let mut streams = Vec::new();
+
+    let collect = async {
+        while streams.len() < count {
+            match receiver.recv().await {
+                Some(stream) => streams.push(stream),
+                None => break,
+            }
+        }
+    };
+
+    if tokio::time::timeout(SETUP_TIMEOUT, collect).await.is_err() {
+        return Err(Error::Timeout("open its streams"));
+    }
+
+    // Connections are handed over as their cookie arrives, the IDs follow
+    // the order the client connected in.
+    streams.sort_by_key(|(sequence, _)| *sequence);
+
+    Ok(streams.into_iter().map(|(_, stream)| stream).collect())
+}
+
+/// Peers of `client` that sent the datagram opening a UDP stream.
+async fn udp_streams(
+    socket: &UdpSocket,
+    client: SocketAddr,
+    count: usize,
+) -> Result<Vec<SocketAddr>, Error> {
+    let mut peers = Vec::new();
+    let mut message = [0; 64];
+
+    within("open its streams", async {
+        while peers.len() < count {
+            let (len, peer) = socket.recv_from(&mut message).await?;
+
+            if len == 4 && peer.ip() == client.ip() && !peers.contains(&peer) {
+                socket
+                    .send_to(&UDP_CONNECT_REPLY.to_ne_bytes(), peer)
+                    .await?;
+                peers.push(peer);
+            }
+        }
+
+        Ok(())
+    })
+    .await?;
+
+    Ok(peers)
+}
+
+async fn receive_tcp(mut stream: TcpStream, index: usize, stats: Shared) {
+    let mut buffer = vec![0; MAX_BLOCK as usize];
+
+    while let Ok(len @ 1..) = stream.read(&mut buffer).await {
+        stats.lock().unwrap()[index].bytes += len as u64;
+    }
+}
+
+async fn send_tcp(mut stream: TcpStream, index: usize, stats: Shared, sending: Sending) {
+    let block = vec![0; sending.len];
+    let mut sent = 0;
+
+    loop {
+        sending.pacer.wait(sent).await;
+
+        if !sending.budget.take(block.len() as u64) || stream.write_all(&block).await.is_err() {
+            break;
+        }
+
+        sent += block.len() as u64;
+        stats.lock().unwrap()[index].bytes = sent;
+    }
+}
+
+/// Datagrams start with the time they were sent and a sequence number.
+fn parse_datagram(datagram: &[u8], header: usize) -> Option<(f64, u64)> {
+    let word = |at: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(
+            datagram.get(at..at + 4)?.try_into().ok()?,
+        ))
+    };
+
+    let sent = f64::from(word(0)?) + f64::from(word(4)?) / 1e6;
+    let count = match header {
+        12 => u64::from(word(8)?),
+        _ => u64::from_be_bytes(datagram.get(8..16)?.try_into().ok()?),
+    };
+
+    Some((sent, count))
+}
+
+fn write_datagram(datagram: &mut [u8], count: u64, header: usize) {
+    let now = since_epoch();
+
+    datagram[0..4].copy_from_slice(&(now.as_secs() as u32).to_be_bytes());
+    datagram[4..8].copy_from_slice(&now.subsec_micros().to_be_bytes());
+
+    match header {
+        12 => datagram[8..12].copy_from_slice(&(count as u32).to_be_bytes()),
+        _ => datagram[8..16].copy_from_slice(&count.to_be_bytes()),
+    }
+}
+
+async fn receive_udp(socket: Arc<UdpSocket>, peers: Vec<SocketAddr>, stats: Shared, header: usize) {
+    let mut datagram = vec![0; 65536];
+
+    while let Ok((len, peer)) = socket.recv_from(&mut datagram).await {
+        let Some(index) = peers.iter().position(|known| *known == peer) else {
+            continue;
+        };
+        let Some((sent, count)) = parse_datagram(&datagram[..len], header) else {
+            continue;
+        };
+
+        let mut stats = stats.lock().unwrap();
+        stats[index].bytes += len as u64;
+        stats[index].received(count, since_epoch().as_secs_f64() - sent);
+    }
+}
+
+async fn send_udp(
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    index: usize,
+    stats: Shared,
+    sending: Sending,
+) {
+    let mut datagram = vec![0; sending.len];
+    let mut sent = 0;
+    let mut count = 0;
+
+    loop {
+        sending.pacer.wait(sent).await;
+
+        if !sending.budget.take(datagram.len() as u64) {
+            break;
+        }
+
+        count += 1;
+        write_datagram(&mut datagram, count, sending.header);
+
+        if socket.send_to(&datagram, peer).await.is_ok() {
+            sent += datagram.len() as u64;
+
+            let mut stats = stats.lock().unwrap();
+            stats[index].bytes = sent;
+            stats[index].packets = count;
+        }
+    }
+}
+
+/// Results of the server side, as iperf3 exchanges them.
+fn results(stats: &[Stats], omitted: &[Stats], seconds: f64) -> serde_json::Value {
+    let streams = stats
+        .iter()
+        .zip(omitted)
+        .enumerate()
+        .map(|(index, (stats, omitted))| {
+            json!({
+                "id": stream_id(index),
+                "bytes": stats.bytes.saturating_sub(omitted.bytes),
+                "retransmits": -1,
+                "jitter": stats.jitter,
+                "errors": stats.errors.saturating_sub(omitted.errors),
+                "omitted_errors": omitted.errors,
+                "packets": stats.packets.saturating_sub(omitted.packets),
+                "omitted_packets": omitted.packets,
+                "start_time": 0.0,
+                "end_time": seconds,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "cpu_util_total": 0.0,
+        "cpu_util_user": 0.0,
+        "cpu_util_system": 0.0,
+        "sender_has_retransmits": 0,
+        "streams": streams,
+    })
+}
+
+async fn test(
+    mut control: TcpStream,
+    peer: SocketAddr,
+    state: &State,
+    shutdown: &CancellationToken,
+) -> Result<Summary, Error> {
+    control.write_i8(PARAM_EXCHANGE).await?;
+    let params = read_json::<Params>(&mut control, "send its parameters").await?;
+
+    if let Err((code, reason)) = params.check() {
+        control.write_i8(SERVER_ERROR).await?;
+        control.write_i32(code).await?;
+        control.write_i32(0).await?;
+
+        return Err(Error::Rejected(reason));
+    }
+
+    let count = params.streams();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    if let Some(ref mut active) = *state.active.lock().unwrap() {
+        active.streams = Some(sender);
+    }
+
+    control.write_i8(CREATE_STREAMS).await?;
+
+    let streams = match params.udp {
+        true => Streams::Udp(udp_streams(&state.udp, peer, count).await?),
+        false => Streams::Tcp(tcp_streams(&mut receiver, count).await?),
+    };
+
+    if let Some(ref mut active) = *state.active.lock().unwrap() {
+        active.streams = None;
+    }
+
+    control.write_i8(TEST_START).await?;
+    control.write_i8(TEST_RUNNING).await?;
+
+    let started = Instant::now();
+    let stats: Shared = Arc::new(Mutex::new(vec![Stats::default(); count]));
+    let sending = Sending {
+        len: params.len as usize,
+        pacer: Pacer {
+            rate: params.bandwidth,
+            started,
+            interval: Duration::from_micros(params.pacing_timer.clamp(1, 1_000_000)),
+        },
+        budget: params.budget(),
+        header: params.header(),
+    };
+    let mut transfers = JoinSet::new();
+
+    match streams {
+        Streams::Tcp(streams) => {
+            for (index, stream) in streams.into_iter().enumerate() {
+                let stats = Arc::clone(&stats);
+
+                match params.reverse {
+                    true => transfers.spawn(send_tcp(stream, index, stats, sending.clone())),
+                    false => transfers.spawn(receive_tcp(stream, index, stats)),
+                };
+            }
+        }
+        Streams::Udp(peers) if params.reverse => {
+            for (index, peer) in peers.into_iter().enumerate() {
+                transfers.spawn(send_udp(
+                    Arc::clone(&state.udp),
+                    peer,
+                    index,
+                    Arc::clone(&stats),
+                    sending.clone(),
+                ));
+            }
+        }
+        Streams::Udp(peers) => {
+            transfers.spawn(receive_udp(
+                Arc::clone(&state.udp),
+                peers,
+                Arc::clone(&stats),
+                sending.header,
+            ));
+        }
+    }
+
+    let omit = Duration::from_secs(params.omit);
+    let deadline = started
+        + omit
+        + match params.time {
+            0 => Duration::from_secs(MAX_TIME),
+            time => Duration::from_secs(time) + GRACE,
+        };
+    let mut omitted = vec![Stats::default(); count];
+    let mut omitting = params.omit > 0;
+
+    loop {
+        tokio::select! {
+            message = control.read_i8() => match message? {
+                TEST_END => break,
+                CLIENT_TERMINATE => return Err(Error::Terminated),
+                message => return Err(Error::State(message)),
+            },
+            _ = tokio::time::sleep_until(started + omit), if omitting => {
+                omitting = false;
+                omitted = stats.lock().unwrap().clone();
+            }
+            _ = tokio::time::sleep_until(deadline) => return Err(Error::Timeout("end the test")),
+            _ = shutdown.cancelled() => {
+                _ = control.write_i8(SERVER_TERMINATE).await;
+                return Err(Error::Shutdown);
+            }
+        }
+    }
+
+    transfers.abort_all();
+
+    let seconds = started.elapsed().saturating_sub(omit).as_secs_f64();
+    let stats = stats.lock().unwrap().clone();
+    let bytes = stats.iter().map(|stats| stats.bytes).sum::<u64>()
+        - omitted.iter().map(|stats| stats.bytes).sum::<u64>();
+
+    control.write_i8(EXCHANGE_RESULTS).await?;
+    read_json::<serde_json::Value>(&mut control, "send its results").await?;
+    write_json(&mut control, &results(&stats, &omitted, seconds)).await?;
+    control.write_i8(DISPLAY_RESULTS).await?;
+
+    // The results are out, a client leaving without its goodbye changes nothing.
+    match tokio::time::timeout(SETUP_TIMEOUT, control.read_i8()).await {
+        Ok(Ok(message)) if message != IPERF_DONE => return Err(Error::State(message)),
+        _ => {}
+    }
+
+    Ok(Summary {
+        params,
+        bytes,
+        seconds,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use rand::distributions::{Alphanumeric, DistString};
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpStream, UdpSocket};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        parse_datagram, read_json, stream_id, write_datagram, write_json, Flight, Server, Stats,
+        ACCESS_DENIED, COOKIE_SIZE, CREATE_STREAMS, DISPLAY_RESULTS, EXCHANGE_RESULTS, IEUNIMP,
+        IPERF_DONE, PARAM_EXCHANGE, SERVER_ERROR, SERVER_TERMINATE, TEST_END, TEST_RUNNING,
+        TEST_START, UDP_CONNECT_REPLY,
+    };
+    use crate::iperf3::{self, Options, Protocol};
+    use crate::models::IPerf3;
+    use crate::server::{self, Ports};
+
+    /// The client side of the protocol, as far as the tests need it.
+    struct Client {
+        control: TcpStream,
+        cookie: Vec<u8>,
+        addr: SocketAddr,
+    }
+
+    enum Data {
+        Tcp(Vec<TcpStream>),
+        Udp(UdpSocket),
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr) -> Self {
+            let mut cookie = Alphanumeric
+                .sample_string(&mut rand::thread_rng(), COOKIE_SIZE - 1)
+                .into_bytes();
+            cookie.push(0);
+
+            let mut control = TcpStream::connect(addr).await.unwrap();
+            control.write_all(&cookie).await.unwrap();
+
+            Self {
+                control,
+                cookie,
+                addr,
+            }
+        }
+
+        async fn state(&mut self) -> i8 {
+            self.control.read_i8().await.unwrap()
+        }
+
+        /// Runs the protocol up to the start of the transfer, or returns the
+        /// state the server answered with instead.
+        async fn setup(&mut self, params: serde_json::Value) -> Result<Data, i8> {
+            match self.state().await {
+                PARAM_EXCHANGE => {}
+                state => return Err(state),
+            }
+
+            write_json(&mut self.control, &params).await.unwrap();
+
+            match self.state().await {
+                CREATE_STREAMS => {}
+                state => return Err(state),
+            }
+
+            let count = params["parallel"].as_u64().unwrap();
+            let data = match params["udp"].as_bool() == Some(true) {
+                true => {
+                    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                    socket.connect(self.addr).await.unwrap();
+                    socket.send(&0x3637_3839_u32.to_ne_bytes()).await.unwrap();
+
+                    let mut reply = [0; 4];
+                    socket.recv(&mut reply).await.unwrap();
+                    assert_eq!(UDP_CONNECT_REPLY.to_ne_bytes(), reply);
+
+                    Data::Udp(socket)
+                }
+                false => {
+                    let mut streams = Vec::new();
+
+                    for _ in 0..count {
+                        let mut stream = TcpStream::connect(self.addr).await.unwrap();
+                        stream.write_all(&self.cookie).await.unwrap();
+                        streams.push(stream);
+                    }
+
+                    Data::Tcp(streams)
+                }
+            };
+
+            assert_eq!(TEST_START, self.state().await);
+            assert_eq!(TEST_RUNNING, self.state().await);
+
+            Ok(data)
+        }
+
+        /// Ends the test and returns the results of the server.
+        async fn finish(mut self) -> serde_json::Value {
+            self.control.write_i8(TEST_END).await.unwrap();
+            assert_eq!(EXCHANGE_RESULTS, self.state().await);
+
+            write_json(&mut self.control, &json!({ "streams": [] }))
+                .await
+                .unwrap();
+            let results = read_json(&mut self.control, "send its results")
+                .await
+                .unwrap();
+
+            assert_eq!(DISPLAY_RESULTS, self.state().await);
+            self.control.write_i8(IPERF_DONE).await.unwrap();
+
+            results
+        }
+    }
+
+    async fn serve() -> (SocketAddr, CancellationToken) {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Flight::default())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+
+        tokio::spawn(server.run(shutdown.clone()));
+
+        (addr, shutdown)
+    }
+
+    fn bytes(results: &serde_json::Value) -> Vec<u64> {
+        results["streams"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|stream| stream["bytes"].as_u64().unwrap())
+            .collect()
+    }
+
+    fn ids(results: &serde_json::Value) -> Vec<u64> {
+        results["streams"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|stream| stream["id"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_tcp_upload() {
+        let (addr, _shutdown) = serve().await;
+        let mut client = Client::connect(addr).await;
+
+        let Ok(Data::Tcp(mut streams)) = client
+            .setup(json!({ "tcp": true, "time": 1, "parallel": 2, "len": 131072 }))
+            .await
+        else {
+            panic!("no TCP streams");
+        };
+
+        let block = vec![0; 131072];
+        let mut sent = 0;
+        for _ in 0..8 {
+            for stream in streams.iter_mut() {
+                stream.write_all(&block).await.unwrap();
+                sent += block.len() as u64;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let results = client.finish().await;
+
+        assert_eq!(vec![1, 3], ids(&results));
+        assert_eq!(sent, bytes(&results).iter().sum::<u64>());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_download_stops_after_num_bytes() {
+        let (addr, _shutdown) = serve().await;
+        let mut client = Client::connect(addr).await;
+
+        let Ok(Data::Tcp(mut streams)) = client
+            .setup(json!({
+                "tcp": true, "reverse": true, "num": 1048576, "parallel": 1, "len": 131072
+            }))
+            .await
+        else {
+            panic!("no TCP streams");
+        };
+
+        let mut received = vec![0; 1048576];
+        streams[0].read_exact(&mut received).await.unwrap();
+
+        let results = client.finish().await;
+
+        assert_eq!(vec![1048576], bytes(&results));
+    }
+
+    #[tokio::test]
+    async fn test_udp_upload_counts_lost_datagrams() {
+        let (addr, _shutdown) = serve().await;
+        let mut client = Client::connect(addr).await;
+
+        let Ok(Data::Udp(socket)) = client
+            .setup(json!({ "udp": true, "time": 1, "parallel": 1, "len": 100 }))
+            .await
+        else {
+            panic!("no UDP stream");
+        };
+
+        for count in [1, 2, 3, 5, 6] {
+            let mut datagram = vec![0; 100];
+            write_datagram(&mut datagram, count, 12);
+            socket.send(&datagram).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let results = client.finish().await;
+        let stream = &results["streams"][0];
+
+        assert_eq!(Some(6), stream["packets"].as_u64());
+        assert_eq!(Some(1), stream["errors"].as_u64());
+        assert_eq!(Some(500), stream["bytes"].as_u64());
+    }
+
+    #[tokio::test]
+    async fn test_udp_download_is_paced() {
+        let (addr, _shutdown) = serve().await;
+        let mut client = Client::connect(addr).await;
+
+        let Ok(Data::Udp(socket)) = client
+            .setup(json!({
+                "udp": true, "reverse": true, "time": 1, "parallel": 1, "len": 1000,
+                "bandwidth": 400000, "udp_counters_64bit": 1
+            }))
+            .await
+        else {
+            panic!("no UDP stream");
+        };
+
+        let mut received = 0;
+        let mut datagram = vec![0; 2000];
+        let stop = tokio::time::sleep(Duration::from_millis(500));
+        tokio::pin!(stop);
+
+        loop {
+            tokio::select! {
+                _ = &mut stop => break,
+                len = socket.recv(&mut datagram) => {
+                    assert_eq!(1000, len.unwrap());
+                    received += 1;
+                    assert_eq!(Some(received), parse_datagram(&datagram, 16).map(|(_, count)| count));
+                }
+            }
+        }
+
+        let results = client.finish().await;
+        let packets = results["streams"][0]["packets"].as_u64().unwrap();
+
+        // 400 kbit/s are 50 datagrams a second.
+        assert!((20..=35).contains(&received), "{received}");
+        assert!(packets >= received, "{packets} < {received}");
+    }
+
+    #[tokio::test]
+    async fn test_one_test_at_a_time() {
+        let (addr, _shutdown) = serve().await;
+        let params = json!({ "tcp": true, "time": 1, "parallel": 1, "len": 1024 });
+
+        let mut running = Client::connect(addr).await;
+        assert!(running.setup(params.clone()).await.is_ok());
+
+        let mut rejected = Client::connect(addr).await;
+        assert_eq!(ACCESS_DENIED, rejected.state().await);
+
+        running.finish().await;
+        // The server frees the test once it got the goodbye.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut next = Client::connect(addr).await;
+        assert!(next.setup(params).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_tests_are_rejected() {
+        let (addr, _shutdown) = serve().await;
+        let mut client = Client::connect(addr).await;
+
+        let state = client
+            .setup(json!({ "tcp": true, "bidirectional": true, "parallel": 1, "len": 1024 }))
+            .await
+            .err();
+
+        assert_eq!(Some(SERVER_ERROR), state);
+        assert_eq!(IEUNIMP, client.control.read_i32().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_terminates_the_running_test() {
+        let (addr, shutdown) = serve().await;
+        let mut client = Client::connect(addr).await;
+
+        assert!(client
+            .setup(json!({ "tcp": true, "time": 10, "parallel": 1, "len": 1024 }))
+            .await
+            .is_ok());
+
+        shutdown.cancel();
+
+        assert_eq!(SERVER_TERMINATE, client.state().await);
+    }
+
+    /// Whether the real iperf3 is there to test against, tests using it pass
+    /// without doing anything otherwise.
+    async fn installed() -> bool {
+        let found = tokio::process::Command::new(iperf3::IPERF3_BINARY)
+            .arg("--version")
+            .output()
+            .await
+            .is_ok_and(|output| output.status.success());
+
+        if !found {
+            eprintln!("iperf3 is not installed, skipping");
+        }
+
+        found
+    }
+
+    #[tokio::test]
+    async fn test_iperf3_client() {
+        if !installed().await {
+            return;
+        }
+
+        let (addr, _shutdown) = serve().await;
+
+        for args in [&[][..], &["-u", "-b", "10M"], &["-R"]] {
+            let output = tokio::process::Command::new(iperf3::IPERF3_BINARY)
+                .args(["-c", "127.0.0.1", "-p", &addr.port().to_string()])
+                .args(["-t", "1", "-J"])
+                .args(args)
+                .output()
+                .await
+                .unwrap();
+            let result: IPerf3 = serde_json::from_slice(&output.stdout).unwrap();
+
+            assert!(output.status.success(), "{args:?}: {:?}", result.error);
+            assert!(!result.intervals.is_empty(), "{args:?}");
+            assert!(
+                result
+                    .intervals
+                    .iter()
+                    .map(|interval| interval.sum.bytes)
+                    .sum::<i64>()
+                    > 0,
+                "{args:?}"
+            );
+            // Every test waits until the server freed the one before.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_speed_tests() {
+        if !installed().await {
+            return;
+        }
+
+        let (addr, _shutdown) = serve().await;
+        let servers = [server::Server::new(
+            "127.0.0.1",
+            Ports::single(addr.port()),
+            1,
+        )];
+        let cancel = CancellationToken::new();
+
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            let options = Options {
+                timeout: 7,
+                duration: Some(1),
+                streams: 1,
+                protocol,
+                bitrate: Some(10_000_000),
+                ..Default::default()
+            };
+
+            let down = iperf3::download_speed(&servers, &options, &cancel)
+                .await
+                .unwrap();
+            assert!(down.start.test_start.reverse, "{protocol}");
+            assert!(!down.intervals.is_empty(), "{protocol}");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let up = iperf3::upload_speed(&servers, &options, &cancel)
+                .await
+                .unwrap();
+            assert!(!up.start.test_start.reverse, "{protocol}");
+            assert!(!up.intervals.is_empty(), "{protocol}");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[test]
+    fn test_stream_ids_follow_iperf3() {
+        assert_eq!(vec![1, 3, 4, 5], (0..4).map(stream_id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_late_datagrams_are_not_lost() {
+        let mut stats = Stats::default();
+
+        for count in [1, 3, 2, 4] {
+            stats.received(count, 0.001);
+        }
+
+        assert_eq!(4, stats.packets);
+        assert_eq!(0, stats.errors);
+    }
+}
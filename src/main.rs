@@ -1,13 +1,46 @@
+mod api;
+mod archive;
+mod catalog;
 mod cli;
+mod collector;
+mod config;
+mod dashboard;
+mod grafana;
+mod history;
+mod http;
 mod influxdb;
 mod iperf3;
+mod iperf3d;
+mod mesh;
 mod models;
+mod progress;
+mod replay;
+mod report;
+mod scheduler;
+mod selection;
+mod serve;
+mod server;
+mod serverlist;
 mod timetable;
+mod webtest;
+
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     match cli::execute().await {
-        Ok(_) => println!("Job finished"),
-        Err(e) => eprintln!("{e}"),
+        Ok(status) => {
+            match status {
+                cli::Status::Finished => {}
+                cli::Status::Stopped => println!("Shut down gracefully"),
+                cli::Status::Cancelled => eprintln!("Shut down, running tests were cancelled"),
+            }
+
+            status.exit_code()
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
     }
 }
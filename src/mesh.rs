@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio_util::sync::CancellationToken;
+
+use crate::cli::{self, Test};
+use crate::influxdb::Client;
+use crate::iperf3;
+use crate::server::Server;
+
+/// Longest pause before a busy peer is tested again.
+const BACKOFF: Duration = Duration::from_secs(10);
+/// Tests of a direction against a busy peer before it counts as failed.
+const ATTEMPTS: u32 = 5;
+
+/// Site to site tests between speedy agents, each one serving iperf3 to the others.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mesh {
+    /// Site of this agent, stored as `src_site` of its tests.
+    pub site: String,
+    /// Address peers test this agent on, read when `serve` starts.
+    pub listen: Option<SocketAddr>,
+    /// Agents tested by `mode=mesh` rules, by site.
+    pub peers: BTreeMap<String, Server>,
+}
+
+/// Lets an agent run one test at a time, whether it tests or serves a peer.
+#[derive(Debug, Clone, Default)]
+pub struct Flight(Arc<Mutex<()>>);
+
+impl Flight {
+    /// Waits for the running test, `None` when `cancel` fires first.
+    pub async fn acquire(&self, cancel: &CancellationToken) -> Option<OwnedMutexGuard<()>> {
+        tokio::select! {
+            guard = Arc::clone(&self.0).lock_owned() => Some(guard),
+            _ = cancel.cancelled() => None,
+        }
+    }
+
+    /// Whether a test runs right now.
+    pub fn busy(&self) -> bool {
+        self.0.try_lock().is_err()
+    }
+
+    /// `None` while a test runs.
+    pub fn try_acquire(&self) -> Option<OwnedMutexGuard<()>> {
+        Arc::clone(&self.0).try_lock_owned().ok()
+    }
+}
+
+/// Tests every peer of `test.mesh` in turn.
+pub async fn run(client: Option<&Client>, test: &Test) -> Result<(), cli::Error> {
+    let directions = [true, false]
+        .into_iter()
+        .filter(|download| match download {
+            true => test.direction.download(),
+            false => test.direction.upload(),
+        })
+        .collect::<Vec<_>>();
+
+    each(
+        &test.mesh,
+        test.flight.as_ref(),
+        &test.cancel,
+        &directions,
+        BACKOFF,
+        move |peer, download, tags| async move {
+            let tags = tags
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
+            let now = time::OffsetDateTime::now_utc();
+
+            cli::run_direction(&[peer], client, test, download, now, &tags).await
+        },
+    )
+    .await
+}
+
+/// Runs `attempt` for every peer and direction while holding the flight. A
+/// busy peer is most likely testing this agent, the flight is released for
+/// that test and the peer tried again after a random pause.
+async fn each<F, Fut>(
+    mesh: &Mesh,
+    flight: Option<&Flight>,
+    cancel: &CancellationToken,
+    directions: &[bool],
+    backoff: Duration,
+    mut attempt: F,
+) -> Result<(), cli::Error>
+where
+    F: FnMut(Server, bool, Vec<(String, String)>) -> Fut,
+    Fut: Future<Output = Result<(), cli::Error>>,
+{
+    let mut failed = None;
+
+    for (site, peer) in mesh.peers.iter() {
+        let tags = vec![
+            ("src_site".to_string(), mesh.site.clone()),
+            ("dst_site".to_string(), site.clone()),
+        ];
+
+        eprintln!("Testing peer {site} ({})", peer.host);
+
+        for &download in directions {
+            let mut attempts = 0;
+
+            let result = loop {
+                let _flight = match flight {
+                    Some(flight) => match flight.acquire(cancel).await {
+                        Some(guard) => Some(guard),
+                        None => return Err(iperf3::Error::Canceled.into()),
+                    },
+                    None => None,
+                };
+
+                attempts += 1;
+
+                match attempt(peer.clone(), download, tags.clone()).await {
+                    Err(err) if err.busy() && attempts < ATTEMPTS => {}
+                    result => break result,
+                }
+
+                drop(_flight);
+
+                let pause = rand::thread_rng().gen_range(backoff / 10..=backoff);
+                eprintln!(
+                    "Peer {site} is busy, testing it again in {:.1}s",
+                    pause.as_secs_f64()
+                );
+
+                tokio::select! {
+                    _ = tokio::time::sleep(pause) => {}
+                    _ = cancel.cancelled() => return Err(iperf3::Error::Canceled.into()),
+                }
+            };
+
+            if let Err(err) = result {
+                eprintln!("Failed to test peer {site}: {err}");
+                failed.get_or_insert(err);
+            }
+        }
+    }
+
+    match failed {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{each, Flight, Mesh};
+    use crate::server::Server;
+    use crate::{cli, iperf3, iperf3d};
+
+    fn mesh(site: &str, peers: &[(&str, SocketAddr)]) -> Mesh {
+        Mesh {
+            site: site.to_string(),
+            listen: None,
+            peers: peers
+                .iter()
+                .map(|(site, addr)| (site.to_string(), addr.to_string().parse().unwrap()))
+                .collect::<BTreeMap<_, Server>>(),
+        }
+    }
+
+    /// Opens a test on the peer like an iperf3 client and keeps it a moment.
+    async fn probe(peer: Server) -> Result<(), cli::Error> {
+        let port = peer.ports.iter().next().unwrap();
+        let mut control = TcpStream::connect((peer.host.as_str(), port))
+            .await
+            .unwrap();
+        control.write_all(&[b'x'; 37]).await.unwrap();
+
+        match control.read_i8().await.unwrap() {
+            -1 => Err(iperf3::Error::Busy(peer.host).into()),
+            _ => {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_results_are_tagged_with_both_sites() {
+        let addr = "127.0.0.1:5201".parse().unwrap();
+        let mesh = mesh("zurich", &[("berlin", addr), ("paris", addr)]);
+        let seen = Mutex::new(Vec::new());
+
+        each(
+            &mesh,
+            None,
+            &CancellationToken::new(),
+            &[true, false],
+            Duration::ZERO,
+            |_, download, tags| {
+                seen.lock().unwrap().push((download, tags));
+                async { Ok(()) }
+            },
+        )
+        .await
+        .unwrap();
+
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(4, seen.len());
+        assert_eq!(
+            (
+                false,
+                vec![
+                    ("src_site".to_string(), "zurich".to_string()),
+                    ("dst_site".to_string(), "paris".to_string())
+                ]
+            ),
+            seen[3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_busy_peers_are_tried_again() {
+        let addr = "127.0.0.1:5201".parse().unwrap();
+        let attempts = Arc::new(Mutex::new(0));
+
+        let result = each(
+            &mesh("zurich", &[("berlin", addr)]),
+            Some(&Flight::default()),
+            &CancellationToken::new(),
+            &[true],
+            Duration::from_millis(10),
+            |peer, _, _| {
+                let attempts = Arc::clone(&attempts);
+
+                async move {
+                    *attempts.lock().unwrap() += 1;
+
+                    match *attempts.lock().unwrap() {
+                        1 | 2 => Err(iperf3::Error::Busy(peer.host).into()),
+                        _ => Ok(()),
+                    }
+                }
+            },
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(3, *attempts.lock().unwrap());
+    }
+
+    /// Two agents on localhost testing each other at the same time, each one
+    /// refuses the other while it tests, yet both tests eventually run.
+    #[tokio::test]
+    async fn test_agents_testing_each_other_take_turns() {
+        let mut agents = Vec::new();
+
+        for _ in 0..2 {
+            let flight = Flight::default();
+            let server = iperf3d::Server::bind("127.0.0.1:0".parse().unwrap(), flight.clone())
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+
+            tokio::spawn(server.run(CancellationToken::new()));
+            agents.push((flight, addr));
+        }
+
+        let zurich = mesh("zurich", &[("berlin", agents[1].1)]);
+        let berlin = mesh("berlin", &[("zurich", agents[0].1)]);
+        let cancel = CancellationToken::new();
+        let backoff = Duration::from_millis(50);
+
+        let (zurich, berlin) = tokio::join!(
+            each(
+                &zurich,
+                Some(&agents[0].0),
+                &cancel,
+                &[true],
+                backoff,
+                |peer, _, _| probe(peer)
+            ),
+            each(
+                &berlin,
+                Some(&agents[1].0),
+                &cancel,
+                &[true],
+                backoff,
+                |peer, _, _| probe(peer)
+            ),
+        );
+
+        assert!(zurich.is_ok(), "{zurich:?}");
+        assert!(berlin.is_ok(), "{berlin:?}");
+    }
+}
@@ -1,212 +1,520 @@
-use serde_derive::Deserialize;
-use serde_derive::Serialize;
+//! Output of `iperf3 -J`.
+//!
+//! Fields differ between iperf3 3.1 and 3.17 and between platforms (macOS
+//! has no TCP info, UDP tests have no sent/received sums), so every field is
+//! optional or defaulted, numbers are read whether iperf3 wrote them as
+//! integers, floats or strings, and fields this model does not name are kept
+//! in `extra`.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Fields of an object that have no dedicated field in the model.
+pub type Extra = BTreeMap<String, Value>;
+
+fn number(value: Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        Value::Bool(value) => Some(f64::from(u8::from(value))),
+        _ => None,
+    }
+}
+
+fn integer_value(value: Value) -> Option<i64> {
+    match value {
+        Value::Number(ref number) => number
+            .as_i64()
+            .or_else(|| number.as_f64().map(|n| n as i64)),
+        value => number(value).map(|n| n as i64),
+    }
+}
+
+fn int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    Ok(integer_value(Value::deserialize(deserializer)?).unwrap_or_default())
+}
+
+fn float<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    Ok(number(Value::deserialize(deserializer)?).unwrap_or_default())
+}
+
+fn opt_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    Ok(integer_value(Value::deserialize(deserializer)?))
+}
+
+fn opt_float<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    Ok(number(Value::deserialize(deserializer)?))
+}
+
+/// `true`, `1` and `"1"` are all true, older versions write flags as numbers.
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bool(value) => value,
+        value => number(value).unwrap_or_default() != 0.0,
+    })
+}
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct IPerf3 {
     pub start: Start,
     pub intervals: Vec<Interval>,
     pub end: End,
+    /// Set instead of (or next to) results when the test failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Start {
     pub connected: Vec<Connected>,
     pub version: String,
-    #[serde(rename = "system_info")]
     pub system_info: String,
     pub timestamp: Timestamp,
-    #[serde(rename = "connecting_to")]
     pub connecting_to: ConnectingTo,
     pub cookie: String,
-    #[serde(rename = "tcp_mss_default")]
-    pub tcp_mss_default: i64,
-    #[serde(rename = "target_bitrate")]
-    pub target_bitrate: i64,
-    #[serde(rename = "fq_rate")]
-    pub fq_rate: i64,
-    #[serde(rename = "sock_bufsize")]
-    pub sock_bufsize: i64,
-    #[serde(rename = "sndbuf_actual")]
-    pub sndbuf_actual: i64,
-    #[serde(rename = "rcvbuf_actual")]
-    pub rcvbuf_actual: i64,
-    #[serde(rename = "test_start")]
+    // Only reported for TCP tests.
+    #[serde(deserialize_with = "opt_int")]
+    pub tcp_mss_default: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub target_bitrate: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub fq_rate: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub sock_bufsize: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub sndbuf_actual: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub rcvbuf_actual: Option<i64>,
     pub test_start: TestStart,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Connected {
+    #[serde(deserialize_with = "int")]
     pub socket: i64,
-    #[serde(rename = "local_host")]
     pub local_host: String,
-    #[serde(rename = "local_port")]
+    #[serde(deserialize_with = "int")]
     pub local_port: i64,
-    #[serde(rename = "remote_host")]
     pub remote_host: String,
-    #[serde(rename = "remote_port")]
+    #[serde(deserialize_with = "int")]
     pub remote_port: i64,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Timestamp {
     pub time: String,
+    #[serde(deserialize_with = "int")]
     pub timesecs: i64,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct ConnectingTo {
     pub host: String,
+    #[serde(deserialize_with = "int")]
     pub port: i64,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct TestStart {
     pub protocol: String,
-    #[serde(rename = "num_streams")]
+    #[serde(deserialize_with = "int")]
     pub num_streams: i64,
+    #[serde(deserialize_with = "int")]
     pub blksize: i64,
+    #[serde(deserialize_with = "int")]
     pub omit: i64,
+    #[serde(deserialize_with = "int")]
     pub duration: i64,
+    #[serde(deserialize_with = "int")]
     pub bytes: i64,
+    #[serde(deserialize_with = "int")]
     pub blocks: i64,
-    pub reverse: i64,
+    #[serde(deserialize_with = "flag")]
+    pub reverse: bool,
+    #[serde(deserialize_with = "int")]
     pub tos: i64,
-    #[serde(rename = "target_bitrate")]
-    pub target_bitrate: i64,
-    pub bidir: i64,
-    pub fqrate: i64,
+    #[serde(deserialize_with = "opt_int")]
+    pub target_bitrate: Option<i64>,
+    #[serde(deserialize_with = "flag")]
+    pub bidir: bool,
+    #[serde(deserialize_with = "opt_int")]
+    pub fqrate: Option<i64>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Interval {
     pub streams: Vec<Stream>,
     pub sum: Sum,
+    /// Reverse direction of a `--bidir` test.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sum_bidir_reverse: Option<Sum>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Stream {
+    #[serde(deserialize_with = "int")]
     pub socket: i64,
+    #[serde(deserialize_with = "float")]
     pub start: f64,
+    #[serde(deserialize_with = "float")]
     pub end: f64,
+    #[serde(deserialize_with = "float")]
     pub seconds: f64,
+    #[serde(deserialize_with = "int")]
     pub bytes: i64,
-    #[serde(rename = "bits_per_second")]
+    #[serde(deserialize_with = "float")]
     pub bits_per_second: f64,
+    #[serde(deserialize_with = "opt_int")]
+    pub retransmits: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub snd_cwnd: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub rtt: Option<i64>,
+    #[serde(deserialize_with = "flag")]
+    pub omitted: bool,
+    #[serde(deserialize_with = "flag")]
     pub sender: bool,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Sum {
+    #[serde(deserialize_with = "float")]
     pub start: f64,
+    #[serde(deserialize_with = "float")]
     pub end: f64,
+    #[serde(deserialize_with = "float")]
     pub seconds: f64,
+    #[serde(deserialize_with = "int")]
     pub bytes: i64,
-    #[serde(rename = "bits_per_second")]
+    #[serde(deserialize_with = "float")]
     pub bits_per_second: f64,
+    #[serde(deserialize_with = "opt_int")]
+    pub retransmits: Option<i64>,
+    // UDP only.
+    #[serde(deserialize_with = "opt_float")]
+    pub jitter_ms: Option<f64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub lost_packets: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub packets: Option<i64>,
+    #[serde(deserialize_with = "opt_float")]
+    pub lost_percent: Option<f64>,
+    #[serde(deserialize_with = "flag")]
+    pub omitted: bool,
+    #[serde(deserialize_with = "flag")]
     pub sender: bool,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct End {
     pub streams: Vec<Stream2>,
-    #[serde(rename = "sum_sent")]
+    // UDP tests report a single `sum` instead of sent and received sums.
     pub sum_sent: SumSent,
-    #[serde(rename = "sum_received")]
     pub sum_received: SumReceived,
-    #[serde(rename = "cpu_utilization_percent")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sum: Option<Sum>,
     pub cpu_utilization_percent: CpuUtilizationPercent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_tcp_congestion: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receiver_tcp_congestion: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Stream2 {
     pub sender: Sender,
     pub receiver: Receiver,
+    /// Per-stream result of a UDP test.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp: Option<Sum>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Sender {
+    #[serde(deserialize_with = "int")]
     pub socket: i64,
-    pub start: i64,
+    #[serde(deserialize_with = "float")]
+    pub start: f64,
+    #[serde(deserialize_with = "float")]
     pub end: f64,
+    #[serde(deserialize_with = "float")]
     pub seconds: f64,
+    #[serde(deserialize_with = "int")]
     pub bytes: i64,
-    #[serde(rename = "bits_per_second")]
+    #[serde(deserialize_with = "opt_float")]
     pub bits_per_second: Option<f64>,
-    #[serde(rename = "max_snd_cwnd")]
+    #[serde(deserialize_with = "opt_int")]
+    pub retransmits: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
     pub max_snd_cwnd: Option<i64>,
-    #[serde(rename = "max_snd_wnd")]
+    #[serde(deserialize_with = "opt_int")]
     pub max_snd_wnd: Option<i64>,
-    #[serde(rename = "max_rtt")]
+    #[serde(deserialize_with = "opt_int")]
     pub max_rtt: Option<i64>,
-    #[serde(rename = "min_rtt")]
-    pub min_rtt: i64,
-    #[serde(rename = "mean_rtt")]
-    pub mean_rtt: i64,
+    #[serde(deserialize_with = "opt_int")]
+    pub min_rtt: Option<i64>,
+    #[serde(deserialize_with = "opt_int")]
+    pub mean_rtt: Option<i64>,
+    #[serde(deserialize_with = "flag")]
     pub sender: bool,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct Receiver {
+    #[serde(deserialize_with = "int")]
     pub socket: i64,
-    pub start: i64,
+    #[serde(deserialize_with = "float")]
+    pub start: f64,
+    #[serde(deserialize_with = "float")]
     pub end: f64,
+    #[serde(deserialize_with = "float")]
     pub seconds: f64,
+    #[serde(deserialize_with = "int")]
     pub bytes: i64,
-    #[serde(rename = "bits_per_second")]
+    #[serde(deserialize_with = "float")]
     pub bits_per_second: f64,
+    #[serde(deserialize_with = "flag")]
     pub sender: bool,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct SumSent {
-    pub start: i64,
+    #[serde(deserialize_with = "float")]
+    pub start: f64,
+    #[serde(deserialize_with = "float")]
     pub end: f64,
+    #[serde(deserialize_with = "float")]
     pub seconds: f64,
+    #[serde(deserialize_with = "int")]
     pub bytes: i64,
-    #[serde(rename = "bits_per_second")]
+    #[serde(deserialize_with = "float")]
     pub bits_per_second: f64,
+    #[serde(deserialize_with = "opt_int")]
+    pub retransmits: Option<i64>,
+    #[serde(deserialize_with = "flag")]
     pub sender: bool,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct SumReceived {
-    pub start: i64,
+    #[serde(deserialize_with = "float")]
+    pub start: f64,
+    #[serde(deserialize_with = "float")]
     pub end: f64,
+    #[serde(deserialize_with = "float")]
     pub seconds: f64,
+    #[serde(deserialize_with = "int")]
     pub bytes: i64,
-    #[serde(rename = "bits_per_second")]
+    #[serde(deserialize_with = "float")]
     pub bits_per_second: f64,
+    #[serde(deserialize_with = "flag")]
     pub sender: bool,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default)]
 pub struct CpuUtilizationPercent {
-    #[serde(rename = "host_total")]
+    #[serde(deserialize_with = "float")]
     pub host_total: f64,
-    #[serde(rename = "host_user")]
+    #[serde(deserialize_with = "float")]
     pub host_user: f64,
-    #[serde(rename = "host_system")]
+    #[serde(deserialize_with = "float")]
     pub host_system: f64,
-    #[serde(rename = "remote_total")]
+    #[serde(deserialize_with = "float")]
     pub remote_total: f64,
-    #[serde(rename = "remote_user")]
+    #[serde(deserialize_with = "float")]
     pub remote_user: f64,
-    #[serde(rename = "remote_system")]
+    #[serde(deserialize_with = "float")]
     pub remote_system: f64,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IPerf3;
+
+    const CORPUS: [(&str, &str); 7] = [
+        (
+            "3.1.3-linux-tcp-upload",
+            include_str!("../fixtures/iperf3/3.1.3-linux-tcp-upload.json"),
+        ),
+        (
+            "3.7-linux-tcp-download",
+            include_str!("../fixtures/iperf3/3.7-linux-tcp-download.json"),
+        ),
+        (
+            "3.9-linux-udp-upload",
+            include_str!("../fixtures/iperf3/3.9-linux-udp-upload.json"),
+        ),
+        (
+            "3.12-macos-tcp-upload",
+            include_str!("../fixtures/iperf3/3.12-macos-tcp-upload.json"),
+        ),
+        (
+            "3.16-linux-tcp-bidir",
+            include_str!("../fixtures/iperf3/3.16-linux-tcp-bidir.json"),
+        ),
+        (
+            "3.17-linux-tcp-multistream",
+            include_str!("../fixtures/iperf3/3.17-linux-tcp-multistream.json"),
+        ),
+        (
+            "3.17-server-busy",
+            include_str!("../fixtures/iperf3/3.17-server-busy.json"),
+        ),
+    ];
+
+    fn fixture(name: &str) -> IPerf3 {
+        let (_, content) = CORPUS.iter().find(|(file, _)| *file == name).unwrap();
+
+        serde_json::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn test_corpus_parses() {
+        for (name, content) in CORPUS {
+            let result = serde_json::from_str::<IPerf3>(content)
+                .unwrap_or_else(|err| panic!("{name}: {err}"));
+            let version = name.split('-').next().unwrap();
+
+            assert!(
+                result
+                    .start
+                    .version
+                    .starts_with(&format!("iperf {version}")),
+                "{name}"
+            );
+
+            if result.error.is_none() {
+                assert!(!result.intervals.is_empty(), "{name}");
+                assert!(
+                    result
+                        .intervals
+                        .iter()
+                        .all(|interval| interval.sum.bits_per_second > 0.0
+                            && interval.sum.seconds > 0.0),
+                    "{name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_version_differences() {
+        let old = fixture("3.1.3-linux-tcp-upload");
+        assert_eq!(None, old.start.sndbuf_actual);
+        assert!(!old.start.test_start.reverse);
+        assert_eq!(Some(9830), old.end.streams[0].sender.min_rtt);
+
+        let download = fixture("3.7-linux-tcp-download");
+        assert!(download.start.test_start.reverse);
+        assert_eq!(None, download.end.streams[0].sender.min_rtt);
+
+        let macos = fixture("3.12-macos-tcp-upload");
+        assert_eq!(None, macos.intervals[0].streams[0].rtt);
+        assert_eq!(None, macos.end.sum_sent.retransmits);
+
+        let udp = fixture("3.9-linux-udp-upload");
+        let sum = udp.end.sum.unwrap();
+        assert_eq!(Some(12), sum.lost_packets);
+        assert_eq!(Some(0.0412), sum.jitter_ms);
+        assert_eq!(Some(8632), udp.intervals[0].sum.packets);
+        assert_eq!(
+            Some(17265),
+            udp.end.streams[0].udp.as_ref().unwrap().packets
+        );
+
+        let busy = fixture("3.17-server-busy");
+        assert!(busy.error.unwrap().contains("server is busy"));
+        assert!(busy.intervals.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_fields_are_kept() {
+        let bidir = fixture("3.16-linux-tcp-bidir");
+
+        assert!(bidir.start.test_start.bidir);
+        assert!(bidir.intervals[0].sum_bidir_reverse.is_some());
+        assert!(bidir.end.extra.contains_key("sum_sent_bidir_reverse"));
+        assert_eq!(
+            Some(&serde_json::json!(401)),
+            bidir.intervals[0].streams[0].extra.get("rttvar")
+        );
+
+        let value = serde_json::to_value(&bidir).unwrap();
+        assert_eq!(
+            bidir.end.extra["sum_received_bidir_reverse"],
+            value["end"]["sum_received_bidir_reverse"]
+        );
+        assert_eq!(bidir, serde_json::from_value(value).unwrap());
+    }
+
+    #[test]
+    fn test_numbers_are_flexible() {
+        let result: IPerf3 = serde_json::from_str(
+            r#"{
+                "start": {"tcp_mss_default": "1448", "timestamp": {"timesecs": 1.7e9}},
+                "intervals": [{"sum": {"start": 0, "end": "1.5", "bytes": 1024.0, "bits_per_second": 8000, "sender": 1}}],
+                "end": {"streams": [{"sender": {"start": 0.0, "min_rtt": 9830.4, "max_rtt": null}}]}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(Some(1448), result.start.tcp_mss_default);
+        assert_eq!(1_700_000_000, result.start.timestamp.timesecs);
+
+        let sum = &result.intervals[0].sum;
+        assert_eq!((0.0, 1.5, 1024), (sum.start, sum.end, sum.bytes));
+        assert_eq!(8000.0, sum.bits_per_second);
+        assert!(sum.sender);
+
+        let sender = &result.end.streams[0].sender;
+        assert_eq!((Some(9830), None), (sender.min_rtt, sender.max_rtt));
+    }
 }
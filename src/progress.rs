@@ -0,0 +1,236 @@
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+
+use time::OffsetDateTime;
+
+use crate::influxdb::Direction;
+use crate::iperf3::Event;
+use crate::report::{self, Builder, Report};
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+/// Intervals shown by the sparkline, older ones scroll out on the left.
+const SPARKLINE_WIDTH: usize = 30;
+
+/// How the intervals are shown while a test runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Live {
+    /// Redrawn in place on a terminal.
+    Interactive,
+    /// One line per interval.
+    Lines,
+    /// Nothing, stdout only gets the final report.
+    Quiet,
+}
+
+/// Progress of the tests of `speedy run`, keeps a [`Report`] per direction.
+#[derive(Debug)]
+pub struct Progress {
+    live: Live,
+    reports: Mutex<Vec<Report>>,
+}
+
+/// Progress of one direction of a test.
+#[derive(Debug)]
+pub struct Meter<'a> {
+    progress: &'a Progress,
+    builder: Builder,
+}
+
+impl Progress {
+    /// `Interactive` falls back to `Lines` when stdout is not a terminal.
+    pub fn new(live: Live) -> Self {
+        Self {
+            live: match live {
+                Live::Interactive if !std::io::stdout().is_terminal() => Live::Lines,
+                live => live,
+            },
+            reports: Mutex::default(),
+        }
+    }
+
+    pub fn meter(
+        &self,
+        direction: Direction,
+        time: OffsetDateTime,
+        tags: &[(&str, &str)],
+    ) -> Meter<'_> {
+        Meter {
+            progress: self,
+            builder: Builder::new(direction, time, tags),
+        }
+    }
+
+    /// Every direction tested so far.
+    pub fn reports(&self) -> Vec<Report> {
+        self.reports.lock().unwrap().clone()
+    }
+}
+
+impl Meter<'_> {
+    fn label(&self) -> &'static str {
+        match self.builder.report().direction {
+            Direction::Download => "download",
+            Direction::Upload => "upload",
+        }
+    }
+
+    pub fn event(&mut self, event: &Event) {
+        let restarted = !self.builder.report().intervals.is_empty();
+        self.builder.event(event);
+
+        match event {
+            Event::Start(_) => {
+                if self.progress.live == Live::Interactive && restarted {
+                    println!();
+                }
+
+                if self.progress.live != Live::Quiet {
+                    println!(
+                        "Testing {} against {}",
+                        self.label(),
+                        self.builder.report().server
+                    );
+                }
+            }
+            Event::Interval(interval) => match self.progress.live {
+                Live::Interactive => {
+                    print!("\r\x1b[2K{}", self.line());
+                    _ = std::io::stdout().flush();
+                }
+                Live::Lines => println!(
+                    "{} {:.0}-{:.0}s {}",
+                    self.label(),
+                    interval.sum.start,
+                    interval.sum.end,
+                    self.line()
+                ),
+                Live::Quiet => {}
+            },
+            Event::End(_) => {}
+        }
+    }
+
+    /// `host` and `port` are the server a failed direction was tried against.
+    pub fn attempted(&mut self, host: &str, port: u16) {
+        self.builder.attempted(host, port);
+    }
+
+    fn line(&self) -> String {
+        let intervals = &self.builder.report().intervals;
+        let last = intervals.last();
+
+        let mut line = format!(
+            "{:<8} {:<width$} {:>13}",
+            self.label(),
+            sparkline(
+                &intervals
+                    .iter()
+                    .map(|interval| interval.bits_per_second)
+                    .collect::<Vec<_>>()
+            ),
+            report::bitrate(last.map_or(0.0, |interval| interval.bits_per_second)),
+            width = SPARKLINE_WIDTH
+        );
+
+        if let Some(rtt) = last.and_then(|interval| interval.rtt_ms) {
+            line += &format!("  rtt {rtt:.1}ms");
+        }
+
+        if let Some(retransmits) = report::retransmits(intervals) {
+            line += &format!("  retr {retransmits}");
+        }
+
+        line
+    }
+
+    /// Ends the live line and keeps the report, `error` is why the direction
+    /// failed, intervals received before it are kept.
+    pub fn done(self, error: Option<String>) {
+        let measured = !self.builder.report().intervals.is_empty();
+
+        if !measured && error.is_none() {
+            return;
+        }
+
+        if self.progress.live == Live::Interactive && measured {
+            println!();
+        }
+
+        let report = self.builder.build(error);
+        self.progress.reports.lock().unwrap().push(report);
+    }
+}
+
+/// One block per speed, scaled to the fastest one shown.
+fn sparkline(speeds: &[f64]) -> String {
+    let shown = &speeds[speeds.len().saturating_sub(SPARKLINE_WIDTH)..];
+    let peak = shown.iter().copied().fold(0.0, f64::max);
+
+    shown
+        .iter()
+        .map(|speed| match peak > 0.0 {
+            true => SPARKS[((speed / peak) * (SPARKS.len() - 1) as f64).round() as usize],
+            false => SPARKS[0],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::{sparkline, Live, Progress};
+    use crate::influxdb::Direction;
+    use crate::iperf3::Event;
+    use crate::models::IPerf3;
+
+    #[test]
+    fn test_sparkline_scales_to_the_peak() {
+        assert_eq!("▁▅█", sparkline(&[0.0, 50.0, 100.0]));
+        assert_eq!("▁▁", sparkline(&[0.0, 0.0]));
+        assert_eq!(30, sparkline(&[1.0; 40]).chars().count());
+    }
+
+    #[test]
+    fn test_meter_reports_a_test() {
+        let result: IPerf3 = serde_json::from_str(include_str!(
+            "../fixtures/iperf3/3.17-linux-tcp-multistream.json"
+        ))
+        .unwrap();
+        let progress = Progress::new(Live::Quiet);
+        let mut meter = progress.meter(Direction::Upload, datetime!(2024-06-04 16:12:48 UTC), &[]);
+
+        meter.event(&Event::Start(Box::new(result.start.clone())));
+        for interval in result.intervals.iter() {
+            meter.event(&Event::Interval(interval.clone()));
+        }
+        meter.event(&Event::End(Box::new(result.end.clone())));
+        meter.done(None);
+
+        let reports = progress.reports();
+        assert_eq!("speedtest.init7.net:5201", reports[0].server);
+        assert_eq!(2, reports[0].intervals.len());
+        assert_eq!(
+            Some(result.end.sum_received.bits_per_second),
+            reports[0].bits_per_second
+        );
+        assert!(reports[0].rtt_ms.is_some());
+    }
+
+    #[test]
+    fn test_meter_keeps_failed_directions() {
+        let progress = Progress::new(Live::Quiet);
+
+        progress
+            .meter(Direction::Download, datetime!(2024-06-04 16:12:48 UTC), &[])
+            .done(None);
+        progress
+            .meter(Direction::Upload, datetime!(2024-06-04 16:12:48 UTC), &[])
+            .done(Some("unable to connect".to_string()));
+
+        let reports = progress.reports();
+        assert_eq!(1, reports.len());
+        assert_eq!(Some("unable to connect"), reports[0].error.as_deref());
+        assert_eq!(None, reports[0].bits_per_second);
+    }
+}
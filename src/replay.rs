@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::collector::Sink;
+use crate::history::{self, History};
+use crate::influxdb::{self, Client, Direction};
+use crate::iperf3::Event;
+use crate::models::{IPerf3, Interval};
+use crate::report::{Builder, Report};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to read results ({0}): {1}")]
+    IO(String, std::io::Error),
+
+    #[error("Failed to write results of {0}: {1}")]
+    InfluxDB(String, influxdb::Error),
+
+    #[error(transparent)]
+    History(#[from] history::Error),
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub files: usize,
+    pub points: usize,
+    /// Files that could not be imported, with the reason.
+    pub skipped: Vec<String>,
+}
+
+/// `paths` with directories replaced by the `.json` files below them, sorted.
+async fn files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    let mut pending = paths.to_vec();
+
+    while let Some(path) = pending.pop() {
+        let io = |err| Error::IO(path.display().to_string(), err);
+
+        if !tokio::fs::metadata(&path).await.map_err(io)?.is_dir() {
+            files.push(path);
+            continue;
+        }
+
+        let mut entries = tokio::fs::read_dir(&path).await.map_err(io)?;
+
+        while let Some(entry) = entries.next_entry().await.map_err(io)? {
+            let path = entry.path();
+
+            if entry.file_type().await.map_err(io)?.is_dir() {
+                pending.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    files.dedup();
+
+    Ok(files)
+}
+
+/// One report per direction of an archived test. Points are keyed by the
+/// server, the direction and the time the test started plus the end of each
+/// interval, so the same file always yields the same points.
+fn reports(result: &IPerf3) -> Result<Vec<Report>, String> {
+    if let Some(ref error) = result.error {
+        return Err(format!("failed test: {error}"));
+    }
+
+    if result.intervals.is_empty() {
+        return Err("no intervals".to_string());
+    }
+
+    let started = time::OffsetDateTime::from_unix_timestamp(result.start.timestamp.timesecs)
+        .ok()
+        .filter(|started| started.unix_timestamp() > 0)
+        .ok_or("no start.timestamp")?;
+
+    let test = &result.start.test_start;
+    let forward = match test.reverse {
+        true => Direction::Download,
+        false => Direction::Upload,
+    };
+
+    let tags = [("server", result.start.connecting_to.host.as_str())];
+    let mut reports = vec![Builder::result(forward, started, &tags, result)];
+
+    // --bidir runs the other direction at the same time, it only has intervals.
+    if test.bidir {
+        let mut builder = Builder::new(Direction::Download, started, &tags);
+        builder.event(&Event::Start(Box::new(result.start.clone())));
+
+        for interval in result.intervals.iter() {
+            if let Some(ref sum) = interval.sum_bidir_reverse {
+                builder.event(&Event::Interval(Interval {
+                    sum: sum.clone(),
+                    ..Default::default()
+                }));
+            }
+        }
+
+        reports.push(builder.build(None));
+    }
+
+    Ok(reports)
+}
+
+async fn read(path: &Path) -> Result<Vec<Report>, String> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|err| err.to_string())?;
+    let result = serde_json::from_str::<IPerf3>(&content)
+        .map_err(|err| format!("not iperf3 JSON: {err}"))?;
+
+    reports(&result)
+}
+
+/// Writes the archived iperf3 `-J` results of `paths` to the sinks of
+/// `speedy run`: InfluxDB through `client`, the collector and the history. The
+/// archive is left alone, the files are archived results already.
+pub async fn import(
+    paths: &[PathBuf],
+    client: Option<&Client>,
+    collector: Option<&Sink>,
+    history: Option<&History>,
+    tags: &BTreeMap<String, String>,
+) -> Result<Summary, Error> {
+    let mut summary = Summary::default();
+
+    for path in files(paths).await? {
+        let source = path.display().to_string();
+
+        let reports = match read(&path).await {
+            Ok(reports) => reports,
+            Err(reason) => {
+                summary.skipped.push(format!("{source}: {reason}"));
+                continue;
+            }
+        };
+
+        for report in reports.iter() {
+            summary.points += report.speeds().count();
+
+            let Some(client) = client else {
+                continue;
+            };
+
+            let tags = report
+                .tags
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
+
+            client
+                .insert_multiple(report.speeds(), &tags)
+                .await
+                .map_err(|err| Error::InfluxDB(source.clone(), err))?;
+            client
+                .insert_outcome(report.outcome(), &tags)
+                .await
+                .map_err(|err| Error::InfluxDB(source.clone(), err))?;
+        }
+
+        // Spooled batches are posted with the next file or run, like results of a run.
+        if let Some(sink) = collector {
+            if let Err(err) = sink.submit(&reports, tags).await {
+                eprintln!("Failed to submit results of {source}: {err}");
+            }
+        }
+
+        if let Some(history) = history {
+            history.push(reports).await?;
+        }
+
+        summary.files += 1;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{files, import, reports};
+    use crate::collector::{self, Collector, Sink};
+    use crate::influxdb::Direction;
+    use crate::models::IPerf3;
+
+    fn fixture(name: &str) -> IPerf3 {
+        let path = format!("{}/fixtures/iperf3/{name}", env!("CARGO_MANIFEST_DIR"));
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn points(name: &str) -> Vec<(String, i64)> {
+        reports(&fixture(name))
+            .unwrap()
+            .iter()
+            .flat_map(|report| {
+                report
+                    .intervals
+                    .iter()
+                    .map(|interval| {
+                        (
+                            report.direction.to_string(),
+                            (report.time + time::Duration::seconds_f64(interval.end))
+                                .unix_timestamp(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_direction_server_and_time_come_from_the_result() {
+        let download = reports(&fixture("3.7-linux-tcp-download.json")).unwrap();
+        let upload = reports(&fixture("3.12-macos-tcp-upload.json")).unwrap();
+
+        assert_eq!(1, download.len());
+        assert!(matches!(download[0].direction, Direction::Download));
+        assert!(matches!(upload[0].direction, Direction::Upload));
+
+        let result = fixture("3.7-linux-tcp-download.json");
+        assert_eq!(
+            result.start.timestamp.timesecs,
+            download[0].time.unix_timestamp()
+        );
+        assert_eq!(
+            vec![(
+                "server".to_string(),
+                result.start.connecting_to.host.clone()
+            )],
+            download[0].tags
+        );
+    }
+
+    #[test]
+    fn test_points_are_keyed_by_the_test_start() {
+        let started = fixture("3.17-linux-tcp-multistream.json")
+            .start
+            .timestamp
+            .timesecs;
+        let points = points("3.17-linux-tcp-multistream.json");
+
+        assert_eq!(
+            vec![
+                ("up".to_string(), started + 1),
+                ("up".to_string(), started + 2)
+            ],
+            points
+        );
+    }
+
+    #[test]
+    fn test_bidir_imports_both_directions() {
+        let reports = reports(&fixture("3.16-linux-tcp-bidir.json")).unwrap();
+
+        assert_eq!(2, reports.len());
+        assert!(matches!(reports[0].direction, Direction::Upload));
+        assert!(matches!(reports[1].direction, Direction::Download));
+        assert_eq!(reports[0].intervals.len(), reports[1].intervals.len());
+    }
+
+    #[test]
+    fn test_failed_tests_are_skipped() {
+        let err = reports(&fixture("3.17-server-busy.json")).unwrap_err();
+
+        assert!(err.contains("server is busy"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_directories_expand_to_json_files() {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let files = files(&[fixtures.join("iperf3")]).await.unwrap();
+
+        assert_eq!(7, files.len());
+        assert!(files.iter().all(|file| file.extension().unwrap() == "json"));
+        assert!(files.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[tokio::test]
+    async fn test_collector_only_configs_post_to_the_collector() {
+        let dir = TempDir::new().unwrap();
+        let database = dir.path().join("results.db");
+        let (addr, server) = collector::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Collector {
+                agents: BTreeMap::from([("zurich".to_string(), "secret".to_string())]),
+                sqlite: Some(database.clone()),
+                ..Default::default()
+            },
+            None,
+            BTreeMap::new(),
+            CancellationToken::new(),
+        )
+        .unwrap();
+        tokio::spawn(server);
+
+        let sink = Sink {
+            url: format!("http://{addr}"),
+            agent: "zurich".to_string(),
+            token: "secret".to_string(),
+            spool: dir.path().join("spool"),
+        };
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/iperf3");
+        let paths = [
+            fixtures.join("3.7-linux-tcp-download.json"),
+            fixtures.join("3.16-linux-tcp-bidir.json"),
+        ];
+
+        let summary = import(&paths, None, Some(&sink), None, &BTreeMap::new())
+            .await
+            .unwrap();
+
+        assert_eq!(2, summary.files);
+        let rows = rusqlite::Connection::open(database)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM results", (), |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap();
+        assert_eq!(3, rows);
+    }
+}
@@ -0,0 +1,547 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use time::OffsetDateTime;
+
+use crate::influxdb::{self, Direction, Outcome, Speed};
+use crate::iperf3::{self, Event};
+use crate::models;
+
+/// How `speedy run` prints its results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Live progress and a summary table
+    #[default]
+    Table,
+    /// One JSON array with every tested direction
+    Json,
+    /// One row per tested direction
+    Csv,
+    /// InfluxDB line protocol, second precision, one point per interval
+    InfluxLine,
+}
+
+/// Normalized result of one direction of a test.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    /// `host:port` that ran the test, empty when no server was reached.
+    pub server: String,
+    #[serde(serialize_with = "direction", deserialize_with = "parse_direction")]
+    pub direction: Direction,
+    /// Received bitrate of the whole test.
+    pub bits_per_second: Option<f64>,
+    pub rtt_ms: Option<f64>,
+    pub retransmits: Option<i64>,
+    pub intervals: Vec<Interval>,
+    pub error: Option<String>,
+    /// Tags the points are written with besides the configured ones.
+    #[serde(skip)]
+    pub tags: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interval {
+    pub start: f64,
+    pub end: f64,
+    pub bits_per_second: f64,
+    pub rtt_ms: Option<f64>,
+    pub retransmits: Option<i64>,
+}
+
+fn direction<S: Serializer>(direction: &Direction, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&direction.to_string())
+}
+
+fn parse_direction<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Direction, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "down" => Ok(Direction::Download),
+        "up" => Ok(Direction::Upload),
+        other => Err(serde::de::Error::unknown_variant(other, &["down", "up"])),
+    }
+}
+
+impl Report {
+    /// Points of the intervals, as the InfluxDB sink writes them. Points have
+    /// second precision, intervals ending in the same second (`-i 0.5`) are
+    /// merged into one point of their mean bitrate instead of overwriting it.
+    pub fn speeds(&self) -> impl Iterator<Item = Speed> + '_ {
+        // End, bits sent and seconds of the intervals merged so far.
+        let mut merged: Vec<(OffsetDateTime, f64, f64)> = Vec::new();
+
+        for interval in self.intervals.iter() {
+            let end = self.time + time::Duration::seconds_f64(interval.end);
+            let seconds = (interval.end - interval.start).max(f64::EPSILON);
+            let bits = interval.bits_per_second * seconds;
+
+            match merged.last_mut() {
+                Some(last) if last.0.unix_timestamp() == end.unix_timestamp() => {
+                    *last = (end, last.1 + bits, last.2 + seconds)
+                }
+                _ => merged.push((end, bits, seconds)),
+            }
+        }
+
+        merged.into_iter().map(|(end, bits, seconds)| {
+            Speed::new(end, self.direction.clone(), (bits / seconds) as u64)
+        })
+    }
+
+    /// Point of the test as a whole, as the InfluxDB sink writes it.
+    pub fn outcome(&self) -> Outcome {
+        Outcome::new(
+            self.time,
+            self.direction.clone(),
+            self.rtt_ms,
+            self.error.is_some(),
+        )
+    }
+
+    fn peak(&self) -> Option<f64> {
+        self.intervals
+            .iter()
+            .map(|interval| interval.bits_per_second)
+            .reduce(f64::max)
+    }
+}
+
+/// Builds the [`Report`] of one direction from the events of its test.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    report: Report,
+}
+
+impl Builder {
+    pub fn new(direction: Direction, time: OffsetDateTime, tags: &[(&str, &str)]) -> Self {
+        Self {
+            report: Report {
+                time,
+                server: String::new(),
+                direction,
+                bits_per_second: None,
+                rtt_ms: None,
+                retransmits: None,
+                intervals: Vec::new(),
+                error: None,
+                tags: tags
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            },
+        }
+    }
+
+    /// Report of a finished iperf3 `result`.
+    pub fn result(
+        direction: Direction,
+        time: OffsetDateTime,
+        tags: &[(&str, &str)],
+        result: &models::IPerf3,
+    ) -> Report {
+        let mut builder = Self::new(direction, time, tags);
+        iperf3::events(result).for_each(|event| builder.event(&event));
+        builder.build(None)
+    }
+
+    /// The report as built so far.
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
+    pub fn event(&mut self, event: &Event) {
+        match event {
+            // A retry starts over, possibly on another server.
+            Event::Start(start) => {
+                let host = &start.connecting_to.host;
+
+                // Servers turning the test away start it without a host.
+                if !host.is_empty() {
+                    self.server(host, start.connecting_to.port);
+                }
+
+                self.report.intervals.clear();
+            }
+            Event::Interval(interval) => self.interval(interval),
+            Event::End(end) => self.end(end),
+        }
+    }
+
+    fn interval(&mut self, interval: &models::Interval) {
+        let rtts = interval
+            .streams
+            .iter()
+            .filter_map(|stream| stream.rtt)
+            .collect::<Vec<_>>();
+
+        self.report.intervals.push(Interval {
+            start: interval.sum.start,
+            end: interval.sum.end,
+            bits_per_second: interval.sum.bits_per_second,
+            rtt_ms: mean_ms(&rtts),
+            retransmits: interval.sum.retransmits,
+        });
+    }
+
+    /// Prefers the totals iperf3 computed over the ones of the intervals.
+    fn end(&mut self, end: &models::End) {
+        let received = match end.sum {
+            Some(ref sum) => sum.bits_per_second,
+            None => end.sum_received.bits_per_second,
+        };
+
+        if received > 0.0 {
+            self.report.bits_per_second = Some(received);
+        }
+
+        self.report.rtt_ms = mean_rtt_ms(end);
+        self.report.retransmits = end.sum_sent.retransmits;
+    }
+
+    /// Keeps the server a failed direction was tried against when no Start
+    /// event named one.
+    pub fn attempted(&mut self, host: &str, port: u16) {
+        if self.report.server.is_empty() {
+            self.server(host, port);
+        }
+    }
+
+    fn server(&mut self, host: &str, port: impl std::fmt::Display) {
+        self.report.server = format!("{host}:{port}");
+        self.report.tags.retain(|(key, _)| key != "server");
+        self.report
+            .tags
+            .push(("server".to_string(), host.to_string()));
+    }
+
+    /// Fills the totals iperf3 did not send from the intervals, `error` is why
+    /// the direction failed.
+    pub fn build(mut self, error: Option<String>) -> Report {
+        let intervals = &self.report.intervals;
+
+        if self.report.bits_per_second.is_none() && !intervals.is_empty() {
+            self.report.bits_per_second = Some(
+                intervals
+                    .iter()
+                    .map(|interval| interval.bits_per_second)
+                    .sum::<f64>()
+                    / intervals.len() as f64,
+            );
+        }
+
+        if self.report.rtt_ms.is_none() {
+            self.report.rtt_ms = intervals.iter().rev().find_map(|interval| interval.rtt_ms);
+        }
+
+        if self.report.retransmits.is_none() {
+            self.report.retransmits = retransmits(intervals);
+        }
+
+        self.report.error = error;
+        self.report
+    }
+}
+
+pub fn retransmits(intervals: &[Interval]) -> Option<i64> {
+    intervals
+        .iter()
+        .filter_map(|interval| interval.retransmits)
+        .reduce(|sum, retransmits| sum + retransmits)
+}
+
+/// Mean RTT the senders of a test measured.
+pub fn mean_rtt_ms(end: &models::End) -> Option<f64> {
+    let rtts = end
+        .streams
+        .iter()
+        .filter_map(|stream| stream.sender.mean_rtt)
+        .collect::<Vec<_>>();
+
+    mean_ms(&rtts)
+}
+
+/// Mean of iperf3 round trip times, which are in microseconds.
+fn mean_ms(rtts: &[i64]) -> Option<f64> {
+    match rtts.is_empty() {
+        true => None,
+        false => Some(rtts.iter().sum::<i64>() as f64 / rtts.len() as f64 / 1000.0),
+    }
+}
+
+/// Renders `reports`, `measurement` and `tags` are only used by line protocol.
+pub fn render(
+    reports: &[Report],
+    format: Format,
+    measurement: &str,
+    tags: &[(&str, &str)],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let output = match format {
+        Format::Table => table(reports),
+        Format::Json => serde_json::to_string_pretty(reports)? + "\n",
+        Format::Csv => csv(reports),
+        Format::InfluxLine => {
+            let mut lines = String::new();
+
+            for report in reports {
+                let tags = tags
+                    .iter()
+                    .copied()
+                    .chain(report.tags.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+                    .collect::<Vec<_>>();
+
+                let points =
+                    influxdb::lines(measurement, &tags, report.speeds(), report.outcome())?;
+
+                for line in points {
+                    lines += &line;
+                    lines.push('\n');
+                }
+            }
+
+            lines
+        }
+    };
+
+    Ok(output)
+}
+
+fn table(reports: &[Report]) -> String {
+    let mut table = format!(
+        "{:<9} {:<36} {:>9} {:>13} {:>13} {:>9} {:>7}  ERROR\n",
+        "DIRECTION", "SERVER", "INTERVALS", "BITRATE", "PEAK", "RTT", "RETR"
+    );
+
+    for report in reports {
+        table += &format!(
+            "{:<9} {:<36} {:>9} {:>13} {:>13} {:>9} {:>7}  {}\n",
+            report.direction.to_string(),
+            or_dash(Some(&report.server).filter(|server| !server.is_empty())),
+            report.intervals.len(),
+            or_dash(report.bits_per_second.map(bitrate)),
+            or_dash(report.peak().map(bitrate)),
+            or_dash(report.rtt_ms.map(|rtt| format!("{rtt:.1}ms"))),
+            or_dash(report.retransmits),
+            or_dash(report.error.as_ref()),
+        );
+    }
+
+    table
+}
+
+fn csv(reports: &[Report]) -> String {
+    let mut csv =
+        "time,server,direction,bits_per_second,rtt_ms,retransmits,intervals,error\n".to_string();
+
+    for report in reports {
+        let time = report
+            .time
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+
+        csv += &[
+            time,
+            escape(&report.server),
+            report.direction.to_string(),
+            or_empty(report.bits_per_second),
+            or_empty(report.rtt_ms),
+            or_empty(report.retransmits),
+            report.intervals.len().to_string(),
+            escape(report.error.as_deref().unwrap_or_default()),
+        ]
+        .join(",");
+        csv.push('\n');
+    }
+
+    csv
+}
+
+fn escape(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+fn or_empty(value: Option<impl ToString>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn or_dash(value: Option<impl ToString>) -> String {
+    value
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+pub fn bitrate(bits_per_second: f64) -> String {
+    match bits_per_second {
+        speed if speed >= 1e9 => format!("{:.2} Gbit/s", speed / 1e9),
+        speed if speed >= 1e6 => format!("{:.1} Mbit/s", speed / 1e6),
+        speed => format!("{:.0} Kbit/s", speed / 1e3),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::{bitrate, Builder, Format, Interval, Report};
+    use crate::influxdb::Direction;
+    use crate::iperf3::Event;
+    use crate::models::IPerf3;
+
+    fn reports() -> Vec<Report> {
+        let interval = |start: f64, bits_per_second: f64| Interval {
+            start,
+            end: start + 1.0,
+            bits_per_second,
+            rtt_ms: Some(11.2),
+            retransmits: Some(2),
+        };
+
+        vec![
+            Report {
+                time: datetime!(2024-06-04 16:12:48 UTC),
+                server: "speedtest.init7.net:5201".to_string(),
+                direction: Direction::Download,
+                bits_per_second: Some(935_248_451.2),
+                rtt_ms: Some(11.2),
+                retransmits: Some(4),
+                intervals: vec![interval(0.0, 900e6), interval(1.0, 950e6)],
+                error: None,
+                tags: vec![("server".to_string(), "speedtest.init7.net".to_string())],
+            },
+            Report {
+                time: datetime!(2024-06-04 16:12:58 UTC),
+                server: String::new(),
+                direction: Direction::Upload,
+                bits_per_second: None,
+                rtt_ms: None,
+                retransmits: None,
+                intervals: Vec::new(),
+                error: Some("unable to connect, \"refused\"".to_string()),
+                tags: Vec::new(),
+            },
+        ]
+    }
+
+    fn render(format: Format) -> String {
+        super::render(&reports(), format, "network_speeds", &[("host", "probe")]).unwrap()
+    }
+
+    #[test]
+    fn test_json() {
+        let json: serde_json::Value = serde_json::from_str(&render(Format::Json)).unwrap();
+
+        assert_eq!("2024-06-04T16:12:48Z", json[0]["time"]);
+        assert_eq!("down", json[0]["direction"]);
+        assert_eq!(2, json[0]["intervals"].as_array().unwrap().len());
+        assert!(json[0].get("tags").is_none());
+        assert_eq!("up", json[1]["direction"]);
+        assert!(json[1]["bits_per_second"].is_null());
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = render(Format::Csv);
+        let lines = csv.lines().collect::<Vec<_>>();
+
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            "2024-06-04T16:12:48Z,speedtest.init7.net:5201,down,935248451.2,11.2,4,2,",
+            lines[1]
+        );
+        assert_eq!(
+            r#"2024-06-04T16:12:58Z,,up,,,,0,"unable to connect, ""refused""""#,
+            lines[2]
+        );
+    }
+
+    #[test]
+    fn test_influx_line_has_one_point_per_interval_and_test() {
+        let lines = render(Format::InfluxLine);
+
+        assert_eq!(
+            "network_speeds,direction=down,host=probe,server=speedtest.init7.net speed=900000000i 1717517569\n\
+             network_speeds,direction=down,host=probe,server=speedtest.init7.net speed=950000000i 1717517570\n\
+             network_speeds,direction=down,host=probe,server=speedtest.init7.net failed=0i,rtt_ms=11.2 1717517568\n\
+             network_speeds,direction=up,host=probe failed=1i 1717517578\n",
+            lines
+        );
+    }
+
+    #[test]
+    fn test_sub_second_intervals_share_a_point() {
+        let mut report = reports().remove(0);
+        report.intervals = [(0.0, 0.5, 100e6), (0.5, 1.0, 200e6), (1.0, 1.25, 300e6)]
+            .into_iter()
+            .map(|(start, end, bits_per_second)| Interval {
+                start,
+                end,
+                bits_per_second,
+                rtt_ms: None,
+                retransmits: None,
+            })
+            .collect();
+        report.bits_per_second = None;
+        report.rtt_ms = None;
+
+        let lines = super::render(&[report], Format::InfluxLine, "m", &[]).unwrap();
+
+        assert_eq!(
+            "m,direction=down,server=speedtest.init7.net speed=100000000i 1717517568\n\
+             m,direction=down,server=speedtest.init7.net speed=233333333i 1717517569\n\
+             m,direction=down,server=speedtest.init7.net failed=0i 1717517568\n",
+            lines
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let table = render(Format::Table);
+
+        assert!(table.lines().next().unwrap().starts_with("DIRECTION"));
+        assert!(table.contains("950.0 Mbit/s"));
+        assert!(table.contains("unable to connect"));
+    }
+
+    #[test]
+    fn test_builder_falls_back_to_the_intervals() {
+        let result: IPerf3 = serde_json::from_str(include_str!(
+            "../fixtures/iperf3/3.17-linux-tcp-multistream.json"
+        ))
+        .unwrap();
+        let mut builder = Builder::new(
+            Direction::Upload,
+            datetime!(2024-06-04 16:12:48 UTC),
+            &[("server", "speedtest.init7.net")],
+        );
+
+        builder.event(&Event::Start(Box::new(result.start.clone())));
+        for interval in result.intervals.iter() {
+            builder.event(&Event::Interval(interval.clone()));
+        }
+        let report = builder.build(Some("interrupted".to_string()));
+
+        let mean = result
+            .intervals
+            .iter()
+            .map(|interval| interval.sum.bits_per_second)
+            .sum::<f64>()
+            / 2.0;
+        assert_eq!("speedtest.init7.net:5201", report.server);
+        assert_eq!(Some(mean), report.bits_per_second);
+        assert_eq!(Some("interrupted"), report.error.as_deref());
+        assert_eq!(1, report.tags.len());
+
+        let finished = Builder::result(Direction::Upload, report.time, &[], &result);
+        assert_eq!(
+            Some(result.end.sum_received.bits_per_second),
+            finished.bits_per_second
+        );
+    }
+
+    #[test]
+    fn test_bitrate() {
+        assert_eq!("935.2 Mbit/s", bitrate(935_248_451.2));
+        assert_eq!("1.20 Gbit/s", bitrate(1.2e9));
+        assert_eq!("512 Kbit/s", bitrate(512_000.0));
+    }
+}
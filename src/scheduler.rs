@@ -0,0 +1,592 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::timetable::Table;
+
+/// First back-off after a crashed job, doubled on every consecutive crash.
+const BACKOFF_BASE: Duration = Duration::from_secs(30);
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Time given to cancelled jobs to clean up before their tasks are aborted.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Name of the machine, used to spread runs of agents started together.
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// Deterministic offset inside `interval` derived from `host`.
+///
+/// FNV-1a is used instead of `DefaultHasher` because its output must not
+/// change between Rust releases, otherwise agents would shift after upgrades.
+pub fn phase_offset(host: &str, interval: Duration) -> Duration {
+    let nanos = interval.as_nanos() as u64;
+
+    if nanos == 0 {
+        return Duration::ZERO;
+    }
+
+    let hash = host.bytes().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+
+    Duration::from_nanos(hash % nanos)
+}
+
+/// Delay until the next run: `interval` shifted by a uniform random
+/// amount in `[-jitter, +jitter]`.
+pub fn next_delay(interval: Duration, jitter: Duration, rng: &mut impl Rng) -> Duration {
+    let jitter = jitter.min(interval).as_nanos() as i128;
+
+    if jitter == 0 {
+        return interval;
+    }
+
+    let nanos = interval.as_nanos() as i128 + rng.gen_range(-jitter..=jitter);
+
+    Duration::from_nanos(nanos as u64)
+}
+
+#[inline]
+pub fn to_std(duration: time::Duration) -> Duration {
+    Duration::from_nanos(duration.whole_nanoseconds().max(0) as u64)
+}
+
+/// Outcome counters of a single rule, exposed through the status endpoint.
+#[derive(Debug, Clone, Default, serde_derive::Serialize)]
+pub struct Health {
+    pub rule: String,
+    pub runs: u64,
+    pub failures: u64,
+    pub crashes: u64,
+    pub consecutive_failures: u64,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_run: Option<time::OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_success: Option<time::OffsetDateTime>,
+    pub last_error: Option<String>,
+    /// When the rule tests next, `None` if that falls outside its hours.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_run: Option<time::OffsetDateTime>,
+}
+
+/// Health of every scheduled rule, shared between rule tasks and readers.
+#[derive(Debug, Clone, Default)]
+pub struct Board {
+    rules: Arc<Mutex<BTreeMap<String, Health>>>,
+    /// Rules skip their runs while set, runs already started finish.
+    paused: Arc<AtomicBool>,
+}
+
+impl Board {
+    pub fn snapshot(&self) -> Vec<Health> {
+        self.rules.lock().unwrap().values().cloned().collect()
+    }
+
+    #[inline]
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    fn insert(&self, rule: &Table) {
+        let rule = rule.to_string();
+        self.rules.lock().unwrap().insert(
+            rule.clone(),
+            Health {
+                rule,
+                ..Default::default()
+            },
+        );
+    }
+
+    fn remove(&self, rule: &Table) {
+        self.rules.lock().unwrap().remove(&rule.to_string());
+    }
+
+    fn update(&self, rule: &str, f: impl FnOnce(&mut Health)) {
+        if let Some(health) = self.rules.lock().unwrap().get_mut(rule) {
+            f(health);
+        }
+    }
+}
+
+fn backoff(crashes: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(2_u32.saturating_pow(crashes.saturating_sub(1)))
+        .min(BACKOFF_MAX)
+}
+
+/// Aborts the run of a job when dropped, so aborting the task of a rule aborts
+/// the job it is running too.
+struct Running(AbortHandle);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs `job` every `rule.duration` (± `rule.jitter`) while the current UTC
+/// hour is inside the rule's range, starting after `offset`.
+///
+/// Every run is spawned as its own task, so a panicking job does not stop
+/// the rule, the next run is only delayed by an increasing back-off.
+/// Cancelling `token` stops the loop between runs, a job that is already
+/// running is allowed to finish. Runs due while `board` is paused are skipped.
+pub async fn every<F, Fut, E>(
+    rule: Table,
+    offset: Duration,
+    token: CancellationToken,
+    board: Board,
+    mut job: F,
+) where
+    F: FnMut(&Table) -> Fut,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: Display + Send + 'static,
+{
+    let name = rule.to_string();
+    let interval = to_std(rule.duration);
+    let jitter = rule.jitter.map(to_std).unwrap_or_default();
+    let mut rng = StdRng::from_entropy();
+    let mut delay = offset;
+    let mut crashes = 0;
+
+    loop {
+        let next = time::OffsetDateTime::now_utc() + delay;
+        board.update(&name, |health| {
+            health.next_run = rule.contains(next.hour()).then_some(next);
+        });
+
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        delay = next_delay(interval, jitter, &mut rng);
+
+        let now = time::OffsetDateTime::now_utc();
+
+        if !rule.contains(now.hour()) || board.paused() {
+            continue;
+        }
+
+        board.update(&name, |health| {
+            health.runs += 1;
+            health.last_run = Some(now);
+        });
+
+        let mut run = tokio::spawn(job(&rule));
+        let _running = Running(run.abort_handle());
+
+        match (&mut run).await {
+            Ok(Ok(())) => {
+                crashes = 0;
+                board.update(&name, |health| {
+                    health.consecutive_failures = 0;
+                    health.last_success = Some(time::OffsetDateTime::now_utc());
+                });
+            }
+            Ok(Err(err)) => {
+                eprintln!("Rule ({name}) failed: {err}");
+                board.update(&name, |health| {
+                    health.failures += 1;
+                    health.consecutive_failures += 1;
+                    health.last_error = Some(err.to_string());
+                });
+            }
+            Err(err) if err.is_panic() => {
+                crashes += 1;
+                delay += backoff(crashes);
+                eprintln!("Rule ({name}) crashed, next run in {}s", delay.as_secs());
+                board.update(&name, |health| {
+                    health.crashes += 1;
+                    health.consecutive_failures += 1;
+                    health.last_error = Some("job panicked".to_string());
+                });
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Rules removed from and added to the schedule by [`Scheduler::apply`].
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub removed: Vec<Table>,
+    pub added: Vec<Table>,
+}
+
+impl Changes {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+}
+
+impl Display for Changes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for rule in &self.removed {
+            writeln!(f, "- {rule}")?;
+        }
+
+        for rule in &self.added {
+            writeln!(f, "+ {rule}")?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Task {
+    rule: Table,
+    token: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+/// Owns one task per timetable rule and swaps them when the timetable changes.
+pub struct Scheduler<F> {
+    host: String,
+    job: F,
+    tasks: Vec<Task>,
+    board: Board,
+}
+
+impl<F> Scheduler<F> {
+    pub fn board(&self) -> Board {
+        self.board.clone()
+    }
+
+    /// Stops scheduling new runs and waits up to `deadline` for running jobs.
+    ///
+    /// Jobs still running after `deadline` are cancelled through `cancel` and
+    /// aborted if they ignore it, in that case `false` is returned.
+    pub async fn shutdown(&mut self, deadline: Duration, cancel: &CancellationToken) -> bool {
+        let mut tasks = std::mem::take(&mut self.tasks);
+        tasks.iter().for_each(|task| task.token.cancel());
+
+        if tokio::time::timeout(deadline, join(&mut tasks))
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+
+        cancel.cancel();
+
+        if tokio::time::timeout(SHUTDOWN_GRACE, join(&mut tasks))
+            .await
+            .is_err()
+        {
+            tasks.iter().for_each(|task| task.handle.abort());
+        }
+
+        false
+    }
+}
+
+async fn join(tasks: &mut [Task]) {
+    for task in tasks.iter_mut() {
+        if !task.handle.is_finished() {
+            _ = (&mut task.handle).await;
+        }
+    }
+}
+
+impl<F, Fut, E> Scheduler<F>
+where
+    F: FnMut(&Table) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: Display + Send + 'static,
+{
+    pub fn new(host: String, job: F) -> Self {
+        Self {
+            host,
+            job,
+            tasks: Vec::new(),
+            board: Board::default(),
+        }
+    }
+
+    /// Replaces the running schedule with `table`.
+    ///
+    /// Rules present in both the old and the new table keep running untouched,
+    /// so their phase and in-flight runs are preserved.
+    pub fn apply(&mut self, table: Vec<Table>) -> Changes {
+        let mut changes = Changes::default();
+        let (kept, removed): (Vec<_>, Vec<_>) = self
+            .tasks
+            .drain(..)
+            .partition(|task| table.contains(&task.rule));
+
+        for task in removed {
+            task.token.cancel();
+            self.board.remove(&task.rule);
+            changes.removed.push(task.rule);
+        }
+
+        self.tasks = kept;
+
+        for rule in table {
+            if self.tasks.iter().any(|task| task.rule == rule) {
+                continue;
+            }
+
+            let token = CancellationToken::new();
+            let offset = phase_offset(&self.host, to_std(rule.duration));
+            self.board.insert(&rule);
+            let handle = tokio::spawn(every(
+                rule.clone(),
+                offset,
+                token.clone(),
+                self.board.clone(),
+                self.job.clone(),
+            ));
+
+            changes.added.push(rule.clone());
+            self.tasks.push(Task {
+                rule,
+                token,
+                handle,
+            });
+        }
+
+        self.tasks.retain(|task| !task.handle.is_finished());
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::time::Duration;
+
+    use tokio_util::sync::CancellationToken;
+
+    use super::{backoff, next_delay, phase_offset, Scheduler};
+    use crate::timetable::parse;
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let interval = Duration::from_secs(15 * 60);
+        let jitter = Duration::from_secs(3 * 60);
+        let samples = 10_000;
+
+        let delays = (0..samples)
+            .map(|_| next_delay(interval, jitter, &mut rng))
+            .collect::<Vec<_>>();
+
+        assert!(delays
+            .iter()
+            .all(|delay| *delay >= interval - jitter && *delay <= interval + jitter));
+
+        let mean = delays.iter().map(Duration::as_secs_f64).sum::<f64>() / samples as f64;
+        assert!((mean - interval.as_secs_f64()).abs() < 5.0);
+
+        // Both halves of the window have to be used, not only one side.
+        let early = delays.iter().filter(|delay| **delay < interval).count();
+        assert!(early > samples * 4 / 10 && early < samples * 6 / 10);
+    }
+
+    #[test]
+    fn test_no_jitter() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let interval = Duration::from_secs(60);
+
+        assert_eq!(interval, next_delay(interval, Duration::ZERO, &mut rng));
+    }
+
+    #[test]
+    fn test_phase_offset_is_deterministic_and_bounded() {
+        let interval = Duration::from_secs(10 * 60);
+
+        assert_eq!(
+            phase_offset("agent-01", interval),
+            phase_offset("agent-01", interval)
+        );
+        assert_eq!(Duration::ZERO, phase_offset("agent-01", Duration::ZERO));
+
+        let offsets = (0..200)
+            .map(|i| phase_offset(&format!("agent-{i:02}"), interval))
+            .collect::<Vec<_>>();
+
+        assert!(offsets.iter().all(|offset| *offset < interval));
+
+        // Hosts have to be spread over the whole interval, not bunched together.
+        let buckets = offsets.iter().fold([0; 10], |mut buckets, offset| {
+            buckets[(offset.as_secs() / 60) as usize] += 1;
+            buckets
+        });
+        assert!(buckets.iter().all(|count| *count > 0));
+    }
+
+    #[tokio::test]
+    async fn test_apply_keeps_unchanged_rules() {
+        let mut scheduler =
+            Scheduler::new("agent".to_string(), |_: &_| async { Ok::<_, String>(()) });
+
+        let (_, table) = parse("0-3 10m\n3-8 5m\n").unwrap();
+        let changes = scheduler.apply(table);
+        assert_eq!(2, changes.added.len());
+        assert!(changes.removed.is_empty());
+
+        let (_, table) = parse("0-3 10m\n3-8 15m ±3m\n").unwrap();
+        let changes = scheduler.apply(table);
+        assert_eq!("- 3-8 5m\n+ 3-8 15m ±3m\n", changes.to_string());
+
+        let (_, table) = parse("0-3 10m\n3-8 15m ±3m\n").unwrap();
+        assert!(scheduler.apply(table).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_waits_for_running_job() {
+        let mut scheduler = Scheduler::new("agent".to_string(), |_: &_| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, String>(())
+        });
+
+        scheduler.apply(parse("0-0 1m\n").unwrap().1);
+        tokio::time::sleep(Duration::from_secs(61)).await;
+
+        let cancel = CancellationToken::new();
+        assert!(scheduler.shutdown(Duration::from_secs(30), &cancel).await);
+        assert!(!cancel.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_cancels_job_after_deadline() {
+        let cancel = CancellationToken::new();
+        let job_cancel = cancel.clone();
+        let mut scheduler = Scheduler::new("agent".to_string(), move |_: &_| {
+            let cancel = job_cancel.clone();
+            async move {
+                tokio::select! {
+                    _ = cancel.cancelled() => {}
+                    _ = tokio::time::sleep(Duration::from_secs(3600)) => {}
+                }
+                Ok::<_, String>(())
+            }
+        });
+
+        scheduler.apply(parse("0-0 1m\n").unwrap().1);
+        tokio::time::sleep(Duration::from_secs(61)).await;
+
+        assert!(!scheduler.shutdown(Duration::from_secs(30), &cancel).await);
+        assert!(cancel.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_aborts_jobs_ignoring_the_cancel() {
+        /// Dropped with the job once its task is aborted.
+        struct Dropped(Arc<AtomicBool>);
+
+        impl Drop for Dropped {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let aborted = Arc::new(AtomicBool::new(false));
+        let flag = aborted.clone();
+        let mut scheduler = Scheduler::new("agent".to_string(), move |_: &_| {
+            let dropped = Dropped(flag.clone());
+            async move {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+                drop(dropped);
+                Ok::<_, String>(())
+            }
+        });
+
+        scheduler.apply(parse("0-0 1m\n").unwrap().1);
+        tokio::time::sleep(Duration::from_secs(61)).await;
+
+        assert!(
+            !scheduler
+                .shutdown(Duration::from_secs(30), &CancellationToken::new())
+                .await
+        );
+        tokio::task::yield_now().await;
+
+        assert!(aborted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failing_and_crashing_jobs_keep_the_rule_alive() {
+        let runs = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = runs.clone();
+        let mut scheduler = Scheduler::new("agent".to_string(), move |_: &_| {
+            let run = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                match run {
+                    0 => Err("influxdb unreachable".to_string()),
+                    1 => panic!("job crashed"),
+                    _ => Ok(()),
+                }
+            }
+        });
+
+        scheduler.apply(parse("0-0 1m\n").unwrap().1);
+        tokio::time::sleep(Duration::from_secs(60 * 10)).await;
+
+        let health = &scheduler.board().snapshot()[0];
+        assert!(runs.load(std::sync::atomic::Ordering::SeqCst) > 3);
+        assert_eq!(1, health.failures);
+        assert_eq!(1, health.crashes);
+        assert_eq!(0, health.consecutive_failures);
+        assert!(health.last_success.is_some());
+        assert_eq!(Some("job panicked"), health.last_error.as_deref());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paused_rules_skip_their_runs() {
+        let runs = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = runs.clone();
+        let mut scheduler = Scheduler::new("agent".to_string(), move |_: &_| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { Ok::<_, String>(()) }
+        });
+        let board = scheduler.board();
+
+        board.set_paused(true);
+        scheduler.apply(parse("0-0 1m\n").unwrap().1);
+        tokio::time::sleep(Duration::from_secs(60 * 5)).await;
+
+        assert_eq!(0, runs.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(0, board.snapshot()[0].runs);
+
+        board.set_paused(false);
+        tokio::time::sleep(Duration::from_secs(60 * 5)).await;
+
+        assert!(runs.load(std::sync::atomic::Ordering::SeqCst) > 3);
+    }
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        assert_eq!(Duration::from_secs(30), backoff(1));
+        assert_eq!(Duration::from_secs(60), backoff(2));
+        assert_eq!(Duration::from_secs(120), backoff(3));
+        assert_eq!(Duration::from_secs(30 * 60), backoff(40));
+    }
+}
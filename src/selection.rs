@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::distributions::WeightedError;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use tokio::task::JoinSet;
+
+use crate::iperf3::Protocol;
+use crate::server::{IpVersion, Server};
+
+/// How long a measured latency is trusted before the host is probed again.
+const LATENCY_TTL: Duration = Duration::from_secs(15 * 60);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Order in which the hosts of a server group are tried.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Random, hosts with a higher weight come first more often.
+    #[default]
+    Weighted,
+    /// Lowest TCP connect latency first, unreachable hosts last.
+    Nearest,
+    /// Each run starts with the host after the one the previous run started with.
+    RoundRobin,
+    /// Always in the order the group lists them.
+    Pinned,
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Strategy::Weighted => f.write_str("weighted"),
+            Strategy::Nearest => f.write_str("nearest"),
+            Strategy::RoundRobin => f.write_str("round-robin"),
+            Strategy::Pinned => f.write_str("pinned"),
+        }
+    }
+}
+
+/// Entries sharing a host, a public host runs one iperf3 per port and is
+/// treated as a single logical server.
+#[derive(Debug)]
+pub struct Host<'a> {
+    pub name: &'a str,
+    pub weight: u32,
+    pub ports: Vec<(&'a Server, u16)>,
+}
+
+/// Groups `servers` supporting `protocol` by host, in the order they are listed.
+pub fn hosts(servers: &[Server], protocol: Protocol) -> Vec<Host<'_>> {
+    let mut hosts: Vec<Host> = Vec::new();
+
+    for server in servers.iter().filter(|server| server.supports(protocol)) {
+        let ports = server.ports.iter().map(|port| (server, port));
+
+        match hosts.iter_mut().find(|host| host.name == server.host) {
+            Some(host) => {
+                host.weight = host.weight.saturating_add(server.weight);
+                host.ports.extend(ports);
+            }
+            None => hosts.push(Host {
+                name: &server.host,
+                weight: server.weight,
+                ports: ports.collect(),
+            }),
+        }
+    }
+
+    hosts
+}
+
+/// State the strategies keep between runs, shared by every rule of a daemon.
+#[derive(Debug, Default)]
+pub struct Selector {
+    /// Connect latency per host, `None` when the host did not answer.
+    latencies: Mutex<HashMap<String, (Instant, Option<Duration>)>>,
+    /// Runs started so far per group, keyed by the hosts of the group.
+    turns: Mutex<HashMap<String, usize>>,
+}
+
+impl Selector {
+    /// Probes the hosts of `servers` that were never measured or whose
+    /// latency is older than [`LATENCY_TTL`].
+    pub async fn measure(&self, servers: &[Server], protocol: Protocol) {
+        let stale = {
+            let latencies = self.latencies.lock().unwrap();
+
+            hosts(servers, protocol)
+                .into_iter()
+                .filter(|host| match latencies.get(host.name) {
+                    Some((measured, _)) => measured.elapsed() > LATENCY_TTL,
+                    None => true,
+                })
+                .map(|host| {
+                    let (server, port) = host.ports[0];
+                    (server.host.clone(), port, server.ip)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        if stale.is_empty() {
+            return;
+        }
+
+        let mut probes = JoinSet::new();
+
+        for (host, port, ip) in stale {
+            probes.spawn(async move {
+                let latency = probe(&host, port, ip).await;
+                (host, latency)
+            });
+        }
+
+        while let Some(result) = probes.join_next().await {
+            let Ok((host, latency)) = result else {
+                continue;
+            };
+
+            match latency {
+                Some(latency) => eprintln!("Latency to {host}: {}ms", latency.as_millis()),
+                None => eprintln!("Latency to {host}: unreachable"),
+            }
+
+            self.latencies
+                .lock()
+                .unwrap()
+                .insert(host, (Instant::now(), latency));
+        }
+    }
+
+    /// Sorts `hosts` into the order `strategy` tries them in, hosts with a
+    /// weight of 0 are never tried.
+    pub fn order<'a>(
+        &self,
+        strategy: Strategy,
+        mut hosts: Vec<Host<'a>>,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<Host<'a>>, WeightedError> {
+        if !hosts.is_empty() && hosts.iter().all(|host| host.weight == 0) {
+            return Err(WeightedError::AllWeightsZero);
+        }
+
+        hosts.retain(|host| host.weight > 0);
+
+        match strategy {
+            Strategy::Weighted => {
+                let mut ordered = Vec::with_capacity(hosts.len());
+
+                while !hosts.is_empty() {
+                    let index = (0..hosts.len())
+                        .collect::<Vec<_>>()
+                        .choose_weighted(rng, |index| hosts[*index].weight)
+                        .copied()?;
+
+                    ordered.push(hosts.remove(index));
+                }
+
+                hosts = ordered;
+            }
+            Strategy::Nearest => {
+                let latencies = self.latencies.lock().unwrap();
+
+                hosts.sort_by_key(|host| {
+                    latencies
+                        .get(host.name)
+                        .and_then(|(_, latency)| *latency)
+                        .unwrap_or(Duration::MAX)
+                });
+            }
+            Strategy::RoundRobin => {
+                let key = hosts
+                    .iter()
+                    .map(|host| host.name)
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                let mut turns = self.turns.lock().unwrap();
+                let turn = turns.entry(key).or_default();
+
+                if !hosts.is_empty() {
+                    let len = hosts.len();
+                    hosts.rotate_left(*turn % len);
+                }
+
+                *turn = turn.wrapping_add(1);
+            }
+            Strategy::Pinned => {}
+        }
+
+        Ok(hosts)
+    }
+}
+
+/// Time to open a TCP connection to `host`, name resolution not included.
+pub async fn probe(host: &str, port: u16, ip: IpVersion) -> Option<Duration> {
+    let addr = tokio::time::timeout(PROBE_TIMEOUT, tokio::net::lookup_host((host, port)))
+        .await
+        .ok()?
+        .ok()?
+        .find(|addr| match ip {
+            IpVersion::Any => true,
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6(),
+        })?;
+
+    let started = Instant::now();
+
+    match tokio::time::timeout(PROBE_TIMEOUT, tokio::net::TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Some(started.elapsed()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{hosts, Selector, Strategy};
+    use crate::iperf3::Protocol;
+    use crate::server::{IpVersion, Server};
+
+    fn servers(list: &[&str]) -> Vec<Server> {
+        list.iter().map(|server| server.parse().unwrap()).collect()
+    }
+
+    fn order(selector: &Selector, strategy: Strategy, servers: &[Server]) -> Vec<String> {
+        selector
+            .order(
+                strategy,
+                hosts(servers, Protocol::Tcp),
+                &mut StdRng::seed_from_u64(7),
+            )
+            .unwrap()
+            .iter()
+            .map(|host| host.name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_hosts_merge_entries_of_one_host() {
+        let servers = servers(&[
+            "a.example:5201-5204:5",
+            "b.example:5201:5",
+            "a.example:5209:1",
+        ]);
+        let hosts = hosts(&servers, Protocol::Tcp);
+
+        assert_eq!(2, hosts.len());
+        assert_eq!(
+            ("a.example", 6, 5),
+            (hosts[0].name, hosts[0].weight, hosts[0].ports.len())
+        );
+        assert_eq!(
+            ("b.example", 5, 1),
+            (hosts[1].name, hosts[1].weight, hosts[1].ports.len())
+        );
+    }
+
+    #[test]
+    fn test_pinned_keeps_the_listed_order() {
+        let servers = servers(&["c.example", "a.example", "b.example:5201:0"]);
+
+        assert_eq!(
+            vec!["c.example", "a.example"],
+            order(&Selector::default(), Strategy::Pinned, &servers)
+        );
+    }
+
+    #[test]
+    fn test_round_robin_rotates_the_first_host() {
+        let servers = servers(&["a.example", "b.example", "c.example"]);
+        let selector = Selector::default();
+
+        let firsts = (0..4)
+            .map(|_| order(&selector, Strategy::RoundRobin, &servers).remove(0))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec!["a.example", "b.example", "c.example", "a.example"],
+            firsts
+        );
+        assert_eq!(
+            vec!["b.example", "c.example", "a.example"],
+            order(&selector, Strategy::RoundRobin, &servers)
+        );
+    }
+
+    #[test]
+    fn test_nearest_prefers_low_latency_and_reachable_hosts() {
+        let servers = servers(&["far.example", "down.example", "near.example", "new.example"]);
+        let selector = Selector::default();
+
+        {
+            let mut latencies = selector.latencies.lock().unwrap();
+            let now = Instant::now();
+            latencies.insert("far.example".into(), (now, Some(Duration::from_millis(80))));
+            latencies.insert("down.example".into(), (now, None));
+            latencies.insert("near.example".into(), (now, Some(Duration::from_millis(9))));
+        }
+
+        assert_eq!(
+            vec!["near.example", "far.example", "down.example", "new.example"],
+            order(&selector, Strategy::Nearest, &servers)
+        );
+    }
+
+    #[test]
+    fn test_weighted_tries_every_host_once() {
+        let servers = servers(&["a.example:5201:1", "b.example:5201:100", "c.example:5201:0"]);
+        let selector = Selector::default();
+
+        let mut first_b = 0;
+
+        for seed in 0..100 {
+            let order = selector
+                .order(
+                    Strategy::Weighted,
+                    hosts(&servers, Protocol::Tcp),
+                    &mut StdRng::seed_from_u64(seed),
+                )
+                .unwrap();
+
+            assert_eq!(2, order.len());
+            first_b += usize::from(order[0].name == "b.example");
+        }
+
+        assert!(first_b > 90, "{first_b}");
+        assert!(selector
+            .order(
+                Strategy::Weighted,
+                hosts(&servers[2..], Protocol::Tcp),
+                &mut StdRng::seed_from_u64(1),
+            )
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_measure_marks_closed_ports_unreachable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap().port();
+
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+
+        let mut servers = vec![
+            format!("127.0.0.1:{closed}:1").parse::<Server>().unwrap(),
+            format!("localhost:{open}:1").parse::<Server>().unwrap(),
+        ];
+        servers[1].ip = IpVersion::V4;
+        let selector = Selector::default();
+
+        selector.measure(&servers, Protocol::Tcp).await;
+
+        let latencies = selector.latencies.lock().unwrap();
+        assert_eq!(None, latencies["127.0.0.1"].1);
+        assert!(latencies["localhost"].1.is_some());
+        drop(latencies);
+
+        assert_eq!(
+            vec!["localhost", "127.0.0.1"],
+            order(&selector, Strategy::Nearest, &servers)
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::api::Trigger;
+use crate::cli::{Status, Test};
+use crate::config::{self, Config, Overrides};
+use crate::history::History;
+use crate::influxdb::Client;
+use crate::mesh::{Flight, Mesh};
+use crate::progress::{Live, Progress};
+use crate::scheduler::{self, Scheduler};
+use crate::selection::Selector;
+use crate::server::{Groups, DEFAULT_GROUP};
+use crate::timetable::{Mode, Params, Table};
+use crate::{api, cli, http, iperf3d};
+
+/// How often watched files are checked for modifications.
+const RELOAD_POLL: Duration = Duration::from_secs(5);
+
+pub struct Options {
+    pub config: Option<PathBuf>,
+    pub overrides: Overrides,
+    pub shutdown_timeout: Duration,
+    pub status_addr: Option<SocketAddr>,
+}
+
+/// Notices edits of a file by comparing its modification time and size.
+struct Watched {
+    path: PathBuf,
+    stamp: Option<(SystemTime, u64)>,
+}
+
+impl Watched {
+    async fn new(path: PathBuf) -> Self {
+        let mut watched = Self { path, stamp: None };
+        watched.changed().await;
+        watched
+    }
+
+    async fn changed(&mut self) -> bool {
+        let stamp = tokio::fs::metadata(&self.path)
+            .await
+            .ok()
+            .and_then(|meta| Some((meta.modified().ok()?, meta.len())));
+
+        let changed = stamp != self.stamp;
+        self.stamp = stamp;
+        changed
+    }
+}
+
+/// Configuration currently used by scheduled runs, swapped as a whole on reload.
+struct State {
+    config: Config,
+    /// `None` when results only go to a collector without InfluxDB credentials.
+    client: Option<Client>,
+}
+
+/// Everything a reload produces, built completely before anything is swapped.
+struct Loaded {
+    state: State,
+    table: Vec<Table>,
+    watched: Vec<PathBuf>,
+}
+
+/// Server group used by `rule`.
+fn group(rule: &Table) -> &str {
+    rule.params.servers.as_deref().unwrap_or(DEFAULT_GROUP)
+}
+
+/// Checks that every group referenced by `rules` exists in `groups` and that
+/// mesh rules have peers to test.
+fn check_groups<'a>(
+    mut rules: impl Iterator<Item = &'a Table>,
+    groups: &Groups,
+    mesh: &Mesh,
+) -> Result<(), String> {
+    match rules.find(|rule| match rule.params.mode {
+        Some(Mode::Mesh) => mesh.peers.is_empty(),
+        _ => !groups.contains_key(group(rule)),
+    }) {
+        Some(rule) if rule.params.mode == Some(Mode::Mesh) => {
+            Err(format!("Rule ({rule}) has no [mesh.peers] to test"))
+        }
+        Some(rule) => Err(format!(
+            "Server group ({}) used by rule ({rule}) does not exist",
+            group(rule)
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Loads the configuration, its schedule and the files to watch for changes.
+async fn load(options: &Options) -> Result<Loaded, Box<dyn std::error::Error>> {
+    let config = config::load(options.config.as_deref(), &options.overrides).await?;
+    let table = config.schedule().await?;
+    check_groups(table.iter(), &config.servers, &config.mesh)?;
+
+    let watched = options
+        .config
+        .iter()
+        .cloned()
+        .chain(config.timetable_path())
+        .chain(options.overrides.servers_file.iter().cloned())
+        .collect();
+
+    Ok(Loaded {
+        state: State {
+            client: match config.sinks.collector {
+                Some(_) => config.influx_client().ok(),
+                None => Some(config.influx_client()?),
+            },
+            config,
+        },
+        table,
+        watched,
+    })
+}
+
+/// Settings of a run with `params`, its reports are gathered by the progress.
+fn test(
+    state: &State,
+    params: &Params,
+    selector: &Arc<Selector>,
+    cancel: &CancellationToken,
+) -> (Test, Arc<Progress>) {
+    let defaults = &state.config.defaults;
+    let progress = Arc::new(Progress::new(Live::Quiet));
+    let mut test = Test::new(
+        defaults.timeout,
+        defaults.retries,
+        params,
+        Arc::clone(selector),
+        cancel.clone(),
+    );
+    test.archive = state.config.sinks.archive.clone();
+    test.endpoints = state.config.engines.http.clone();
+    test.mesh = state.config.mesh.clone();
+    test.tags = state.config.tags.clone();
+    test.collector = state.config.sinks.collector.clone();
+    test.progress = Some(Arc::clone(&progress));
+
+    (test, progress)
+}
+
+async fn watch_all(paths: Vec<PathBuf>) -> Vec<Watched> {
+    let mut watched = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        watched.push(Watched::new(path).await);
+    }
+
+    watched
+}
+
+pub async fn serve(options: Options) -> Result<Status, Box<dyn std::error::Error>> {
+    let Loaded {
+        state,
+        table,
+        watched,
+    } = load(&options).await?;
+
+    let (state_tx, state_rx) = watch::channel(Arc::new(state));
+    let cancel = CancellationToken::new();
+    let job_cancel = cancel.clone();
+    let selector = Arc::new(Selector::default());
+    let flight = Flight::default();
+    let job_flight = flight.clone();
+    let trigger_selector = Arc::clone(&selector);
+    let trigger_cancel = cancel.clone();
+
+    let history = match state_rx.borrow().config.sinks.history {
+        Some(ref store) => History::open(store).await?,
+        None => History::default(),
+    };
+    let job_history = history.clone();
+
+    let host = scheduler::hostname();
+    let mut scheduler = Scheduler::new(host.clone(), move |rule: &Table| {
+        let state = Arc::clone(&state_rx.borrow());
+        let (mut test, progress) = test(&state, &rule.params, &selector, &job_cancel);
+        test.flight = Some(job_flight.clone());
+        let history = job_history.clone();
+        let name = group(rule).to_string();
+
+        async move {
+            let result = match state.config.servers.get(&name) {
+                Some(servers) => cli::run(servers, state.client.as_ref(), &test)
+                    .await
+                    .map_err(|err| err.to_string()),
+                None => Err(format!("Server group ({name}) does not exist")),
+            };
+
+            if let Err(err) = history.push(progress.reports()).await {
+                eprintln!("{err}");
+            }
+
+            result
+        }
+    });
+
+    scheduler.apply(table);
+
+    let stopped = CancellationToken::new();
+
+    let listen = state_tx.borrow().config.mesh.listen;
+    let api = state_tx.borrow().config.api.clone();
+
+    if let Some(addr) = listen {
+        let server = iperf3d::Server::bind(addr, flight.clone()).await?;
+        println!("Serving mesh peers on {}", server.local_addr()?);
+
+        tokio::spawn(server.run(stopped.clone()));
+    }
+
+    if let Some(ref api) = api {
+        let current = state_tx.subscribe();
+        let kept = history.clone();
+
+        // Runs as a single run rule would, the API holds the flight meanwhile.
+        let trigger = move |trigger: Trigger| {
+            let state = Arc::clone(&current.borrow());
+            let params = Params {
+                direction: trigger.direction,
+                servers: trigger.group,
+                ..Default::default()
+            };
+            let name = params.servers.as_deref().unwrap_or(DEFAULT_GROUP);
+            let servers = match trigger.servers {
+                Some(servers) if servers.is_empty() => return Err("No servers given".to_string()),
+                Some(servers) => servers,
+                None => match state.config.servers.get(name) {
+                    Some(servers) => servers.clone(),
+                    None => return Err(format!("Server group ({name}) does not exist")),
+                },
+            };
+            let (test, progress) = test(&state, &params, &trigger_selector, &trigger_cancel);
+            let history = kept.clone();
+
+            Ok((test.run.clone(), async move {
+                let result = cli::run(&servers, state.client.as_ref(), &test)
+                    .await
+                    .map_err(|err| err.to_string());
+
+                if let Err(err) = history.push(progress.reports()).await {
+                    eprintln!("{err}");
+                }
+
+                result
+            }))
+        };
+
+        let (addr, server) = api::bind(
+            api,
+            scheduler.board(),
+            history.clone(),
+            flight.clone(),
+            trigger,
+            stopped.clone(),
+        )?;
+        println!("Serving the remote control API on http://{addr}/api/v1");
+
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                eprintln!("Remote control API failed: {err}");
+            }
+        });
+    }
+
+    if let Some(addr) = options.status_addr {
+        let token = api.as_ref().map(|api| api.token.clone());
+        let (addr, server) = http::status(
+            addr,
+            host,
+            scheduler.board(),
+            history,
+            token,
+            stopped.clone(),
+        )?;
+        println!("Serving rule status on http://{addr}/status and the dashboard on http://{addr}/");
+
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                eprintln!("Status endpoint failed: {err}");
+            }
+        });
+    }
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut watched = watch_all(watched).await;
+
+    let received = loop {
+        tokio::select! {
+            _ = terminate.recv() => break "SIGTERM",
+            _ = interrupt.recv() => break "SIGINT",
+            _ = hangup.recv() => {
+                println!("Received SIGHUP, reloading configuration");
+            }
+            _ = tokio::time::sleep(RELOAD_POLL) => {
+                let mut changed = false;
+
+                for file in watched.iter_mut() {
+                    changed |= file.changed().await;
+                }
+
+                if !changed {
+                    continue;
+                }
+            }
+        }
+
+        match load(&options).await {
+            Ok(loaded) => {
+                watched = watch_all(loaded.watched).await;
+                reload(&mut scheduler, &state_tx, loaded.state, loaded.table);
+            }
+            Err(err) => eprintln!("Keeping the current configuration: {err}"),
+        }
+    };
+
+    println!(
+        "Received {received}, waiting up to {}s for running tests",
+        options.shutdown_timeout.as_secs()
+    );
+
+    let finished = scheduler.shutdown(options.shutdown_timeout, &cancel).await;
+    stopped.cancel();
+
+    match finished {
+        true => Ok(Status::Stopped),
+        false => Ok(Status::Cancelled),
+    }
+}
+
+/// Swaps the running schedule and configuration, logging what changed.
+fn reload<F, Fut, E>(
+    scheduler: &mut Scheduler<F>,
+    current: &watch::Sender<Arc<State>>,
+    state: State,
+    table: Vec<Table>,
+) where
+    F: FnMut(&Table) -> Fut + Clone + Send + 'static,
+    Fut: std::future::Future<Output = Result<(), E>> + Send + 'static,
+    E: std::fmt::Display + Send + 'static,
+{
+    let old = current.send_replace(Arc::new(state));
+    let new = current.borrow();
+    let changes = scheduler.apply(table);
+
+    if changes.is_empty() {
+        println!("Configuration reloaded, no rules changed");
+    } else {
+        print!("Configuration reloaded:\n{changes}");
+    }
+
+    let (old, new) = (&old.config.servers, &new.config.servers);
+
+    for (name, list) in old.iter() {
+        let current = new.get(name).map(Vec::as_slice).unwrap_or_default();
+        list.iter()
+            .filter(|server| !current.contains(server))
+            .for_each(|server| println!("- [{name}] {server}"));
+    }
+
+    for (name, list) in new.iter() {
+        let previous = old.get(name).map(Vec::as_slice).unwrap_or_default();
+        list.iter()
+            .filter(|server| !previous.contains(server))
+            .for_each(|server| println!("+ [{name}] {server}"));
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::iperf3::Protocol;
+
+/// Port of entries without one, iperf3's own. Before typed servers a bare host
+/// was tested on 5001.
+pub const DEFAULT_PORT: u16 = 5201;
+pub const DEFAULT_GROUP: &str = "default";
+
+/// Named server lists, rules without `servers=` use [`DEFAULT_GROUP`].
+pub type Groups = BTreeMap<String, Vec<Server>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error(
+        "invalid server ({0}): {1}, expected \"<host>[:<port>[-<port>][:<weight>]]\", the port defaults to {port}",
+        port = DEFAULT_PORT
+    )]
+    Invalid(String, &'static str),
+
+    #[error("invalid bitrate ({0}), expected a number with an optional K, M or G suffix")]
+    Bitrate(String),
+
+    #[error("server group ({0}) does not contain any server")]
+    EmptyGroup(String),
+
+    #[error("failed to read server list: {0}")]
+    IO(String),
+
+    #[error("unknown region ({0}), available regions: {1}")]
+    Region(String, String),
+}
+
+/// Inclusive range of ports a host runs iperf3 servers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Ports {
+    #[inline]
+    pub fn single(port: u16) -> Self {
+        Self {
+            start: port,
+            end: port,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Self::single(DEFAULT_PORT)
+    }
+}
+
+impl Display for Ports {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.start == self.end {
+            true => write!(f, "{}", self.start),
+            false => write!(f, "{}-{}", self.start, self.end),
+        }
+    }
+}
+
+impl FromStr for Ports {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let port = |val: &str| match val.parse::<u16>() {
+            Ok(0) | Err(_) => Err("port has to be a number between 1 and 65535"),
+            Ok(port) => Ok(port),
+        };
+
+        let ports = match s.split_once('-') {
+            Some((start, end)) => Self {
+                start: port(start)?,
+                end: port(end)?,
+            },
+            None => Self::single(port(s)?),
+        };
+
+        match ports.start <= ports.end {
+            true => Ok(ports),
+            false => Err("port range has to be ascending"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+/// Parses a bitrate such as `100M` into bits per second.
+pub fn parse_bitrate(value: &str) -> Result<u64, Error> {
+    let (number, multiplier) = match value.chars().last() {
+        Some('K') => (&value[..value.len() - 1], 1_000),
+        Some('M') => (&value[..value.len() - 1], 1_000_000),
+        Some('G') => (&value[..value.len() - 1], 1_000_000_000),
+        _ => (value, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| Error::Bitrate(value.to_string()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Server {
+    pub host: String,
+    pub ports: Ports,
+    pub weight: u32,
+    pub region: Option<String>,
+    pub tags: Vec<String>,
+    /// Protocols the server accepts, empty means all of them.
+    pub protocols: Vec<Protocol>,
+    /// Highest bitrate the server may be tested with, in bits per second.
+    pub max_bitrate: Option<u64>,
+    pub ip: IpVersion,
+}
+
+impl Server {
+    pub fn new(host: impl Into<String>, ports: Ports, weight: u32) -> Self {
+        Self {
+            host: host.into(),
+            ports,
+            weight,
+            region: None,
+            tags: Vec::new(),
+            protocols: Vec::new(),
+            max_bitrate: None,
+            ip: IpVersion::Any,
+        }
+    }
+
+    #[inline]
+    pub fn supports(&self, protocol: Protocol) -> bool {
+        self.protocols.is_empty() || self.protocols.contains(&protocol)
+    }
+
+    /// Whether the server can be written in the legacy string form.
+    fn is_plain(&self) -> bool {
+        self.region.is_none()
+            && self.tags.is_empty()
+            && self.protocols.is_empty()
+            && self.max_bitrate.is_none()
+            && self.ip == IpVersion::Any
+    }
+}
+
+impl Display for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}:{}", self.host, self.ports, self.weight),
+            false => write!(f, "{}:{}:{}", self.host, self.ports, self.weight),
+        }
+    }
+}
+
+/// Parses the legacy `host[:port[-port][:weight]]` form, IPv6 literals have
+/// to be wrapped in brackets when a port is given.
+impl FromStr for Server {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| Error::Invalid(s.to_string(), reason);
+        let value = s.trim();
+
+        let (host, rest) = match value.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| invalid("missing closing bracket"))?;
+
+                match rest {
+                    "" => (host, None),
+                    _ => (
+                        host,
+                        Some(
+                            rest.strip_prefix(':')
+                                .ok_or_else(|| invalid("expected ':' after the address"))?,
+                        ),
+                    ),
+                }
+            }
+            // A bare IPv6 address cannot carry a port, it is used as the whole host.
+            None if value.parse::<std::net::Ipv6Addr>().is_ok() => (value, None),
+            None => match value.split_once(':') {
+                Some((host, rest)) => (host, Some(rest)),
+                None => (value, None),
+            },
+        };
+
+        if host.is_empty() || host.contains(char::is_whitespace) {
+            return Err(invalid("host is empty or contains whitespace"));
+        }
+
+        let mut fields = rest.map(|rest| rest.split(':')).into_iter().flatten();
+        let ports = match fields.next() {
+            Some(ports) => ports.parse::<Ports>().map_err(invalid)?,
+            None => Ports::default(),
+        };
+        let weight = match fields.next() {
+            Some(weight) => weight
+                .parse::<u32>()
+                .map_err(|_| invalid("weight has to be a non-negative number"))?,
+            None => 1,
+        };
+
+        if fields.next().is_some() {
+            return Err(invalid("too many fields"));
+        }
+
+        Ok(Self::new(host, ports, weight))
+    }
+}
+
+/// Table form of a server in the configuration file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Definition {
+    host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ports: Option<PortsValue>,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    region: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    protocols: Vec<Protocol>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bitrate: Option<BitrateValue>,
+    #[serde(default)]
+    ip: IpVersion,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum PortsValue {
+    Single(u16),
+    Range(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum BitrateValue {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ServerValue {
+    Legacy(String),
+    Definition(Definition),
+}
+
+impl TryFrom<Definition> for Server {
+    type Error = Error;
+
+    fn try_from(value: Definition) -> Result<Self, Self::Error> {
+        let invalid = |reason| Error::Invalid(value.host.clone(), reason);
+
+        if value.host.is_empty() || value.host.contains(char::is_whitespace) {
+            return Err(invalid("host is empty or contains whitespace"));
+        }
+
+        let ports = match value.ports {
+            Some(PortsValue::Single(port)) => format!("{port}").parse().map_err(invalid)?,
+            Some(PortsValue::Range(ref range)) => range.parse().map_err(invalid)?,
+            None => Ports::default(),
+        };
+        let max_bitrate = match value.max_bitrate {
+            Some(BitrateValue::Number(bitrate)) => Some(bitrate),
+            Some(BitrateValue::Text(ref bitrate)) => Some(parse_bitrate(bitrate)?),
+            None => None,
+        };
+
+        Ok(Self {
+            host: value.host,
+            ports,
+            weight: value.weight,
+            region: value.region,
+            tags: value.tags,
+            protocols: value.protocols,
+            max_bitrate,
+            ip: value.ip,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Server {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match ServerValue::deserialize(deserializer)? {
+            ServerValue::Legacy(value) => value.parse().map_err(D::Error::custom),
+            ServerValue::Definition(value) => Server::try_from(value).map_err(D::Error::custom),
+        }
+    }
+}
+
+impl Serialize for Server {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.is_plain() {
+            return serializer.serialize_str(&self.to_string());
+        }
+
+        Definition {
+            host: self.host.clone(),
+            ports: Some(PortsValue::Range(self.ports.to_string())),
+            weight: self.weight,
+            region: self.region.clone(),
+            tags: self.tags.clone(),
+            protocols: self.protocols.clone(),
+            max_bitrate: self.max_bitrate.map(BitrateValue::Number),
+            ip: self.ip,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Reads a server list file, one server per line, `#` starts a comment.
+///
+/// A `[name]` line starts a named group, servers listed before the first
+/// group belong to [`DEFAULT_GROUP`]. Files ending in `.toml` hold the groups
+/// in the same form as the `[servers]` table of the configuration.
+pub async fn read_server_list(path: &Path) -> Result<Groups, Error> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|err| Error::IO(format!("{}: {err}", path.display())))?;
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => check_groups(
+            toml::from_str(&content)
+                .map_err(|err| Error::IO(format!("{}: {err}", path.display())))?,
+        ),
+        _ => parse_server_list(&content),
+    }
+}
+
+fn check_groups(groups: Groups) -> Result<Groups, Error> {
+    match groups.iter().find(|(_, servers)| servers.is_empty()) {
+        Some((name, _)) => Err(Error::EmptyGroup(name.clone())),
+        None if groups.is_empty() => Err(Error::EmptyGroup(DEFAULT_GROUP.to_string())),
+        None => Ok(groups),
+    }
+}
+
+pub fn parse_server_list(content: &str) -> Result<Groups, Error> {
+    let mut groups = Groups::new();
+    let mut group = DEFAULT_GROUP.to_string();
+
+    for line in content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+    {
+        match line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            Some(name) if !name.contains(':') => {
+                group = name.trim().to_string();
+                groups.insert(group.clone(), Vec::new());
+            }
+            _ => groups.entry(group.clone()).or_default().push(line.parse()?),
+        }
+    }
+
+    check_groups(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bitrate, parse_server_list, IpVersion, Ports, Server, DEFAULT_GROUP};
+    use crate::iperf3::Protocol;
+
+    fn parse(value: &str) -> Server {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_host_only() {
+        let server = parse("speedtest.init7.net");
+
+        assert_eq!("speedtest.init7.net", server.host);
+        assert_eq!(Ports::single(5201), server.ports);
+        assert_eq!(1, server.weight);
+    }
+
+    #[test]
+    fn test_parse_host_and_port_keeps_the_port() {
+        let server = parse("iperf.online.net:5209");
+
+        assert_eq!(Ports::single(5209), server.ports);
+        assert_eq!(1, server.weight);
+    }
+
+    #[test]
+    fn test_parse_host_port_weight() {
+        let server = parse("ams.speedtest.clouvider.net:5204:10");
+
+        assert_eq!("ams.speedtest.clouvider.net", server.host);
+        assert_eq!(Ports::single(5204), server.ports);
+        assert_eq!(10, server.weight);
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        let server = parse("ams.speedtest.clouvider.net:5201-5209:10");
+
+        assert_eq!(
+            Ports {
+                start: 5201,
+                end: 5209
+            },
+            server.ports
+        );
+        assert_eq!((5201, 5209), (server.ports.start, server.ports.end));
+    }
+
+    #[test]
+    fn test_parse_ipv6() {
+        let server = parse("[2001:db8::1]:5202:3");
+        assert_eq!("2001:db8::1", server.host);
+        assert_eq!(Ports::single(5202), server.ports);
+        assert_eq!(3, server.weight);
+
+        let server = parse("[::1]");
+        assert_eq!("::1", server.host);
+        assert_eq!(Ports::single(5201), server.ports);
+
+        let server = parse("2001:db8::1");
+        assert_eq!("2001:db8::1", server.host);
+        assert_eq!(Ports::single(5201), server.ports);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for value in [
+            "",
+            ":5201",
+            "host:abc",
+            "host:0",
+            "host:70000",
+            "host:5209-5201",
+            "host:5201:heavy",
+            "host:5201:-1",
+            "host:5201:1:extra",
+            "[2001:db8::1",
+            "[2001:db8::1]5201",
+            "two words:5201",
+        ] {
+            assert!(value.parse::<Server>().is_err(), "{value} should not parse");
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for value in [
+            "speedtest.init7.net:5201:10",
+            "ams.speedtest.clouvider.net:5201-5209:3",
+            "[2001:db8::1]:5202:3",
+        ] {
+            assert_eq!(value, parse(value).to_string());
+            assert_eq!(parse(value), parse(&parse(value).to_string()));
+        }
+    }
+
+    #[test]
+    fn test_parse_bitrate() {
+        assert_eq!(Ok(100), parse_bitrate("100"));
+        assert_eq!(Ok(100_000_000), parse_bitrate("100M"));
+        assert_eq!(Ok(10_000_000_000), parse_bitrate("10G"));
+        assert!(parse_bitrate("fast").is_err());
+        assert!(parse_bitrate("M").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_legacy_and_table_forms() {
+        #[derive(serde_derive::Deserialize)]
+        struct Servers {
+            servers: Vec<Server>,
+        }
+
+        let Servers { servers } = toml::from_str(
+            r#"
+            servers = [
+                "iperf.online.net:5209:7",
+                { host = "ams.speedtest.clouvider.net", ports = "5201-5209", weight = 10, region = "nl", tags = ["clouvider"], protocols = ["tcp"], max_bitrate = "10G", ip = "v4" },
+                { host = "::1", ports = 5201 },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(parse("iperf.online.net:5209:7"), servers[0]);
+
+        let server = &servers[1];
+        assert_eq!(
+            Ports {
+                start: 5201,
+                end: 5209
+            },
+            server.ports
+        );
+        assert_eq!(10, server.weight);
+        assert_eq!(Some("nl"), server.region.as_deref());
+        assert_eq!(vec!["clouvider"], server.tags);
+        assert!(server.supports(Protocol::Tcp));
+        assert!(!server.supports(Protocol::Udp));
+        assert_eq!(Some(10_000_000_000), server.max_bitrate);
+        assert_eq!(IpVersion::V4, server.ip);
+
+        assert_eq!(parse("[::1]:5201:1"), servers[2]);
+        assert!(servers[2].supports(Protocol::Udp));
+    }
+
+    #[test]
+    fn test_deserialize_rejects_invalid_entries() {
+        #[derive(Debug, serde_derive::Deserialize)]
+        struct Servers {
+            #[allow(dead_code)]
+            servers: Vec<Server>,
+        }
+
+        for value in [
+            r#"servers = ["host:5201:heavy"]"#,
+            r#"servers = [{ host = "host", ports = "5209-5201" }]"#,
+            r#"servers = [{ host = "host", max_bitrate = "fast" }]"#,
+            r#"servers = [{ host = "host", speed = 1 }]"#,
+        ] {
+            assert!(toml::from_str::<Servers>(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        #[derive(Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+        struct Servers {
+            servers: Vec<Server>,
+        }
+
+        let mut detailed = parse("ams.speedtest.clouvider.net:5201-5209:10");
+        detailed.region = Some("nl".to_string());
+        detailed.ip = IpVersion::V6;
+
+        let servers = Servers {
+            servers: vec![parse("iperf.online.net:5209:7"), detailed],
+        };
+        let content = toml::to_string(&servers).unwrap();
+
+        assert!(content.contains("\"iperf.online.net:5209:7\""));
+        assert_eq!(servers, toml::from_str(&content).unwrap());
+    }
+
+    #[test]
+    fn test_parse_server_list_groups() {
+        let groups = parse_server_list(
+            "speedtest.init7.net:5201:10\n\
+             # comment\n\
+             [eu-core]\n\
+             iperf.online.net:5209:7 # trailing comment\n\
+             [2001:db8::1]:5201:1\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![parse("speedtest.init7.net:5201:10")],
+            groups[DEFAULT_GROUP]
+        );
+        assert_eq!(
+            vec![
+                parse("iperf.online.net:5209:7"),
+                parse("[2001:db8::1]:5201:1")
+            ],
+            groups["eu-core"]
+        );
+    }
+
+    #[test]
+    fn test_parse_server_list_rejects_empty_group() {
+        assert!(parse_server_list("[eu-core]\n[us]\nhost:5201:1\n").is_err());
+        assert!(parse_server_list("# nothing here\n").is_err());
+        assert!(parse_server_list("host:5201:heavy\n").is_err());
+    }
+}
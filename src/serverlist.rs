@@ -0,0 +1,457 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::server::{Groups, IpVersion, Ports, Server};
+
+/// Group of entries without a continent when no `--group` is given.
+const OTHER_GROUP: &str = "other";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to access server list ({0}): {1}")]
+    IO(String, std::io::Error),
+
+    #[error("Invalid JSON server list: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Invalid CSV server list: {0}")]
+    Csv(String),
+
+    #[error("Cannot tell the format of {0}, pass --format json or --format csv")]
+    UnknownFormat(String),
+
+    #[error("Server list does not contain any usable server")]
+    Empty,
+
+    #[error("Failed to write servers: {0}")]
+    Toml(#[from] toml::ser::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    fn detect(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// One entry of the published list, keyed by the normalized column name.
+type Record = BTreeMap<String, String>;
+
+/// `IP/HOST` and `ip host` both become `iphost`.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn json_records(content: &str) -> Result<Vec<Record>, Error> {
+    let items = match serde_json::from_str(content)? {
+        serde_json::Value::Array(items) => items,
+        // Some exports wrap the list, e.g. `{"servers": [...]}`.
+        serde_json::Value::Object(object) => object
+            .into_iter()
+            .find_map(|(_, value)| match value {
+                serde_json::Value::Array(items) => Some(items),
+                _ => None,
+            })
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+
+    Ok(items
+        .into_iter()
+        .filter_map(|item| match item {
+            serde_json::Value::Object(object) => Some(
+                object
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = match value {
+                            serde_json::Value::String(value) => value,
+                            serde_json::Value::Null => String::new(),
+                            value => value.to_string(),
+                        };
+
+                        (normalize(&key), value)
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Splits CSV into rows of fields, quoted fields may contain commas,
+/// newlines and doubled quotes.
+fn csv_rows(content: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, '\r') => {}
+            (false, c) => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(Error::Csv("unterminated quoted field".to_string()));
+    }
+
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    rows.retain(|row| row.iter().any(|field| !field.trim().is_empty()));
+
+    Ok(rows)
+}
+
+fn csv_records(content: &str) -> Result<Vec<Record>, Error> {
+    let mut rows = csv_rows(content)?.into_iter();
+    let header = rows
+        .next()
+        .ok_or_else(|| Error::Csv("missing header row".to_string()))?
+        .iter()
+        .map(|name| normalize(name))
+        .collect::<Vec<_>>();
+
+    Ok(rows
+        .map(|row| header.iter().cloned().zip(row).collect())
+        .collect())
+}
+
+/// First non-empty value among the column `names`.
+fn field<'a>(record: &'a Record, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .filter_map(|name| record.get(*name))
+        .map(|value| value.trim())
+        .find(|value| !value.is_empty())
+}
+
+/// Value of `-p`/`--port` in the iperf3 options a list suggests.
+fn port_option(options: &str) -> Option<&str> {
+    let mut tokens = options.split_whitespace();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "-p" | "--port" => return tokens.next(),
+            _ => {
+                if let Some(port) = token.strip_prefix("-p").filter(|port| !port.is_empty()) {
+                    return Some(port);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Leading number of a speed column such as `10`, `0.5` or `40 Gbit/s`.
+fn gigabits(value: &str) -> Option<f64> {
+    let end = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+
+    value[..end]
+        .parse::<f64>()
+        .ok()
+        .filter(|speed| *speed > 0.0)
+}
+
+fn slug(value: &str) -> String {
+    value
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Maps one entry to a server and the group it belongs to.
+fn convert(record: &Record) -> Result<(Option<String>, Server), String> {
+    let host = field(record, &["iphost", "host", "hostname", "server", "ip"])
+        .ok_or("missing host")?
+        .to_lowercase();
+
+    if host.contains(char::is_whitespace) {
+        return Err(format!("{host}: host contains whitespace"));
+    }
+
+    let options = field(record, &["options"]).unwrap_or_default();
+    let ports = match field(record, &["port", "ports"]).or_else(|| port_option(options)) {
+        Some(ports) => ports
+            .parse::<Ports>()
+            .map_err(|reason| format!("{host}: {reason}"))?,
+        None => Ports::default(),
+    };
+
+    let speed = field(record, &["gbs", "gbps", "speed"]).and_then(gigabits);
+    let mut server = Server::new(host, ports, speed.map_or(1, |speed| speed.ceil() as u32));
+
+    server.max_bitrate = speed.map(|speed| (speed * 1e9) as u64);
+    server.region = field(record, &["country", "countrycode", "cc"]).map(str::to_lowercase);
+    server.tags = [
+        field(record, &["provider"]),
+        field(record, &["site", "city"]),
+    ]
+    .into_iter()
+    .flatten()
+    .map(str::to_string)
+    .collect();
+
+    for token in options.split_whitespace() {
+        match token {
+            "-4" | "--version4" => server.ip = IpVersion::V4,
+            "-6" | "--version6" => server.ip = IpVersion::V6,
+            _ => {}
+        }
+    }
+
+    let group = field(record, &["continent", "region"]).map(slug);
+
+    Ok((group, server))
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub groups: Groups,
+    /// Entries of a host and port range that was already imported.
+    pub duplicates: usize,
+    /// Entries that could not be used, with the reason.
+    pub skipped: Vec<String>,
+}
+
+/// Turns `records` into server groups, by continent unless everything goes
+/// into `group`.
+fn collect(records: &[Record], group: Option<&str>) -> Summary {
+    let mut summary = Summary::default();
+
+    for record in records {
+        let (continent, server) = match convert(record) {
+            Ok(converted) => converted,
+            Err(reason) => {
+                summary.skipped.push(reason);
+                continue;
+            }
+        };
+
+        let name = group
+            .map(str::to_string)
+            .or(continent)
+            .unwrap_or_else(|| OTHER_GROUP.to_string());
+        let servers = summary.groups.entry(name).or_default();
+
+        match servers
+            .iter_mut()
+            .find(|known| known.host == server.host && known.ports == server.ports)
+        {
+            Some(known) => {
+                summary.duplicates += 1;
+                known.weight = known.weight.max(server.weight);
+                known.max_bitrate = known.max_bitrate.max(server.max_bitrate);
+
+                for tag in server.tags {
+                    if !known.tags.contains(&tag) {
+                        known.tags.push(tag);
+                    }
+                }
+            }
+            None => servers.push(server),
+        }
+    }
+
+    summary
+}
+
+fn render(groups: &Groups, source: &str) -> Result<String, Error> {
+    Ok(format!(
+        "# Generated by `speedy servers import {source}`, run it again to refresh.\n\
+         # Use it with `speedy --servers-file <this file>`.\n\n{}",
+        toml::to_string_pretty(groups)?
+    ))
+}
+
+/// Reads a published server list and writes it as server groups to `output`,
+/// replacing the previous import.
+pub async fn import(
+    path: &Path,
+    format: Option<Format>,
+    group: Option<&str>,
+    output: &Path,
+) -> Result<Summary, Error> {
+    let source = path.display().to_string();
+    let format = format
+        .or_else(|| Format::detect(path))
+        .ok_or_else(|| Error::UnknownFormat(source.clone()))?;
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|err| Error::IO(source.clone(), err))?;
+
+    let records = match format {
+        Format::Json => json_records(&content)?,
+        Format::Csv => csv_records(&content)?,
+    };
+
+    let summary = collect(&records, group);
+
+    if summary.groups.is_empty() {
+        return Err(Error::Empty);
+    }
+
+    // Written next to the target and renamed, `serve` never reads half a file.
+    let target = output.display().to_string();
+    let temporary = output.with_extension("tmp");
+
+    tokio::fs::write(&temporary, render(&summary.groups, &source)?)
+        .await
+        .map_err(|err| Error::IO(target.clone(), err))?;
+    tokio::fs::rename(&temporary, output)
+        .await
+        .map_err(|err| Error::IO(target, err))?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect, csv_records, csv_rows, json_records, render, Summary};
+    use crate::server::{Groups, IpVersion, Ports};
+
+    const JSON: &str = include_str!("../fixtures/serverlist/iperf3serverlist.json");
+    const CSV: &str = include_str!("../fixtures/serverlist/iperf3serverlist.csv");
+
+    fn summary(json: bool) -> Summary {
+        let records = match json {
+            true => json_records(JSON).unwrap(),
+            false => csv_records(CSV).unwrap(),
+        };
+
+        collect(&records, None)
+    }
+
+    #[test]
+    fn test_json_maps_fields() {
+        let summary = summary(true);
+        let groups = &summary.groups;
+
+        assert_eq!(
+            vec!["asia", "europe", "north-america", "other"],
+            groups.keys().collect::<Vec<_>>()
+        );
+
+        let init7 = &groups["europe"][0];
+        assert_eq!("speedtest.init7.net", init7.host);
+        assert_eq!(Ports::single(5201), init7.ports);
+        assert_eq!(10, init7.weight);
+        assert_eq!(Some(10_000_000_000), init7.max_bitrate);
+        assert_eq!(Some("ch"), init7.region.as_deref());
+        assert_eq!(vec!["Init7", "Winterthur"], init7.tags);
+
+        let online = &groups["europe"][2];
+        assert_eq!(IpVersion::V6, online.ip);
+        assert_eq!(1, online.weight);
+        assert_eq!(Some(500_000_000), online.max_bitrate);
+
+        assert_eq!(40, groups["north-america"][0].weight);
+        assert_eq!(
+            Ports {
+                start: 5201,
+                end: 5210
+            },
+            groups["asia"][0].ports
+        );
+
+        let unknown = &groups["other"][0];
+        assert_eq!(
+            (1, None, None),
+            (
+                unknown.weight,
+                unknown.max_bitrate,
+                unknown.region.as_deref()
+            )
+        );
+    }
+
+    #[test]
+    fn test_duplicates_and_invalid_entries() {
+        let summary = summary(true);
+
+        assert_eq!(1, summary.duplicates);
+        assert_eq!(3, summary.groups["europe"].len());
+        assert_eq!(2, summary.skipped.len(), "{:?}", summary.skipped);
+        assert!(summary.skipped[0].contains("broken host"));
+        assert!(summary.skipped[1].contains("bad-port.example.org"));
+    }
+
+    #[test]
+    fn test_csv_matches_json() {
+        let (json, csv) = (summary(true), summary(false));
+
+        assert_eq!(json.duplicates, csv.duplicates);
+        assert_eq!(json.skipped, csv.skipped);
+        assert_eq!(
+            vec!["Leaseweb, \"Asia\"", "Singapore"],
+            csv.groups["asia"][0].tags
+        );
+
+        let mut expected = json.groups.clone();
+        expected.get_mut("asia").unwrap()[0].tags = csv.groups["asia"][0].tags.clone();
+        assert_eq!(expected, csv.groups);
+    }
+
+    #[test]
+    fn test_csv_quoting() {
+        let rows = csv_rows("a,\"b,c\",\"d\"\"e\"\r\n\n\"multi\nline\",x").unwrap();
+
+        assert_eq!(vec!["a", "b,c", "d\"e"], rows[0]);
+        assert_eq!(vec!["multi\nline", "x"], rows[1]);
+        assert!(csv_rows("\"open").is_err());
+    }
+
+    #[test]
+    fn test_group_override() {
+        let summary = collect(&json_records(JSON).unwrap(), Some("default"));
+
+        assert_eq!(vec!["default"], summary.groups.keys().collect::<Vec<_>>());
+        assert_eq!(6, summary.groups["default"].len());
+    }
+
+    #[test]
+    fn test_rendered_file_reads_back() {
+        let groups = summary(true).groups;
+        let content = render(&groups, "list.json").unwrap();
+
+        assert!(content.starts_with("# Generated by `speedy servers import list.json`"));
+        assert_eq!(groups, toml::from_str::<Groups>(&content).unwrap());
+    }
+}
@@ -1,34 +1,192 @@
 use std::fmt::Display;
+use std::path::Path;
 
 use human_time::ToHumanTimeString;
-use nom::character::complete::{char, i64, line_ending, multispace0, one_of, u8};
-use nom::combinator::{map, opt};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while1};
+use nom::character::complete::{
+    char, digit1, i32, i64, line_ending, multispace0, one_of, space0, space1, u16, u8,
+};
+use nom::combinator::{map, opt, recognize, value};
 use nom::error::{context, ContextError, ParseError};
-use nom::multi::many1;
+use nom::multi::{many0, many1};
+use nom::sequence::{pair, preceded, tuple};
 use nom::Parser;
 use nom::{sequence::Tuple, IResult};
 
+use crate::iperf3::Protocol;
+use crate::selection::Strategy;
+use crate::server;
+use crate::webtest::Engine;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to read timetable file ({0}): {1}")]
+    IO(String, std::io::Error),
+
+    #[error("Failed to parse timetable file ({0}).")]
+    Parse(String),
+
+    #[error("File contains invalid TimeRange: {0}")]
+    InvalidRange(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Down,
+    Up,
+    Both,
+}
+
+impl Direction {
+    #[inline]
+    pub fn download(self) -> bool {
+        matches!(self, Direction::Down | Direction::Both)
+    }
+
+    #[inline]
+    pub fn upload(self) -> bool {
+        matches!(self, Direction::Up | Direction::Both)
+    }
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Direction::Down => f.write_str("down"),
+            Direction::Up => f.write_str("up"),
+            Direction::Both => f.write_str("both"),
+        }
+    }
+}
+
+/// Whether a run tests one server, every server of the group or the mesh peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// One server picked by the strategy for each direction.
+    #[default]
+    Single,
+    /// Every server of the group in turn, both directions against the same one.
+    Compare,
+    /// Every peer of `[mesh]` in turn, tagged with both sites.
+    Mesh,
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Mode::Single => f.write_str("single"),
+            Mode::Compare => f.write_str("compare"),
+            Mode::Mesh => f.write_str("mesh"),
+        }
+    }
+}
+
+/// Optional per-rule overrides of the global test settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    pub duration: Option<time::Duration>,
+    pub direction: Option<Direction>,
+    pub protocol: Option<Protocol>,
+    pub streams: Option<u16>,
+    pub bitrate: Option<String>,
+    pub servers: Option<String>,
+    pub retries: Option<i32>,
+    pub strategy: Option<Strategy>,
+    pub mode: Option<Mode>,
+    pub engine: Option<Engine>,
+}
+
+impl Display for Params {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(duration) = self.duration {
+            write!(f, " duration={}", human(duration))?;
+        }
+        if let Some(direction) = self.direction {
+            write!(f, " direction={direction}")?;
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, " protocol={protocol}")?;
+        }
+        if let Some(streams) = self.streams {
+            write!(f, " streams={streams}")?;
+        }
+        if let Some(ref bitrate) = self.bitrate {
+            write!(f, " bitrate={bitrate}")?;
+        }
+        if let Some(ref servers) = self.servers {
+            write!(f, " servers={servers}")?;
+        }
+        if let Some(retries) = self.retries {
+            write!(f, " retries={retries}")?;
+        }
+        if let Some(strategy) = self.strategy {
+            write!(f, " strategy={strategy}")?;
+        }
+        if let Some(mode) = self.mode {
+            write!(f, " mode={mode}")?;
+        }
+        if let Some(engine) = self.engine {
+            write!(f, " engine={engine}")?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
+enum Param {
+    Duration(time::Duration),
+    Direction(Direction),
+    Protocol(Protocol),
+    Streams(u16),
+    Bitrate(String),
+    Servers(String),
+    Retries(i32),
+    Strategy(Strategy),
+    Mode(Mode),
+    Engine(Engine),
+}
+
+#[derive(Debug, Clone, PartialEq)]
 
 pub struct Table {
     pub start_hour: u8,
     pub end_hour: u8,
     pub duration: time::Duration,
+    pub jitter: Option<time::Duration>,
+    pub params: Params,
+}
+
+impl Table {
+    #[inline]
+    pub fn contains(&self, hour: u8) -> bool {
+        hour >= self.start_hour && hour < self.end_hour
+    }
+}
+
+fn human(duration: time::Duration) -> String {
+    std::time::Duration::new(
+        duration.whole_seconds() as u64,
+        duration.subsec_nanoseconds() as u32,
+    )
+    .to_human_time_string()
 }
 
 impl Display for Table {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let d = std::time::Duration::new(
-            self.duration.whole_seconds() as u64,
-            self.duration.whole_nanoseconds() as u32,
-        );
-
         f.write_fmt(format_args!(
             "{}-{} {}",
             self.start_hour,
             self.end_hour,
-            d.to_human_time_string(),
-        ))
+            human(self.duration),
+        ))?;
+
+        if let Some(jitter) = self.jitter {
+            f.write_fmt(format_args!(" ±{}", human(jitter)))?;
+        }
+
+        self.params.fmt(f)
     }
 }
 
@@ -79,6 +237,118 @@ where
     .parse(content)
 }
 
+fn jitter<'a, E>(content: &'a str) -> IResult<&'a str, time::Duration, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str>,
+{
+    preceded(
+        tuple((space0, alt((tag("±"), tag("+-"))), space0)),
+        context("jitter", duration_map),
+    )
+    .parse(content)
+}
+
+fn param<'a, E>(content: &'a str) -> IResult<&'a str, Param, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str>,
+{
+    let name = |val| take_while1(|c: char| c.is_alphanumeric() || c == '-' || c == '_')(val);
+
+    alt((
+        map(preceded(tag("duration="), duration_map), Param::Duration),
+        map(
+            preceded(
+                tag("direction="),
+                alt((
+                    value(Direction::Down, tag("down")),
+                    value(Direction::Up, tag("up")),
+                    value(Direction::Both, tag("both")),
+                )),
+            ),
+            Param::Direction,
+        ),
+        map(
+            preceded(
+                tag("protocol="),
+                alt((
+                    value(Protocol::Tcp, tag("tcp")),
+                    value(Protocol::Udp, tag("udp")),
+                )),
+            ),
+            Param::Protocol,
+        ),
+        map(preceded(tag("streams="), u16), Param::Streams),
+        map(
+            preceded(tag("bitrate="), recognize(pair(digit1, opt(one_of("KMG"))))),
+            |val: &str| Param::Bitrate(val.to_string()),
+        ),
+        map(preceded(tag("servers="), name), |val: &str| {
+            Param::Servers(val.to_string())
+        }),
+        map(preceded(tag("retries="), i32), Param::Retries),
+        map(
+            preceded(
+                tag("strategy="),
+                alt((
+                    value(Strategy::Weighted, tag("weighted")),
+                    value(Strategy::Nearest, tag("nearest")),
+                    value(Strategy::RoundRobin, tag("round-robin")),
+                    value(Strategy::Pinned, tag("pinned")),
+                )),
+            ),
+            Param::Strategy,
+        ),
+        map(
+            preceded(
+                tag("mode="),
+                alt((
+                    value(Mode::Single, tag("single")),
+                    value(Mode::Compare, tag("compare")),
+                    value(Mode::Mesh, tag("mesh")),
+                )),
+            ),
+            Param::Mode,
+        ),
+        map(
+            preceded(
+                tag("engine="),
+                alt((
+                    value(Engine::Iperf3, tag("iperf3")),
+                    value(Engine::Http, tag("http")),
+                )),
+            ),
+            Param::Engine,
+        ),
+    ))
+    .parse(content)
+}
+
+fn params<'a, E>(content: &'a str) -> IResult<&'a str, Params, E>
+where
+    E: ParseError<&'a str> + ContextError<&'a str>,
+{
+    map(many0(preceded(space1, context("param", param))), |items| {
+        items
+            .into_iter()
+            .fold(Params::default(), |mut params, item| {
+                match item {
+                    Param::Duration(val) => params.duration = Some(val),
+                    Param::Direction(val) => params.direction = Some(val),
+                    Param::Protocol(val) => params.protocol = Some(val),
+                    Param::Streams(val) => params.streams = Some(val),
+                    Param::Bitrate(val) => params.bitrate = Some(val),
+                    Param::Servers(val) => params.servers = Some(val),
+                    Param::Retries(val) => params.retries = Some(val),
+                    Param::Strategy(val) => params.strategy = Some(val),
+                    Param::Mode(val) => params.mode = Some(val),
+                    Param::Engine(val) => params.engine = Some(val),
+                }
+                params
+            })
+    })
+    .parse(content)
+}
+
 fn table<'a, E>(content: &'a str) -> IResult<&'a str, Table, E>
 where
     E: ParseError<&'a str> + ContextError<&'a str>,
@@ -90,16 +360,20 @@ where
                 context("hours", hour_span),
                 multispace0,
                 context("duration", duration_map),
+                opt(jitter),
+                params,
             )
                 .parse(val)
         }),
-        |(_, (start, end), _, duration)| Table {
+        |(_, (start, end), _, duration, jitter, params)| Table {
             start_hour: start,
             end_hour: match end {
                 0 => 24,
                 res => res,
             },
             duration,
+            jitter,
+            params,
         },
     )
     .parse(content)
@@ -112,10 +386,64 @@ pub fn parse(content: &str) -> IResult<&str, Vec<Table>, nom::error::Error<&str>
     .parse(content)
 }
 
+/// Checks that every range fits its interval, that jitter is smaller than
+/// the interval it is applied to and that a test fits between two runs.
+pub fn validate(table: &[Table]) -> Result<(), Error> {
+    let invalid = table
+        .iter()
+        .filter(|item| {
+            (item.end_hour <= 24
+                && ((item.end_hour - item.start_hour) as i64) < item.duration.whole_hours())
+                || item.jitter.is_some_and(|jitter| jitter >= item.duration)
+                || item.params.duration.is_some_and(|duration| {
+                    duration <= time::Duration::ZERO || duration >= item.duration
+                })
+                || item.params.streams == Some(0)
+                || item
+                    .params
+                    .bitrate
+                    .as_deref()
+                    .is_some_and(|bitrate| server::parse_bitrate(bitrate).is_err())
+                || item.params.retries.is_some_and(|retries| retries < 1)
+        })
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InvalidRange(invalid.join(", ")))
+    }
+}
+
+/// Parses and validates timetable `content`, `name` is used in errors.
+pub fn load(content: &str, name: &str) -> Result<Vec<Table>, Error> {
+    let table = match parse(content) {
+        Ok((rest, table)) if rest.trim().is_empty() => table,
+        Ok(_) | Err(_) => return Err(Error::Parse(name.to_string())),
+    };
+
+    validate(&table)?;
+
+    Ok(table)
+}
+
+/// Reads, parses and validates the timetable at `path`.
+pub async fn read(path: &Path) -> Result<Vec<Table>, Error> {
+    let name = path.display().to_string();
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|err| Error::IO(name.clone(), err))?;
+
+    load(&content, &name)
+}
+
 #[cfg(test)]
 mod tests {
 
-    use super::parse;
+    use super::{parse, validate, Direction, Engine, Mode, Params};
+    use crate::iperf3::Protocol;
+    use crate::selection::Strategy;
 
     #[test]
     fn test_parse_single_line() {
@@ -126,5 +454,82 @@ mod tests {
         assert_eq!(1, data.len());
         assert_eq!(0_u8, data[0].start_hour);
         assert_eq!(1_u8, data[0].end_hour);
+        assert_eq!(None, data[0].jitter);
+    }
+
+    #[test]
+    fn test_parse_jitter() {
+        let (_, data) = parse("0-3 10m\n3-8 15m ±3m\n8-0 1h +-90s\n").unwrap();
+
+        assert_eq!(3, data.len());
+        assert_eq!(None, data[0].jitter);
+        assert_eq!(Some(time::Duration::minutes(3)), data[1].jitter);
+        assert_eq!(time::Duration::minutes(15), data[1].duration);
+        assert_eq!(Some(time::Duration::seconds(90)), data[2].jitter);
+        assert_eq!(24_u8, data[2].end_hour);
+    }
+
+    #[test]
+    fn test_parse_params() {
+        let (rest, data) = parse(
+            "0-8 15m ±3m duration=20s streams=4 servers=eu-core direction=both\n\
+             8-12 5m protocol=udp bitrate=100M retries=1 strategy=round-robin \n\
+             12-19 1h mode=compare\n\
+             19-0 2h engine=http\n\
+             0-24 6h mode=mesh\n",
+        )
+        .unwrap();
+
+        assert!(rest.trim().is_empty());
+        assert_eq!(5, data.len());
+
+        let params = &data[0].params;
+        assert_eq!(Some(time::Duration::seconds(20)), params.duration);
+        assert_eq!(Some(4), params.streams);
+        assert_eq!(Some("eu-core"), params.servers.as_deref());
+        assert_eq!(Some(Direction::Both), params.direction);
+        assert_eq!(Some(time::Duration::minutes(3)), data[0].jitter);
+
+        let params = &data[1].params;
+        assert_eq!(Some(Protocol::Udp), params.protocol);
+        assert_eq!(Some("100M"), params.bitrate.as_deref());
+        assert_eq!(Some(1), params.retries);
+        assert_eq!(Some(Strategy::RoundRobin), params.strategy);
+
+        assert_eq!(
+            Params {
+                mode: Some(Mode::Compare),
+                ..Default::default()
+            },
+            data[2].params
+        );
+        assert_eq!(Some(Engine::Http), data[3].params.engine);
+        assert_eq!("19-24 2h engine=http", data[3].to_string());
+        assert_eq!(Some(Mode::Mesh), data[4].params.mode);
+        assert_eq!(
+            "0-8 15m ±3m duration=20s direction=both streams=4 servers=eu-core",
+            data[0].to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_param() {
+        let (rest, _) = parse("0-8 15m speed=fast\n").unwrap();
+
+        assert!(!rest.trim().is_empty());
+    }
+
+    #[test]
+    fn test_validate_rejects_test_longer_than_interval() {
+        let (_, data) = parse("0-3 1m duration=2m").unwrap();
+
+        assert!(validate(&data).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_jitter_larger_than_interval() {
+        let (_, data) = parse("0-3 10m ±10m").unwrap();
+
+        assert!(validate(&data).is_err());
     }
 }
@@ -0,0 +1,537 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use crate::iperf3::{Event, Options};
+use crate::models::{self, IPerf3};
+
+/// Size of the chunks an upload sends.
+const CHUNK: usize = 64 * 1024;
+const SAMPLE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no HTTP {0} endpoint configured, set [engines.http] {0}")]
+    NoEndpoint(&'static str),
+
+    #[error("invalid HTTP endpoint ({0}): {1}")]
+    Url(String, String),
+
+    #[error("request to {0} failed: {1}")]
+    Request(String, reqwest::Error),
+
+    #[error("{0} answered with {1}")]
+    Status(String, reqwest::StatusCode),
+
+    #[error("test was canceled")]
+    Canceled,
+
+    #[error("test was interrupted after {} intervals", .0.intervals.len())]
+    Partial(Box<IPerf3>),
+}
+
+/// Which tool measures the throughput.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Engine {
+    /// iperf3 against the servers of the group
+    #[default]
+    Iperf3,
+    /// HTTP(S) transfers from and to the configured endpoints
+    Http,
+}
+
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Engine::Iperf3 => f.write_str("iperf3"),
+            Engine::Http => f.write_str("http"),
+        }
+    }
+}
+
+/// Endpoints of the HTTP engine, tried in order until one works.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Endpoints {
+    /// URLs answering GET with a large body.
+    pub download: Vec<String>,
+    /// URLs accepting POST of any body.
+    pub upload: Vec<String>,
+    /// Seconds measured before the test proper and left out of the result,
+    /// while the connections ramp up.
+    pub warmup: u32,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            download: Vec::new(),
+            upload: Vec::new(),
+            warmup: 2,
+        }
+    }
+}
+
+pub async fn download_speed(
+    endpoints: &Endpoints,
+    options: &Options,
+    cancel: &CancellationToken,
+) -> Result<IPerf3, Error> {
+    execute(&endpoints.download, "download", endpoints, options, cancel).await
+}
+
+pub async fn upload_speed(
+    endpoints: &Endpoints,
+    options: &Options,
+    cancel: &CancellationToken,
+) -> Result<IPerf3, Error> {
+    execute(&endpoints.upload, "upload", endpoints, options, cancel).await
+}
+
+async fn execute(
+    urls: &[String],
+    name: &'static str,
+    endpoints: &Endpoints,
+    options: &Options,
+    cancel: &CancellationToken,
+) -> Result<IPerf3, Error> {
+    let mut failed = Err(Error::NoEndpoint(name));
+
+    for url in urls {
+        match transfer(url, name == "download", endpoints.warmup, options, cancel).await {
+            Ok(result) => return Ok(result),
+            Err(err @ (Error::Canceled | Error::Partial(_))) => return Err(err),
+            Err(err) => {
+                eprintln!("Failed to {name} from {url}: {err}");
+                failed = Err(err);
+            }
+        }
+    }
+
+    failed
+}
+
+/// Sends the events of a test and builds its result like iperf3 would.
+struct Recorder<'a> {
+    options: &'a Options,
+    download: bool,
+    result: IPerf3,
+}
+
+impl Recorder<'_> {
+    fn emit(&self, event: Event) {
+        if let Some(ref events) = self.options.events {
+            _ = events.send(event);
+        }
+    }
+
+    fn start(&mut self, url: &reqwest::Url) {
+        let now = time::OffsetDateTime::now_utc();
+        let start = &mut self.result.start;
+
+        start.version = format!("speedy {} http", env!("CARGO_PKG_VERSION"));
+        start.timestamp.timesecs = now.unix_timestamp();
+        start.timestamp.time = now
+            .format(&time::format_description::well_known::Rfc2822)
+            .unwrap_or_default();
+        start.connecting_to.host = url.host_str().unwrap_or_default().to_string();
+        start.connecting_to.port = url.port_or_known_default().unwrap_or_default().into();
+        start.test_start.protocol = "HTTP".to_string();
+        start.test_start.num_streams = self.options.streams.into();
+        start.test_start.duration = self.options.length().into();
+        start.test_start.reverse = self.download;
+
+        self.emit(Event::Start(Box::new(self.result.start.clone())));
+    }
+
+    fn sum(&self, start: f64, end: f64, bytes: u64) -> models::Sum {
+        models::Sum {
+            start,
+            end,
+            seconds: end - start,
+            bytes: bytes as i64,
+            bits_per_second: bytes as f64 * 8.0 / (end - start).max(f64::EPSILON),
+            sender: !self.download,
+            ..Default::default()
+        }
+    }
+
+    fn interval(&mut self, start: f64, end: f64, bytes: u64) {
+        let interval = models::Interval {
+            sum: self.sum(start, end, bytes),
+            ..Default::default()
+        };
+
+        self.emit(Event::Interval(interval.clone()));
+        self.result.intervals.push(interval);
+    }
+
+    fn end(mut self, seconds: f64) -> IPerf3 {
+        let bytes = self
+            .result
+            .intervals
+            .iter()
+            .map(|interval| interval.sum.bytes as u64)
+            .sum();
+        let sum = self.sum(0.0, seconds, bytes);
+        let end = &mut self.result.end;
+
+        end.sum_sent.seconds = sum.seconds;
+        end.sum_sent.end = sum.end;
+        end.sum_sent.bytes = sum.bytes;
+        end.sum_sent.bits_per_second = sum.bits_per_second;
+        end.sum_sent.sender = true;
+        end.sum_received.seconds = sum.seconds;
+        end.sum_received.end = sum.end;
+        end.sum_received.bytes = sum.bytes;
+        end.sum_received.bits_per_second = sum.bits_per_second;
+
+        self.emit(Event::End(Box::new(self.result.end.clone())));
+        self.result
+    }
+}
+
+/// One connection, transfers until `stop` is set and adds the bytes to `bytes`.
+/// `answered` is notified once a download got a 2xx response, uploads only get
+/// theirs after the body so they notify once it is being sent.
+async fn connection(
+    client: reqwest::Client,
+    url: reqwest::Url,
+    download: bool,
+    bytes: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    answered: Arc<Notify>,
+) -> Result<(), Error> {
+    let failed = |err| Error::Request(url.to_string(), err);
+
+    while !stop.load(Ordering::Relaxed) {
+        let request = match download {
+            true => client.get(url.clone()),
+            false => {
+                let chunk = Bytes::from(vec![0; CHUNK]);
+                let sent = Arc::clone(&bytes);
+                let stop = Arc::clone(&stop);
+                let answered = Arc::clone(&answered);
+                let body = futures_util::stream::unfold((), move |_| {
+                    let chunk = chunk.clone();
+                    let sent = Arc::clone(&sent);
+                    let stop = Arc::clone(&stop);
+                    let answered = Arc::clone(&answered);
+
+                    async move {
+                        if stop.load(Ordering::Relaxed) {
+                            return None;
+                        }
+
+                        answered.notify_one();
+                        sent.fetch_add(CHUNK as u64, Ordering::Relaxed);
+                        Some((Ok::<_, std::io::Error>(chunk), ()))
+                    }
+                });
+
+                client
+                    .post(url.clone())
+                    .body(reqwest::Body::wrap_stream(body))
+            }
+        };
+
+        let mut response = request.send().await.map_err(failed)?;
+
+        if !response.status().is_success() {
+            return Err(Error::Status(url.to_string(), response.status()));
+        }
+
+        answered.notify_one();
+
+        while let Some(chunk) = response.chunk().await.map_err(failed)? {
+            if download {
+                bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn transfer(
+    url: &str,
+    download: bool,
+    warmup: u32,
+    options: &Options,
+    cancel: &CancellationToken,
+) -> Result<IPerf3, Error> {
+    let url =
+        reqwest::Url::parse(url).map_err(|err| Error::Url(url.to_string(), err.to_string()))?;
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(options.timeout.max(1) as u64))
+        .build()
+        .map_err(|err| Error::Request(url.to_string(), err))?;
+
+    let mut recorder = Recorder {
+        options,
+        download,
+        result: IPerf3::default(),
+    };
+
+    let bytes = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let answered = Arc::new(Notify::new());
+    let mut connections = JoinSet::new();
+
+    for _ in 0..options.streams.max(1) {
+        connections.spawn(connection(
+            client.clone(),
+            url.clone(),
+            download,
+            Arc::clone(&bytes),
+            Arc::clone(&stop),
+            Arc::clone(&answered),
+        ));
+    }
+
+    // Endpoints that fail never start a test.
+    tokio::select! {
+        _ = cancel.cancelled() => {
+            stop.store(true, Ordering::Relaxed);
+            connections.abort_all();
+            return Err(Error::Canceled);
+        }
+        Some(Ok(Err(err))) = connections.join_next() => {
+            stop.store(true, Ordering::Relaxed);
+            connections.abort_all();
+            return Err(err);
+        }
+        _ = answered.notified() => recorder.start(&url),
+    }
+
+    let samples = u64::from(warmup) + options.length().max(1) as u64;
+    let mut ticks = tokio::time::interval_at(Instant::now() + SAMPLE, SAMPLE);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut previous = Instant::now();
+    let mut measured = None;
+    let mut last = 0;
+    let mut outcome = Ok(());
+
+    for sample in 0..samples {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                outcome = Err(());
+                break;
+            }
+            Some(Ok(Err(err))) = connections.join_next() => {
+                stop.store(true, Ordering::Relaxed);
+                connections.abort_all();
+                return Err(err);
+            }
+            _ = ticks.tick() => {}
+        }
+
+        let now = Instant::now();
+        let total = bytes.load(Ordering::Relaxed);
+
+        // Ticks come late on a busy runtime, intervals last as long as they took.
+        if sample >= u64::from(warmup) {
+            let from = *measured.get_or_insert(previous);
+            recorder.interval(
+                (previous - from).as_secs_f64(),
+                (now - from).as_secs_f64(),
+                total - last,
+            );
+        }
+
+        previous = now;
+        last = total;
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    connections.abort_all();
+
+    match outcome {
+        Ok(()) => {
+            let seconds = recorder
+                .result
+                .intervals
+                .last()
+                .map_or(0.0, |interval| interval.sum.end);
+            Ok(recorder.end(seconds))
+        }
+        Err(()) if recorder.result.intervals.is_empty() => Err(Error::Canceled),
+        Err(()) => Err(Error::Partial(Box::new(recorder.result))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::body::{Bytes, HttpBody};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{download_speed, upload_speed, Endpoints, Error};
+    use crate::iperf3::{Event, Options};
+
+    /// Stand-in for a speed test site, `/down` streams zeros until the client
+    /// hangs up and `/up` discards whatever it gets.
+    async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/down") => {
+                let (mut sender, body) = Body::channel();
+
+                tokio::spawn(async move {
+                    let chunk = Bytes::from(vec![0; 64 * 1024]);
+                    while sender.send_data(chunk.clone()).await.is_ok() {}
+                });
+
+                Ok(Response::new(body))
+            }
+            (&Method::POST, "/up") => {
+                let mut body = request.into_body();
+                while let Some(Ok(_)) = body.data().await {}
+
+                Ok(Response::new(Body::empty()))
+            }
+            _ => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()),
+        }
+    }
+
+    fn serve() -> SocketAddr {
+        let server =
+            hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(handle))
+            }));
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        addr
+    }
+
+    fn endpoints(addr: SocketAddr, warmup: u32) -> Endpoints {
+        Endpoints {
+            download: vec![
+                format!("http://{addr}/missing"),
+                format!("http://{addr}/down"),
+            ],
+            upload: vec![format!("http://{addr}/up")],
+            warmup,
+        }
+    }
+
+    fn options(duration: i32) -> (Options, mpsc::UnboundedReceiver<Event>) {
+        let (sender, events) = mpsc::unbounded_channel();
+        let options = Options {
+            timeout: 7,
+            duration: Some(duration),
+            streams: 2,
+            events: Some(sender),
+            ..Default::default()
+        };
+
+        (options, events)
+    }
+
+    #[tokio::test]
+    async fn test_download_skips_warmup_and_failing_endpoints() {
+        let addr = serve();
+        let (options, mut events) = options(2);
+
+        let result = download_speed(&endpoints(addr, 1), &options, &CancellationToken::new())
+            .await
+            .unwrap();
+
+        assert_eq!(2, result.intervals.len());
+        assert_eq!(0.0, result.intervals[0].sum.start);
+        assert_eq!(result.intervals[0].sum.end, result.intervals[1].sum.start);
+        assert_eq!(result.intervals[1].sum.end, result.end.sum_received.end);
+        assert!(result
+            .intervals
+            .iter()
+            .all(|interval| interval.sum.bytes > 0));
+        assert!(result.end.sum_received.bits_per_second > 0.0);
+        assert_eq!("127.0.0.1", result.start.connecting_to.host);
+        assert_eq!(i64::from(addr.port()), result.start.connecting_to.port);
+        assert!(result.start.test_start.reverse);
+
+        drop(options);
+        let mut kinds = Vec::new();
+        while let Some(event) = events.recv().await {
+            kinds.push(match event {
+                Event::Start(_) => "start",
+                Event::Interval(_) => "interval",
+                Event::End(_) => "end",
+            });
+        }
+        // The missing endpoint never answered, so it started nothing.
+        assert_eq!(vec!["start", "interval", "interval", "end"], kinds);
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let addr = serve();
+        let (options, _events) = options(1);
+
+        let result = upload_speed(&endpoints(addr, 0), &options, &CancellationToken::new())
+            .await
+            .unwrap();
+
+        assert_eq!(1, result.intervals.len());
+        assert!(result.intervals[0].sum.bytes > 0);
+        assert!(!result.start.test_start.reverse);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_keeps_measured_intervals() {
+        let addr = serve();
+        let (options, _events) = options(10);
+        let cancel = CancellationToken::new();
+
+        let stop = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+            stop.cancel();
+        });
+
+        match download_speed(&endpoints(addr, 0), &options, &cancel).await {
+            Err(Error::Partial(result)) => assert_eq!(1, result.intervals.len()),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let addr = serve();
+        let (options, _events) = options(1);
+        let cancel = CancellationToken::new();
+
+        let mut endpoints = endpoints(addr, 0);
+        endpoints.download.truncate(1);
+
+        assert!(matches!(
+            download_speed(&endpoints, &options, &cancel).await,
+            Err(Error::Status(_, StatusCode::NOT_FOUND))
+        ));
+        assert!(matches!(
+            download_speed(&Endpoints::default(), &options, &cancel).await,
+            Err(Error::NoEndpoint("download"))
+        ));
+    }
+}